@@ -9,10 +9,74 @@ use crate::scoring_utils::*;
 
 pub type MatchWithPositions = (Score, Vec<usize>);
 
+/// Weights of the bonuses rewarding the matched chars.
+///
+/// The defaults are the weights used by the original fzy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScoringParams {
+    /// Bonus for a match right after the previous matched char.
+    pub consecutive: i32,
+    /// Bonus for a match at the beginning of a word, i.e., after `-`, `_` or ` `.
+    pub word: i32,
+    /// Bonus for an uppercase match following a lowercase char.
+    pub capital: i32,
+    /// Bonus for a match right after `.`.
+    pub dot: i32,
+    /// Bonus for a match at the beginning of a path component, i.e., after `/`.
+    pub slash: i32,
+}
+
+impl Default for ScoringParams {
+    fn default() -> Self {
+        Self {
+            consecutive: SCORE_MATCH_CONSECUTIVE,
+            word: SCORE_MATCH_WORD,
+            capital: SCORE_MATCH_CAPITAL,
+            dot: SCORE_MATCH_DOT,
+            slash: SCORE_MATCH_SLASH,
+        }
+    }
+}
+
 pub fn match_and_score_with_positions(needle: &str, haystack: &str) -> Option<MatchWithPositions> {
-    match matches(needle, haystack) {
+    match_and_score_with_params(needle, haystack, &ScoringParams::default())
+}
+
+/// Same as [`match_and_score_with_positions`], but scores with the given bonus weights.
+pub fn match_and_score_with_params(
+    needle: &str,
+    haystack: &str,
+    params: &ScoringParams,
+) -> Option<MatchWithPositions> {
+    match_and_score_with_case(needle, haystack, params, false)
+}
+
+/// Same as [`match_and_score_with_params`], but the chars must be of the same case if
+/// `respect_case` is true.
+pub fn match_and_score_with_case(
+    needle: &str,
+    haystack: &str,
+    params: &ScoringParams,
+    respect_case: bool,
+) -> Option<MatchWithPositions> {
+    // Dispatched once here to keep the char comparison inlined.
+    if respect_case {
+        match_and_score_by(needle, haystack, params, eq_respect_case)
+    } else {
+        match_and_score_by(needle, haystack, params, eq)
+    }
+}
+
+fn match_and_score_by(
+    needle: &str,
+    haystack: &str,
+    params: &ScoringParams,
+    eq: impl Fn(char, char) -> bool + Copy,
+) -> Option<MatchWithPositions> {
+    match matches(needle, haystack, eq) {
         Some(needle_length) => {
-            let (score, positions) = score_with_positions(needle, needle_length, haystack);
+            let (score, positions) =
+                score_with_positions(needle, needle_length, haystack, params, eq);
             Some((score, positions))
         }
         None => None,
@@ -27,11 +91,11 @@ pub fn match_and_score_with_positions(needle: &str, haystack: &str) -> Option<Ma
 ///
 // This is a proper code, that should compile, but `matches()` function is private.
 /// ```compile_fail
-/// assert_eq!(Some(5), extracted_fzy::matches("amo汉漢", "app/models/order/汉语/漢語"));
+/// assert_eq!(Some(5), extracted_fzy::matches("amo汉漢", "app/models/order/汉语/漢語", eq));
 /// assert_eq!(6, "汉漢".len()); // Length of this two chars in bytes.
 /// ```
 #[inline]
-fn matches(needle: &str, haystack: &str) -> Option<usize> {
+fn matches(needle: &str, haystack: &str, eq: impl Fn(char, char) -> bool) -> Option<usize> {
     if needle.is_empty() || needle == haystack {
         return Some(0);
     }
@@ -49,7 +113,13 @@ fn matches(needle: &str, haystack: &str) -> Option<usize> {
     Some(needle_length)
 }
 
-fn score_with_positions(needle: &str, needle_length: usize, haystack: &str) -> (Score, Vec<usize>) {
+fn score_with_positions(
+    needle: &str,
+    needle_length: usize,
+    haystack: &str,
+    params: &ScoringParams,
+    eq: impl Fn(char, char) -> bool,
+) -> (Score, Vec<usize>) {
     // empty needle
     if needle_length == 0 {
         return (SCORE_MIN, vec![]);
@@ -67,7 +137,7 @@ fn score_with_positions(needle: &str, needle_length: usize, haystack: &str) -> (
         return (SCORE_MIN, vec![]);
     }
 
-    let (d, m) = calculate_score(needle, needle_length, haystack, haystack_length);
+    let (d, m) = calculate_score(needle, needle_length, haystack, haystack_length, params, eq);
     let mut positions = vec![0_usize; needle_length];
 
     {
@@ -86,7 +156,7 @@ fn score_with_positions(needle: &str, needle_length: usize, haystack: &str) -> (
                 let m = m.get(i, j);
 
                 if d != SCORE_MIN && (match_required || score_eq(d, m)) {
-                    if i > 0 && j > 0 && score_eq(m, score_add(last, SCORE_MATCH_CONSECUTIVE)) {
+                    if i > 0 && j > 0 && score_eq(m, score_add(last, params.consecutive)) {
                         match_required = true;
                     }
 
@@ -108,8 +178,10 @@ fn calculate_score(
     needle_length: usize,
     haystack: &str,
     haystack_length: usize,
+    params: &ScoringParams,
+    eq: impl Fn(char, char) -> bool,
 ) -> (Matrix, Matrix) {
-    let bonus = compute_bonus(haystack, haystack_length);
+    let bonus = compute_bonus(haystack, haystack_length, params);
 
     let mut m = Matrix::new(needle_length, haystack_length);
     let mut d = Matrix::new(needle_length, haystack_length);
@@ -136,7 +208,7 @@ fn calculate_score(
                         let d = d.get(i - 1, j - 1);
 
                         let m = score_add(m, bonus_score);
-                        let d = score_add(d, SCORE_MATCH_CONSECUTIVE);
+                        let d = score_add(d, params.consecutive);
 
                         (m).max(d)
                     }
@@ -169,7 +241,13 @@ fn eq(a: char, b: char) -> bool {
     }
 }
 
-fn compute_bonus(haystack: &str, haystack_length: usize) -> Vec<Score> {
+/// Compares two characters case-sensitively
+#[inline(always)]
+fn eq_respect_case(a: char, b: char) -> bool {
+    a == b
+}
+
+fn compute_bonus(haystack: &str, haystack_length: usize, params: &ScoringParams) -> Vec<Score> {
     let mut last_char = '/';
 
     let len = haystack_length;
@@ -177,28 +255,28 @@ fn compute_bonus(haystack: &str, haystack_length: usize) -> Vec<Score> {
     haystack
         .chars()
         .fold(Vec::with_capacity(len), |mut vec, ch| {
-            vec.push(bonus_for_char(last_char, ch));
+            vec.push(bonus_for_char(last_char, ch, params));
             last_char = ch;
             vec
         })
 }
 
-fn bonus_for_char(prev: char, current: char) -> Score {
+fn bonus_for_char(prev: char, current: char, params: &ScoringParams) -> Score {
     match current {
-        'a'..='z' | '0'..='9' => bonus_for_prev(prev),
+        'a'..='z' | '0'..='9' => bonus_for_prev(prev, params),
         'A'..='Z' => match prev {
-            'a'..='z' => SCORE_MATCH_CAPITAL,
-            _ => bonus_for_prev(prev),
+            'a'..='z' => params.capital,
+            _ => bonus_for_prev(prev, params),
         },
         _ => SCORE_DEFAULT_BONUS,
     }
 }
 
-fn bonus_for_prev(ch: char) -> Score {
+fn bonus_for_prev(ch: char, params: &ScoringParams) -> Score {
     match ch {
-        '/' => SCORE_MATCH_SLASH,
-        '-' | '_' | ' ' => SCORE_MATCH_WORD,
-        '.' => SCORE_MATCH_DOT,
+        '/' => params.slash,
+        '-' | '_' | ' ' => params.word,
+        '.' => params.dot,
         _ => SCORE_DEFAULT_BONUS,
     }
 }
@@ -232,3 +310,44 @@ impl Matrix {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn word_bonus_should_favor_acronym_match() {
+        let score = |haystack: &str, params: &ScoringParams| {
+            match_and_score_with_params("fb", haystack, params)
+                .unwrap()
+                .0
+        };
+
+        let params = ScoringParams::default();
+        assert!(score("fbar_foo", &params) > score("foo_bar", &params));
+
+        let params = ScoringParams {
+            word: 300,
+            ..Default::default()
+        };
+        assert!(score("foo_bar", &params) > score("fbar_foo", &params));
+    }
+
+    #[test]
+    fn slash_bonus_should_favor_path_component_match() {
+        let score = |haystack: &str, params: &ScoringParams| {
+            match_and_score_with_params("sm", haystack, params)
+                .unwrap()
+                .0
+        };
+
+        let params = ScoringParams::default();
+        assert!(score("src/main.rs", &params) > score("osmosis.rs", &params));
+
+        let params = ScoringParams {
+            slash: 0,
+            ..Default::default()
+        };
+        assert!(score("osmosis.rs", &params) > score("src/main.rs", &params));
+    }
+}
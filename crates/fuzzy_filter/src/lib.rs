@@ -1,24 +1,56 @@
+mod matcher;
 mod source;
+mod tags;
 
 use std::collections::HashMap;
 
 use anyhow::Result;
-use rayon::prelude::*;
 use structopt::clap::arg_enum;
 
-pub use source::Source;
+pub use extracted_fzy::ScoringParams;
+pub use matcher::{cmp_scores, EffectiveQuery, MatchResult, Matcher};
+pub use source::{
+    byte_lines, lossy_lines, mmap_file, read_files_concurrently, remap_indices, trim_trailing_cr,
+    CountingReader, LossyLines, Source,
+};
 #[cfg(feature = "enable_dyn")]
 pub use subprocess;
+pub use tags::{read_tags, TagInfo};
 
 pub const DOTS: &str = "...";
 
+/// Width of clap window used for the truncation when it's not specified.
+pub const DEFAULT_WINWIDTH: usize = 62;
+
 // Implement arg_enum for using it in the command line arguments.
 arg_enum! {
-  /// Supported fuzzy match algorithm.
-  #[derive(Debug)]
+  /// Supported match algorithm, `Substring` is not fuzzy, which matches the query as a whole,
+  /// `Regex` matches the query as a regular expression.
+  #[derive(Debug, Clone, Copy)]
   pub enum Algo {
       Skim,
       Fzy,
+      Substring,
+      Regex,
+  }
+}
+
+arg_enum! {
+  /// Order of sorting the lines by number, see [`Matcher::sort_by_number`].
+  #[derive(Debug, Clone, Copy, PartialEq)]
+  pub enum SortOrder {
+      Asc,
+      Desc,
+  }
+}
+
+arg_enum! {
+  /// Whether the case of the query is respected, see [`Matcher::case_matching`].
+  #[derive(Debug, Clone, Copy, PartialEq)]
+  pub enum CaseMatching {
+      Smart,
+      Ignore,
+      Respect,
   }
 }
 
@@ -28,15 +60,14 @@ pub type LinesTruncatedMap = HashMap<String, String>;
 pub type FuzzyMatchedLineInfo = (String, i64, Vec<usize>);
 
 /// Returns the ranked results after applying the fuzzy filter
-/// given the query String and filtering source.
+/// given the matcher and filtering source.
 pub fn fuzzy_filter_and_rank<I: Iterator<Item = String>>(
-    query: &str,
     source: Source<I>,
-    algo: Algo,
+    matcher: &Matcher,
 ) -> Result<Vec<FuzzyMatchedLineInfo>> {
-    let mut ranked = source.fuzzy_filter(algo, query)?;
+    let mut ranked = source.fuzzy_filter(matcher)?;
 
-    ranked.par_sort_unstable_by(|(_, v1, _), (_, v2, _)| v2.partial_cmp(&v1).unwrap());
+    matcher.rank(&mut ranked);
 
     Ok(ranked)
 }
@@ -56,6 +87,8 @@ pub fn fuzzy_filter_and_rank<I: Iterator<Item = String>>(
 ///  `last_idx - start >= winwidth`
 /// |~~~~~~~~~~~~~~~~~~~~~~~~~~~~[xx--x------------------------------x-----]
 ///
+/// The window is measured in chars as the indices are, so the lines of multi-byte chars keep
+/// the matched chars visible too.
 pub fn truncate_long_matched_lines<T>(
     lines: impl IntoIterator<Item = (String, T, Vec<usize>)>,
     winwidth: usize,
@@ -65,45 +98,46 @@ pub fn truncate_long_matched_lines<T>(
     let lines = lines
         .into_iter()
         .map(|(line, score, indices)| {
-            if !indices.is_empty() {
-                let last_idx = indices.last().expect("indices are non-empty; qed");
-                if *last_idx > winwidth {
-                    let mut start = *last_idx - winwidth;
-                    if start >= indices[0] || (indices.len() > 1 && *last_idx - start > winwidth) {
-                        start = indices[0];
-                    }
-                    let line_len = line.len();
-                    // [--------------------------]
-                    // [-----------------------------------------------------------------xx--x--]
-                    for _ in 0..3 {
-                        if indices[0] - start >= DOTS.len() && line_len - start >= winwidth {
-                            start += DOTS.len();
-                        } else {
-                            break;
-                        }
-                    }
-                    let trailing_dist = line_len - last_idx;
-                    if trailing_dist < indices[0] - start {
-                        start += trailing_dist;
-                    }
-                    let end = line.len();
-                    let truncated = if let Some(starting_point) = starting_point {
-                        let icon: String = line.chars().take(starting_point).collect();
-                        start += starting_point;
-                        format!("{}{}{}", icon, DOTS, &line[start..end])
-                    } else {
-                        format!("{}{}", DOTS, &line[start..end])
-                    };
-                    let offset = line_len - truncated.len();
-                    let truncated_indices = indices.iter().map(|x| x - offset).collect::<Vec<_>>();
-                    truncated_map.insert(truncated.clone(), line);
-                    (truncated, score, truncated_indices)
+            let (first_idx, last_idx) = match (indices.first(), indices.last()) {
+                (Some(&first), Some(&last)) if last > winwidth => (first, last),
+                _ => return (line, score, indices),
+            };
+            let line_len = line.chars().count();
+            let mut start = last_idx - winwidth;
+            if start >= first_idx || (indices.len() > 1 && last_idx - start > winwidth) {
+                start = first_idx;
+            }
+            // [--------------------------]
+            // [-----------------------------------------------------------------xx--x--]
+            for _ in 0..3 {
+                if first_idx - start >= DOTS.len() && line_len.saturating_sub(start) >= winwidth {
+                    start += DOTS.len();
                 } else {
-                    (line, score, indices)
+                    break;
                 }
-            } else {
-                (line, score, indices)
             }
+            let trailing_dist = line_len.saturating_sub(last_idx);
+            if trailing_dist < first_idx - start {
+                start += trailing_dist;
+            }
+            let byte_offset =
+                |idx: usize| line.char_indices().nth(idx).map_or(line.len(), |(i, _)| i);
+            let truncated = match starting_point {
+                Some(starting_point) => format!(
+                    "{}{}{}",
+                    &line[..byte_offset(starting_point)],
+                    DOTS,
+                    &line[byte_offset(start + starting_point)..]
+                ),
+                None => format!("{}{}", DOTS, &line[byte_offset(start)..]),
+            };
+            // The chars before `start` are replaced with `DOTS`.
+            let truncated_indices = indices
+                .iter()
+                .map(|x| x + DOTS.len() - start)
+                .collect::<Vec<_>>();
+            truncated_map.insert(truncated.clone(), line);
+            (truncated, score, truncated_indices)
         })
         .collect::<Vec<_>>();
     (lines, truncated_map)
@@ -147,8 +181,9 @@ mod tests {
         starting_point: Option<usize>,
         winwidth: usize,
     ) {
-        let mut ranked = source.filter(Algo::Fzy, query).unwrap();
-        ranked.par_sort_unstable_by(|(_, v1, _), (_, v2, _)| v2.partial_cmp(&v1).unwrap());
+        let matcher = Matcher::new(Algo::Fzy, query);
+        let mut ranked = source.fuzzy_filter(&matcher).unwrap();
+        matcher.rank(&mut ranked);
 
         println!("");
         println!("query: {:?}", query);
@@ -227,4 +262,87 @@ mod tests {
         let query = "srlisrlisrsr";
         run_test(source, query, None, 50usize);
     }
+
+    #[test]
+    fn truncation_should_keep_matches_visible() {
+        let winwidth = 62;
+        for prefix in ["x", "é", "中"] {
+            let line = format!("{}needle tail", prefix.repeat(100));
+            let source: Source<_> = vec![line.clone()].into();
+            let ranked = fuzzy_filter_and_rank(source, &Matcher::new(Algo::Fzy, "needle")).unwrap();
+            let (truncated, truncated_map) = truncate_long_matched_lines(ranked, winwidth, None);
+
+            let (text, _, indices) = &truncated[0];
+            assert!(text.starts_with(DOTS));
+            assert_eq!(truncated_map[text], line);
+            let chars = text.chars().collect::<Vec<_>>();
+            assert!(indices.iter().all(|&i| i < winwidth));
+            assert_eq!(
+                indices.iter().map(|&i| chars[i]).collect::<String>(),
+                "needle"
+            );
+        }
+    }
+
+    #[test]
+    fn ties_should_keep_source_order() {
+        // Enough lines to be scored by several threads.
+        let lines = (0..20_000)
+            .map(|i| {
+                if i % 2 == 0 {
+                    format!("foo {:05}", i)
+                } else {
+                    format!("f_o_o {:05}", i)
+                }
+            })
+            .collect::<Vec<_>>();
+        let path = std::env::temp_dir().join(format!("fuzzy_filter_ties_{}", std::process::id()));
+        std::fs::write(&path, lines.join("\n")).unwrap();
+
+        let (exact, fuzzy): (Vec<_>, Vec<_>) =
+            lines.iter().partition(|line| line.starts_with("foo"));
+        let expected = exact.into_iter().chain(fuzzy).collect::<Vec<_>>();
+        let matcher = Matcher::new(Algo::Fzy, "foo");
+        for _ in 0..3 {
+            let ranked =
+                fuzzy_filter_and_rank(Source::<std::iter::Empty<_>>::File(path.clone()), &matcher)
+                    .unwrap();
+            assert_eq!(
+                ranked.iter().map(|(text, _, _)| text).collect::<Vec<_>>(),
+                expected
+            );
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn pairs_should_match_text_but_emit_display() {
+        assert_eq!(
+            remap_indices("fn_name  |  src/foo.rs:42", "fn_name", &[0, 3]),
+            vec![0, 3]
+        );
+        assert_eq!(remap_indices("pub fn foo()", "foo", &[0, 1]), vec![7, 8]);
+        assert_eq!(
+            remap_indices("Foo  Bar", "foobar", &[0, 3, 5]),
+            vec![0, 5, 7]
+        );
+        assert_eq!(remap_indices("abc", "xyz", &[0, 1]), Vec::<usize>::new());
+
+        let source: Source<std::iter::Empty<_>> = Source::Pairs(vec![
+            ("main  |  src/lib.rs:1".into(), "main".into()),
+            ("run  |  src/main.rs:7".into(), "run".into()),
+        ]);
+        let ranked = fuzzy_filter_and_rank(source, &Matcher::new(Algo::Fzy, "src")).unwrap();
+        assert!(ranked.is_empty());
+
+        let source: Source<std::iter::Empty<_>> = Source::Pairs(vec![
+            ("main  |  src/lib.rs:1".into(), "main".into()),
+            ("run  |  src/main.rs:7".into(), "run".into()),
+        ]);
+        let ranked = fuzzy_filter_and_rank(source, &Matcher::new(Algo::Fzy, "rn")).unwrap();
+        assert_eq!(ranked.len(), 1);
+        assert_eq!(ranked[0].0, "run  |  src/main.rs:7");
+        assert_eq!(ranked[0].2, vec![0, 2]);
+    }
 }
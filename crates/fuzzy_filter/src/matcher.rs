@@ -0,0 +1,1087 @@
+use crate::{Algo, CaseMatching, FuzzyMatchedLineInfo, SortOrder};
+use anyhow::{anyhow, Result};
+use extracted_fzy::{match_and_score_with_case, ScoringParams};
+use fuzzy_matcher::skim::fuzzy_indices;
+use icon::{strip_icon, ICON_PREFIX_LEN};
+use rayon::slice::ParallelSliceMut;
+use regex::{Regex, RegexBuilder};
+use std::cmp::Ordering;
+use std::sync::OnceLock;
+use unicode_normalization::char::{decompose_canonical, is_combining_mark};
+
+/// Tuple of (filtering score, indices of matched elements).
+pub type MatchResult = (i64, Vec<usize>);
+
+/// Compares the scores in the order of ranking, i.e., the bigger one first unless `reverse`.
+///
+/// The score which ranks before the other is `Ordering::Less`.
+pub fn cmp_scores(score1: i64, score2: i64, reverse: bool) -> Ordering {
+    if reverse {
+        score1.cmp(&score2)
+    } else {
+        score2.cmp(&score1)
+    }
+}
+
+/// The query which actually drives the matching along with the options deciding how it's
+/// matched, see [`Matcher::effective_query`].
+#[derive(Debug, Clone)]
+pub struct EffectiveQuery<'a> {
+    /// The query after trimming and ASCII folding, which has no syntax, e.g., `^`, `!` and `$`
+    /// are matched as is.
+    pub text: &'a str,
+    pub algo: Algo,
+    pub respect_case: bool,
+    pub ascii_fold: bool,
+    pub whole_word: bool,
+    /// 0-based index of the only field matched.
+    pub match_field: Option<usize>,
+    /// 0-based start and exclusive end of the only columns matched.
+    pub match_columns: Option<(usize, Option<usize>)>,
+}
+
+/// Matcher applies the filter algorithm on each candidate line given the query.
+///
+/// The query related preprocessing is done once on creation instead of per line.
+#[derive(Debug, Clone)]
+pub struct Matcher {
+    algo: Algo,
+    query: String,
+    ascii_fold: bool,
+    respect_case: bool,
+    whole_word: bool,
+    strip_icon: bool,
+    fzy_params: ScoringParams,
+    extension_bonus: Option<i64>,
+    length_ratio_bonus: Option<i64>,
+    path_boundary_penalty: Option<i64>,
+    path_aware: bool,
+    score_prefix: Option<usize>,
+    delimiter: char,
+    match_field: Option<usize>,
+    match_columns: Option<(usize, Option<usize>)>,
+    alternate_fields: Vec<usize>,
+    tiebreak_fields: Vec<usize>,
+    number_field: Option<(usize, SortOrder)>,
+    reverse: bool,
+    /// The query compiled by [`Algo::Regex`] on the first use, which is reset by the options
+    /// changing the query or the case matching.
+    regex: OnceLock<std::result::Result<Regex, String>>,
+}
+
+impl Matcher {
+    pub fn new(algo: Algo, query: &str) -> Self {
+        Self {
+            algo,
+            query: query.into(),
+            ascii_fold: false,
+            respect_case: query.chars().any(char::is_uppercase),
+            whole_word: false,
+            strip_icon: false,
+            fzy_params: ScoringParams::default(),
+            extension_bonus: None,
+            length_ratio_bonus: None,
+            path_boundary_penalty: None,
+            path_aware: false,
+            score_prefix: None,
+            delimiter: '\t',
+            match_field: None,
+            match_columns: None,
+            alternate_fields: Vec::new(),
+            tiebreak_fields: Vec::new(),
+            number_field: None,
+            reverse: false,
+            regex: OnceLock::new(),
+        }
+    }
+
+    /// Match against the ASCII folded form of query and line, e.g., `cafe` matches `café`.
+    ///
+    /// The indices still point to the chars of the original line.
+    pub fn ascii_fold(mut self, ascii_fold: bool) -> Self {
+        if ascii_fold {
+            self.query = fold_to_ascii(&self.query).0;
+        }
+        self.ascii_fold = ascii_fold;
+        self.regex = OnceLock::new();
+        self
+    }
+
+    /// Whether the chars must be of the same case, [`CaseMatching::Smart`] by default, i.e.,
+    /// the case is respected only if the query contains an uppercase char.
+    ///
+    /// [`Algo::Skim`] always ignores the case.
+    pub fn case_matching(mut self, case_matching: CaseMatching) -> Self {
+        self.respect_case = match case_matching {
+            CaseMatching::Smart => self.query.chars().any(char::is_uppercase),
+            CaseMatching::Ignore => false,
+            CaseMatching::Respect => true,
+        };
+        self.regex = OnceLock::new();
+        self
+    }
+
+    /// Only match the query as a whole word, e.g., `err` matches `err handler` but not `error`.
+    ///
+    /// The indices are the span of the matched word.
+    pub fn whole_word(mut self, whole_word: bool) -> Self {
+        self.whole_word = whole_word;
+        self
+    }
+
+    /// Ignore the icon prepended to the line, e.g., the cached lines that have been iconized.
+    ///
+    /// The indices still point to the chars of the line including the icon.
+    pub fn strip_icon(mut self, strip_icon: bool) -> Self {
+        self.strip_icon = strip_icon;
+        self
+    }
+
+    /// Bonus weights used by [`Algo::Fzy`].
+    pub fn fzy_params(mut self, fzy_params: ScoringParams) -> Self {
+        self.fzy_params = fzy_params;
+        self
+    }
+
+    /// Extra score for the lines whose file extension is the query, e.g., `rs` for `main.rs`.
+    ///
+    /// Half of the bonus is given if the query is only a prefix of the extension.
+    pub fn extension_bonus(mut self, bonus: Option<i64>) -> Self {
+        self.extension_bonus = bonus;
+        self
+    }
+
+    /// Extra score proportional to the length of query relative to the line, i.e.,
+    /// `bonus * query_len / line_len`, which favors the concise matches.
+    pub fn length_ratio_bonus(mut self, bonus: Option<i64>) -> Self {
+        self.length_ratio_bonus = bonus;
+        self
+    }
+
+    /// Score subtracted for each path separator between the first and last matched chars, so
+    /// that the query matched within a path component outranks the one spread across them.
+    pub fn path_boundary_penalty(mut self, penalty: Option<i64>) -> Self {
+        self.path_boundary_penalty = penalty;
+        self
+    }
+
+    /// Extra [`PATH_SEGMENT_BONUS`] for each matched char at the start of a path segment or
+    /// word, i.e., after `/`, `_`, `-` or a lowercase char if it's uppercase.
+    pub fn path_aware(mut self, path_aware: bool) -> Self {
+        self.path_aware = path_aware;
+        self
+    }
+
+    /// Only score the first `len` bytes of each line, shortened to a char boundary if necessary,
+    /// e.g., the leading identifier followed by a long comment.
+    pub fn score_prefix(mut self, len: Option<usize>) -> Self {
+        self.score_prefix = len;
+        self
+    }
+
+    /// Delimiter of the fields in a structured line, `\t` by default.
+    pub fn delimiter(mut self, delimiter: char) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Match against the field at 0-based `index` only instead of the whole line.
+    ///
+    /// The lines without such field are never matched.
+    pub fn match_field(mut self, index: Option<usize>) -> Self {
+        self.match_field = index;
+        self
+    }
+
+    /// Match against the chars in the 0-based column range `[start, end)` only instead of the
+    /// whole line, which suits the columnar output padded with spaces, e.g., `ps`.
+    ///
+    /// The range extends to the end of line if `end` is None, the lines shorter than `start`
+    /// are never matched. Ignored if the match field is set.
+    pub fn match_columns(mut self, columns: Option<(usize, Option<usize>)>) -> Self {
+        self.match_columns = columns;
+        self
+    }
+
+    /// Fields at 0-based indices matched in addition to the line or the match field, e.g.,
+    /// the unqualified name of a symbol.
+    ///
+    /// The best scored one wins, its indices are mapped to the positions in the whole line.
+    pub fn alternate_fields(mut self, indices: Vec<usize>) -> Self {
+        self.alternate_fields = indices;
+        self
+    }
+
+    /// Fields at 0-based indices used to order the lines with the same score, see [`Matcher::rank`].
+    pub fn tiebreak_fields(mut self, indices: Vec<usize>) -> Self {
+        self.tiebreak_fields = indices;
+        self
+    }
+
+    /// Rank the matched lines by the leading number of the field at 0-based `index` instead of
+    /// the score, e.g., `123` of `123ms`, the query is then only used for filtering.
+    ///
+    /// The lines without such number are ranked last.
+    pub fn sort_by_number(mut self, index: Option<usize>, order: SortOrder) -> Self {
+        self.number_field = index.map(|index| (index, order));
+        self
+    }
+
+    /// Rank the worst matched lines first, the score is still the real one, see [`cmp_scores`].
+    pub fn reverse(mut self, reverse: bool) -> Self {
+        self.reverse = reverse;
+        self
+    }
+
+    /// Returns true if the worst matched lines are ranked first, which applies to the top items
+    /// selected while collecting as well.
+    pub fn ranks_reversed(&self) -> bool {
+        self.reverse
+    }
+
+    /// Returns true if the lines are ranked by the score, i.e., the best lines can be selected
+    /// by the score while collecting.
+    pub fn ranks_by_score(&self) -> bool {
+        self.number_field.is_none()
+    }
+
+    pub fn query(&self) -> &str {
+        &self.query
+    }
+
+    /// Returns the query used for matching and the options deciding how it's matched.
+    pub fn effective_query(&self) -> EffectiveQuery<'_> {
+        EffectiveQuery {
+            text: &self.query,
+            algo: self.algo,
+            respect_case: self.respect_case,
+            ascii_fold: self.ascii_fold,
+            whole_word: self.whole_word,
+            match_field: self.match_field,
+            match_columns: self.match_columns,
+        }
+    }
+
+    /// Returns the error if the query is invalid for the algorithm, i.e., not a valid regex for
+    /// [`Algo::Regex`], which matches nothing otherwise.
+    pub fn check_query(&self) -> Result<()> {
+        match self.algo {
+            Algo::Regex => self.regex().map(|_| ()).map_err(|e| anyhow!("{}", e)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the query compiled as a regex, the case is ignored unless it's respected.
+    fn regex(&self) -> std::result::Result<&Regex, &String> {
+        self.regex
+            .get_or_init(|| {
+                RegexBuilder::new(&self.query)
+                    .case_insensitive(!self.respect_case)
+                    .build()
+                    .map_err(|e| format!("Invalid regex {:?}: {}", self.query, e))
+            })
+            .as_ref()
+    }
+
+    /// Returns the score and indices of matched chars if `line` matches the query.
+    pub fn match_line(&self, line: &str) -> Option<MatchResult> {
+        if self.strip_icon {
+            if let Some(stripped) = strip_icon(line) {
+                return self.match_line(stripped).map(|(score, indices)| {
+                    let indices = indices.into_iter().map(|i| i + ICON_PREFIX_LEN).collect();
+                    (score, indices)
+                });
+            }
+        }
+        let matched = match (self.match_field, self.match_columns) {
+            (Some(index), _) => self.match_nth_field(line, index),
+            (None, Some((start, end))) => {
+                let field = columns(line, start, end)?;
+                self.match_text(field).map(|(score, indices)| {
+                    (score, indices.into_iter().map(|i| i + start).collect())
+                })
+            }
+            (None, None) => self.match_text(line),
+        };
+        self.alternate_fields
+            .iter()
+            .filter_map(|&index| self.match_nth_field(line, index))
+            .fold(matched, |best, matched| match best {
+                Some(best) if best.0 >= matched.0 => Some(best),
+                _ => Some(matched),
+            })
+    }
+
+    fn match_nth_field(&self, line: &str, index: usize) -> Option<MatchResult> {
+        let (offset, field) = nth_field(line, self.delimiter, index)?;
+        self.match_text(field)
+            .map(|(score, indices)| (score, indices.into_iter().map(|i| i + offset).collect()))
+    }
+
+    /// Sorts the matched lines by the score in descending order, or ascending order if
+    /// reversed, the lines with the same score are ordered by the tiebreak fields in
+    /// descending order, e.g., the latest date first.
+    ///
+    /// The lines with the same score and tiebreak fields keep their source order.
+    pub fn rank(&self, ranked: &mut [FuzzyMatchedLineInfo]) {
+        if let Some((index, order)) = self.number_field {
+            ranked.par_sort_by_cached_key(|(text, score, _)| {
+                let number = nth_field(text, self.delimiter, index)
+                    .and_then(|(_, field)| leading_number(field))
+                    .map(|n| match order {
+                        SortOrder::Asc => OrderedNumber(n),
+                        SortOrder::Desc => OrderedNumber(-n),
+                    });
+                // Widened so that negating any score never overflows.
+                let score = i128::from(*score);
+                (
+                    number.is_none(),
+                    number,
+                    if self.reverse { score } else { -score },
+                )
+            });
+            return;
+        }
+        // Nothing to do if all lines score the same, e.g., the query matches everything equally.
+        if self.tiebreak_fields.is_empty() && ranked.windows(2).all(|w| w[0].1 == w[1].1) {
+            return;
+        }
+        ranked.par_sort_by(|(text1, score1, _), (text2, score2, _)| {
+            cmp_scores(*score1, *score2, self.reverse)
+                .then_with(|| self.cmp_tiebreak_fields(text2, text1))
+        });
+    }
+
+    /// Same as [`Matcher::rank`], but only keeps the top `cap` lines, which saves sorting all
+    /// of the huge number of matched lines.
+    pub fn rank_top(&self, ranked: &mut Vec<FuzzyMatchedLineInfo>, cap: usize) {
+        if ranked.len() > cap && self.ranks_by_score() {
+            if cap == 0 {
+                ranked.clear();
+                return;
+            }
+            let mut scores = ranked
+                .iter()
+                .map(|(_, score, _)| *score)
+                .collect::<Vec<_>>();
+            let (_, &mut threshold, _) =
+                scores.select_nth_unstable_by(cap - 1, |a, b| cmp_scores(*a, *b, self.reverse));
+            let ranks_before = |score: i64| cmp_scores(score, threshold, self.reverse).is_lt();
+            // Keep the ones with the lowest ranked score in the source order unless the
+            // tiebreak fields are used, which are ranked later.
+            let mut lowest_to_keep = if self.tiebreak_fields.is_empty() {
+                cap - scores[..cap - 1]
+                    .iter()
+                    .filter(|&&s| ranks_before(s))
+                    .count()
+            } else {
+                usize::MAX
+            };
+            ranked.retain(|(_, score, _)| {
+                if ranks_before(*score) {
+                    true
+                } else if *score == threshold && lowest_to_keep > 0 {
+                    lowest_to_keep -= 1;
+                    true
+                } else {
+                    false
+                }
+            });
+        }
+        self.rank(ranked);
+        ranked.truncate(cap);
+    }
+
+    fn cmp_tiebreak_fields(&self, line1: &str, line2: &str) -> Ordering {
+        self.tiebreak_fields
+            .iter()
+            .map(|&index| {
+                let field1 = nth_field(line1, self.delimiter, index).map(|(_, f)| f);
+                let field2 = nth_field(line2, self.delimiter, index).map(|(_, f)| f);
+                field1.cmp(&field2)
+            })
+            .find(|ordering| *ordering != Ordering::Equal)
+            .unwrap_or(Ordering::Equal)
+    }
+
+    fn match_text(&self, line: &str) -> Option<MatchResult> {
+        let line = match self.score_prefix {
+            Some(len) => truncate_at_char_boundary(line, len),
+            None => line,
+        };
+        if self.ascii_fold && !line.is_ascii() {
+            let (folded, origin) = fold_to_ascii(line);
+            return self.score(&folded).map(|(score, indices)| {
+                let mut indices = indices.into_iter().map(|i| origin[i]).collect::<Vec<_>>();
+                indices.dedup();
+                (score, indices)
+            });
+        }
+        self.score(line)
+    }
+
+    fn score(&self, line: &str) -> Option<MatchResult> {
+        let word_indices = if self.whole_word {
+            Some(find_whole_word(line, &self.query, !self.respect_case)?)
+        } else {
+            None
+        };
+        let (score, indices) = match self.algo {
+            Algo::Skim => fuzzy_indices(line, &self.query),
+            // The fzy score is already a fixed-point integer, no precision is lost here.
+            Algo::Fzy => {
+                match_and_score_with_case(&self.query, line, &self.fzy_params, self.respect_case)
+                    .map(|(score, indices)| (score as i64, indices))
+            }
+            Algo::Substring => substring_indices(line, &self.query, !self.respect_case),
+            Algo::Regex => regex_indices(line, self.regex().ok()?),
+        }?;
+        let score = match self.extension_bonus {
+            Some(bonus) => score + extension_bonus(line, &self.query, bonus),
+            None => score,
+        };
+        let score = match self.length_ratio_bonus {
+            Some(bonus) => score + length_ratio_bonus(line, &self.query, bonus),
+            None => score,
+        };
+        let score = match self.path_boundary_penalty {
+            Some(penalty) => score - penalty * path_boundaries_crossed(line, &indices) as i64,
+            None => score,
+        };
+        let score = if self.path_aware {
+            score + PATH_SEGMENT_BONUS * segment_starts_matched(line, &indices) as i64
+        } else {
+            score
+        };
+        Some((score, word_indices.unwrap_or(indices)))
+    }
+}
+
+/// Returns `bonus` scaled by the ratio of the query length to the line length in chars.
+fn length_ratio_bonus(line: &str, query: &str, bonus: i64) -> i64 {
+    let line_len = line.chars().count() as i64;
+    if line_len == 0 {
+        0
+    } else {
+        bonus * query.chars().count() as i64 / line_len
+    }
+}
+
+/// Returns the number of path separators between the first and last matched chars.
+fn path_boundaries_crossed(line: &str, indices: &[usize]) -> usize {
+    match (indices.iter().min(), indices.iter().max()) {
+        (Some(&first), Some(&last)) => line
+            .chars()
+            .skip(first)
+            .take(last - first)
+            .filter(|&c| c == '/' || c == '\\')
+            .count(),
+        _ => 0,
+    }
+}
+
+/// Bonus of [`Matcher::path_aware`] for each matched char starting a path segment or word.
+const PATH_SEGMENT_BONUS: i64 = 100;
+
+/// Returns the number of matched chars at the start of a path segment or word, including
+/// the first char of the line.
+fn segment_starts_matched(line: &str, indices: &[usize]) -> usize {
+    let mut prev = None;
+    let mut indices = indices.iter().peekable();
+    let mut count = 0;
+    for (idx, c) in line.chars().enumerate() {
+        if indices.peek().is_none() {
+            break;
+        }
+        if indices.peek() == Some(&&idx) {
+            indices.next();
+            let starts_segment = match prev {
+                None => true,
+                Some(p) => matches!(p, '/' | '_' | '-') || (p.is_lowercase() && c.is_uppercase()),
+            };
+            if starts_segment {
+                count += 1;
+            }
+        }
+        prev = Some(c);
+    }
+    count
+}
+
+/// Returns `bonus` if `query` is the extension of the file name in `line`, half of it if
+/// `query` is a prefix of the extension, 0 otherwise. The case is ignored.
+fn extension_bonus(line: &str, query: &str, bonus: i64) -> i64 {
+    let file_name = line.rsplit(&['/', '\\'][..]).next().unwrap_or(line);
+    let extension = match file_name.rfind('.') {
+        Some(idx) if idx > 0 => &file_name[idx + 1..],
+        _ => return 0,
+    };
+    if query.is_empty() || extension.len() < query.len() || !extension.is_char_boundary(query.len())
+    {
+        0
+    } else if extension.eq_ignore_ascii_case(query) {
+        bonus
+    } else if extension[..query.len()].eq_ignore_ascii_case(query) {
+        bonus / 2
+    } else {
+        0
+    }
+}
+
+/// Bonus of [`Algo::Substring`] for the match at the beginning of a word.
+const SUBSTRING_WORD_BONUS: i64 = 1000;
+
+/// Returns the best scored occurrence of `query` in `line`, the earlier one is scored higher and
+/// the one at the beginning of a word gets [`SUBSTRING_WORD_BONUS`].
+fn substring_indices(line: &str, query: &str, ignore_case: bool) -> Option<MatchResult> {
+    let eq = |a: char, b: char| {
+        if ignore_case {
+            a.to_lowercase().eq(b.to_lowercase())
+        } else {
+            a == b
+        }
+    };
+    let line = line.chars().collect::<Vec<_>>();
+    let query = query.chars().collect::<Vec<_>>();
+    if query.is_empty() {
+        return Some((0, Vec::new()));
+    }
+    if query.len() > line.len() {
+        return None;
+    }
+    (0..=line.len() - query.len())
+        .filter(|&start| {
+            line[start..start + query.len()]
+                .iter()
+                .zip(&query)
+                .all(|(&a, &b)| eq(a, b))
+        })
+        .map(|start| {
+            let at_word_start = start == 0 || !line[start - 1].is_alphanumeric();
+            let bonus = if at_word_start {
+                SUBSTRING_WORD_BONUS
+            } else {
+                0
+            };
+            (bonus - start as i64, start)
+        })
+        .max_by_key(|&(score, start)| (score, std::cmp::Reverse(start)))
+        .map(|(score, start)| (score, (start..start + query.len()).collect()))
+}
+
+/// Weight of the length of the [`Algo::Regex`] match in chars.
+const REGEX_LENGTH_WEIGHT: i64 = 100;
+
+/// Returns the first match of `regex` in `line`, the longer and the earlier the match the
+/// higher the score.
+fn regex_indices(line: &str, regex: &Regex) -> Option<MatchResult> {
+    let m = regex.find(line)?;
+    let start = line[..m.start()].chars().count();
+    let len = m.as_str().chars().count();
+    Some((
+        len as i64 * REGEX_LENGTH_WEIGHT - start as i64,
+        (start..start + len).collect(),
+    ))
+}
+
+/// Returns the char indices of the first occurrence of `word` in `line` as a whole word.
+fn find_whole_word(line: &str, word: &str, ignore_case: bool) -> Option<Vec<usize>> {
+    let eq = |a: char, b: char| {
+        if ignore_case {
+            a.to_lowercase().eq(b.to_lowercase())
+        } else {
+            a == b
+        }
+    };
+    let is_word_char = |c: char| c.is_alphanumeric() || c == '_';
+
+    let line = line.chars().collect::<Vec<_>>();
+    let word = word.chars().collect::<Vec<_>>();
+    if word.is_empty() || word.len() > line.len() {
+        return None;
+    }
+    (0..=line.len() - word.len())
+        .find(|&start| {
+            let end = start + word.len();
+            (start == 0 || !is_word_char(line[start - 1]))
+                && (end == line.len() || !is_word_char(line[end]))
+                && line[start..end].iter().zip(&word).all(|(&a, &b)| eq(a, b))
+        })
+        .map(|start| (start..start + word.len()).collect())
+}
+
+/// Returns the longest prefix of `text` in `len` bytes that ends at a char boundary.
+fn truncate_at_char_boundary(text: &str, len: usize) -> &str {
+    if len >= text.len() {
+        return text;
+    }
+    let mut end = len;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+/// Number totally ordered for sorting, which is never NaN.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct OrderedNumber(f64);
+
+impl Eq for OrderedNumber {}
+
+impl PartialOrd for OrderedNumber {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OrderedNumber {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.partial_cmp(&other.0).unwrap_or(Ordering::Equal)
+    }
+}
+
+/// Returns the number at the beginning of `text`, e.g., `1.5` of `1.5s`.
+fn leading_number(text: &str) -> Option<f64> {
+    let text = text.trim_start();
+    let end = text
+        .char_indices()
+        .find(|&(idx, c)| !(c.is_ascii_digit() || c == '.' || (idx == 0 && c == '-')))
+        .map(|(idx, _)| idx)
+        .unwrap_or_else(|| text.len());
+    text[..end].parse().ok()
+}
+
+/// Returns the char offset and content of the field at 0-based `index` in `line`.
+fn nth_field(line: &str, delimiter: char, index: usize) -> Option<(usize, &str)> {
+    let mut offset = 0;
+    for (idx, field) in line.split(delimiter).enumerate() {
+        if idx == index {
+            return Some((offset, field));
+        }
+        offset += field.chars().count() + 1;
+    }
+    None
+}
+
+/// Returns the chars of `line` in the column range `[start, end)`, None if `line` is shorter than
+/// `start`.
+fn columns(line: &str, start: usize, end: Option<usize>) -> Option<&str> {
+    let byte_offset = |column| line.char_indices().nth(column).map(|(idx, _)| idx);
+    let start_byte = byte_offset(start)?;
+    let end_byte = end
+        .and_then(|end| byte_offset(end.max(start)))
+        .unwrap_or(line.len());
+    Some(&line[start_byte..end_byte])
+}
+
+/// Strips the diacritics of `text` by removing the combining marks of its canonical decomposition.
+///
+/// Returns the folded text and the index of the original char for each folded char.
+fn fold_to_ascii(text: &str) -> (String, Vec<usize>) {
+    let mut folded = String::with_capacity(text.len());
+    let mut origin = Vec::with_capacity(text.len());
+    for (idx, ch) in text.chars().enumerate() {
+        decompose_canonical(ch, |c| {
+            if !is_combining_mark(c) {
+                folded.push(c);
+                origin.push(idx);
+            }
+        });
+    }
+    (folded, origin)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the matched `lines` ranked by `matcher`.
+    fn ranked_texts<'a>(matcher: &Matcher, lines: &[&'a str]) -> Vec<&'a str> {
+        let mut ranked = lines
+            .iter()
+            .filter_map(|line| {
+                matcher
+                    .match_line(line)
+                    .map(|(score, indices)| (line.to_string(), score, indices))
+            })
+            .collect::<Vec<_>>();
+        matcher.rank(&mut ranked);
+        ranked
+            .into_iter()
+            .map(|(text, _, _)| *lines.iter().find(|&&line| line == text).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn ascii_fold_should_map_indices_to_original_chars() {
+        let matcher = Matcher::new(Algo::Fzy, "resume").ascii_fold(true);
+        assert_eq!(
+            matcher
+                .match_line("my résumé.pdf")
+                .map(|(_, indices)| indices),
+            Some(vec![3, 4, 5, 6, 7, 8])
+        );
+
+        let matcher = Matcher::new(Algo::Fzy, "cafe").ascii_fold(true);
+        let (_, indices) = matcher.match_line("le café.txt").unwrap();
+        assert_eq!(indices, vec![3, 4, 5, 6]);
+
+        assert!(Matcher::new(Algo::Fzy, "resume")
+            .match_line("my résumé.pdf")
+            .is_none());
+    }
+
+    #[test]
+    fn fzy_near_ties_should_not_collapse() {
+        let matcher = Matcher::new(Algo::Fzy, "abc");
+        let (score1, _) = matcher.match_line("xabc").unwrap();
+        let (score2, _) = matcher.match_line("xxabc").unwrap();
+        // Only differ by one leading gap.
+        assert_eq!(score1 - score2, 1);
+    }
+
+    #[test]
+    fn substring_should_favor_earlier_and_word_start_matches() {
+        let matcher = Matcher::new(Algo::Substring, "Map").case_matching(CaseMatching::Ignore);
+        assert_eq!(
+            matcher.match_line("src/hashmap/map.rs").map(|(_, i)| i),
+            Some(vec![12, 13, 14])
+        );
+        // Fuzzy hits are not matched.
+        assert!(matcher.match_line("src/m_a_p.rs").is_none());
+
+        let lines = [
+            "src/hashmap.rs",
+            "map/mod.rs",
+            "src/map.rs",
+            "src/bitmap.rs",
+        ];
+        assert_eq!(
+            ranked_texts(&matcher, &lines),
+            vec![
+                "map/mod.rs",
+                "src/map.rs",
+                "src/bitmap.rs",
+                "src/hashmap.rs"
+            ]
+        );
+    }
+
+    #[test]
+    fn smart_case_should_respect_case_of_uppercase_query() {
+        let matched = |matcher: Matcher| {
+            ["src/Matcher.rs", "src/matcher.rs"]
+                .iter()
+                .filter(|line| matcher.match_line(line).is_some())
+                .copied()
+                .collect::<Vec<_>>()
+        };
+        for algo in [Algo::Fzy, Algo::Substring] {
+            assert_eq!(
+                matched(Matcher::new(algo, "match")),
+                vec!["src/Matcher.rs", "src/matcher.rs"]
+            );
+            assert_eq!(matched(Matcher::new(algo, "Match")), vec!["src/Matcher.rs"]);
+            assert_eq!(
+                matched(Matcher::new(algo, "Match").case_matching(CaseMatching::Ignore)),
+                vec!["src/Matcher.rs", "src/matcher.rs"]
+            );
+            assert_eq!(
+                matched(Matcher::new(algo, "match").case_matching(CaseMatching::Respect)),
+                vec!["src/matcher.rs"]
+            );
+        }
+    }
+
+    #[test]
+    fn path_boundary_penalty_should_favor_match_within_component() {
+        let spread = "foo/bar/lib.rs";
+        let within = "src/foobar.rs";
+        let score = |matcher: &Matcher, line| matcher.match_line(line).unwrap().0;
+
+        let matcher = Matcher::new(Algo::Fzy, "fb");
+        assert!(score(&matcher, spread) > score(&matcher, within));
+
+        let penalized = matcher.clone().path_boundary_penalty(Some(1000));
+        assert_eq!(score(&penalized, spread), score(&matcher, spread) - 1000);
+        assert_eq!(score(&penalized, within), score(&matcher, within));
+        assert!(score(&penalized, within) > score(&penalized, spread));
+    }
+
+    #[test]
+    fn path_aware_should_favor_segment_starts() {
+        let score = |matcher: &Matcher, line| matcher.match_line(line).unwrap().0;
+        let matcher = Matcher::new(Algo::Fzy, "fb");
+        let path_aware = matcher.clone().path_aware(true);
+
+        assert_eq!(segment_starts_matched("foo/bar", &[0, 4]), 2);
+        assert_eq!(segment_starts_matched("fooBar", &[0, 3]), 2);
+        assert_eq!(segment_starts_matched("xfyb", &[1, 3]), 0);
+
+        assert_eq!(
+            score(&path_aware, "foo-bar.rs"),
+            score(&matcher, "foo-bar.rs") + 2 * PATH_SEGMENT_BONUS
+        );
+        assert_eq!(score(&path_aware, "xfyb.rs"), score(&matcher, "xfyb.rs"));
+    }
+
+    #[test]
+    fn extension_bonus_should_favor_matched_extension() {
+        let lines = [
+            "spec/fixtures/rspec_helper.txt",
+            "lib/tasks/ci.rspec",
+            "crates/maple_cli/src/cmd/grep.rs",
+        ];
+        assert_ne!(
+            ranked_texts(&Matcher::new(Algo::Fzy, "rs"), &lines)[0],
+            lines[2]
+        );
+        assert_eq!(
+            ranked_texts(
+                &Matcher::new(Algo::Fzy, "rs").extension_bonus(Some(1000)),
+                &lines
+            ),
+            vec![lines[2], lines[1], lines[0]]
+        );
+    }
+
+    #[test]
+    fn score_prefix_should_ignore_trailing_matches() {
+        let matcher = Matcher::new(Algo::Fzy, "todo").score_prefix(Some(12));
+        assert!(matcher
+            .match_line("parse_args  // todo: validate")
+            .is_none());
+        assert_eq!(
+            matcher
+                .match_line("todo_list   // parse args")
+                .map(|(_, indices)| indices),
+            Some(vec![0, 1, 2, 3])
+        );
+
+        // Never splits a multibyte char.
+        let matcher = Matcher::new(Algo::Fzy, "é").score_prefix(Some(2));
+        assert!(matcher.match_line("aé").is_none());
+    }
+
+    #[test]
+    fn alternate_fields_should_take_the_best_match() {
+        let line = "std::collections::hash_map::HashMap\tHashMap";
+        let matcher = Matcher::new(Algo::Fzy, "hmap").match_field(Some(0));
+        let (qualified_score, _) = matcher.match_line(line).unwrap();
+
+        let matcher = matcher.alternate_fields(vec![1]);
+        let (score, indices) = matcher.match_line(line).unwrap();
+        assert!(score > qualified_score);
+        // `HashMap` starts at 36 in the whole line.
+        assert_eq!(indices, vec![39, 40, 41, 42]);
+
+        // Falls back to the alternate field if the main one doesn't match.
+        let matcher = Matcher::new(Algo::Fzy, "hm")
+            .match_field(Some(0))
+            .alternate_fields(vec![1]);
+        assert_eq!(
+            matcher.match_line("std::map\tHashMap").map(|(_, i)| i),
+            Some(vec![9, 13])
+        );
+    }
+
+    #[test]
+    fn rank_top_should_keep_the_top_of_full_rank() {
+        let matcher = Matcher::new(Algo::Fzy, "");
+        let lines = (0..1000)
+            .map(|i| (format!("line{}", i), (i * 37 % 100) as i64, vec![]))
+            .collect::<Vec<_>>();
+        let mut expected = lines.clone();
+        matcher.rank(&mut expected);
+
+        for &cap in &[0, 1, 25, 999, 1000, 2000] {
+            let mut ranked = lines.clone();
+            matcher.rank_top(&mut ranked, cap);
+            assert_eq!(ranked[..], expected[..cap.min(1000)]);
+        }
+    }
+
+    #[test]
+    fn iconized_line_should_score_as_raw_one() {
+        let raw = "crates/maple_cli/src/cmd/grep.rs";
+        let iconized = icon::prepend_icon(raw);
+        let matcher = Matcher::new(Algo::Fzy, "grep").strip_icon(true);
+
+        let (raw_score, raw_indices) = matcher.match_line(raw).unwrap();
+        let (score, indices) = matcher.match_line(&iconized).unwrap();
+        assert_eq!(score, raw_score);
+        assert_eq!(
+            indices,
+            raw_indices.iter().map(|i| i + 2).collect::<Vec<_>>()
+        );
+
+        // Only the known icons are stripped.
+        let (score, _) = matcher.match_line(&format!("x {}", raw)).unwrap();
+        assert_ne!(score, raw_score);
+    }
+
+    #[test]
+    fn sort_by_number_should_rank_by_leading_number() {
+        let matcher = Matcher::new(Algo::Fzy, "test").sort_by_number(Some(0), SortOrder::Desc);
+        let lines = [
+            "12ms\ttest_parse",
+            "n/a\ttest_skipped",
+            "1500ms\ttest_index",
+            "-3ms\ttest_clock",
+            "230.5ms\ttest_grep",
+        ];
+
+        assert_eq!(
+            ranked_texts(&matcher, &lines)
+                .iter()
+                .map(|text| text.split('\t').nth(1).unwrap())
+                .collect::<Vec<_>>(),
+            vec![
+                "test_index",
+                "test_grep",
+                "test_parse",
+                "test_clock",
+                "test_skipped"
+            ]
+        );
+
+        let ranked = ranked_texts(&matcher.sort_by_number(Some(0), SortOrder::Asc), &lines);
+        assert_eq!(ranked[0], "-3ms\ttest_clock");
+        assert_eq!(ranked[4], "n/a\ttest_skipped");
+    }
+
+    #[test]
+    fn reverse_should_rank_worst_first() {
+        let lines = ["xaxxbxxc", "abcd", "a_b_c"];
+        let best_first = ranked_texts(&Matcher::new(Algo::Fzy, "abc"), &lines);
+        let mut worst_first = ranked_texts(&Matcher::new(Algo::Fzy, "abc").reverse(true), &lines);
+        assert_eq!(best_first, vec!["abcd", "a_b_c", "xaxxbxxc"]);
+        worst_first.reverse();
+        assert_eq!(worst_first, best_first);
+
+        // The score is the real one.
+        let reversed = Matcher::new(Algo::Fzy, "abc").reverse(true);
+        assert_eq!(
+            reversed.match_line("abcd"),
+            Matcher::new(Algo::Fzy, "abc").match_line("abcd")
+        );
+
+        let mut top = lines
+            .iter()
+            .filter_map(|line| {
+                let (score, indices) = reversed.match_line(line)?;
+                Some((line.to_string(), score, indices))
+            })
+            .collect::<Vec<_>>();
+        reversed.rank_top(&mut top, 2);
+        assert_eq!(
+            top.into_iter().map(|(text, _, _)| text).collect::<Vec<_>>(),
+            vec!["xaxxbxxc", "a_b_c"]
+        );
+    }
+
+    #[test]
+    fn length_ratio_bonus_should_favor_shorter_line() {
+        let long_line = format!("src/{}main.rs", "nested/".repeat(27));
+        let bonus_of = |line: &str| {
+            let plain = Matcher::new(Algo::Fzy, "main").match_line(line).unwrap().0;
+            let boosted = Matcher::new(Algo::Fzy, "main")
+                .length_ratio_bonus(Some(1000))
+                .match_line(line)
+                .unwrap()
+                .0;
+            boosted - plain
+        };
+        assert_eq!(bonus_of("main.rs"), 1000 * 4 / 7);
+        assert_eq!(bonus_of(&long_line), 1000 * 4 / 200);
+        assert!(bonus_of("main.rs") > bonus_of(&long_line));
+    }
+
+    #[test]
+    fn whole_word_should_not_match_inside_word() {
+        let matcher = Matcher::new(Algo::Fzy, "err").whole_word(true);
+        assert_eq!(
+            matcher
+                .match_line("on err handler")
+                .map(|(_, indices)| indices),
+            Some(vec![3, 4, 5])
+        );
+        assert!(matcher.match_line("error").is_none());
+        assert!(matcher.match_line("on_err").is_none());
+
+        let matcher = Matcher::new(Algo::Skim, "err").whole_word(true);
+        assert!(matcher.match_line("Err handler").is_some());
+        assert!(matcher.match_line("terminal").is_none());
+    }
+
+    #[test]
+    fn match_field_should_rank_ties_by_tiebreak_fields() {
+        let records = [
+            "a1b2c3\t2020-03-01\tfix typo",
+            "d4e5f6\t2020-05-01\tfix typo",
+            "f1x7y0\t2020-06-01\tadd docs",
+            "0a9b8c\t2020-04-01\tfix typo",
+        ];
+        let matcher = Matcher::new(Algo::Fzy, "fix")
+            .match_field(Some(2))
+            .tiebreak_fields(vec![1]);
+
+        assert_eq!(
+            ranked_texts(&matcher, &records),
+            vec![records[1], records[3], records[0]]
+        );
+        assert_eq!(
+            matcher.match_line(records[1]).map(|(_, indices)| indices),
+            Some(vec![18, 19, 20])
+        );
+    }
+
+    #[test]
+    fn regex_should_rank_longer_and_earlier_matches_first() {
+        let matcher = Matcher::new(Algo::Regex, r"fn \w+");
+        assert!(matcher.check_query().is_ok());
+        assert!(matcher.match_line("let x = 1;").is_none());
+
+        let (score, indices) = matcher.match_line("pub fn main() {").unwrap();
+        assert_eq!(indices, (4..11).collect::<Vec<_>>());
+        assert!(matcher.match_line("fn main() {").unwrap().0 > score);
+        assert!(matcher.match_line("pub fn run() {").unwrap().0 < score);
+
+        // The case is smart as the other algorithms.
+        assert!(Matcher::new(Algo::Regex, "MAIN")
+            .match_line("fn main")
+            .is_none());
+        assert!(Matcher::new(Algo::Regex, "main")
+            .match_line("fn MAIN")
+            .is_some());
+        let matcher = Matcher::new(Algo::Regex, "héllo").ascii_fold(true);
+        assert_eq!(
+            matcher.match_line("say héllo").unwrap().1,
+            vec![4, 5, 6, 7, 8]
+        );
+    }
+
+    #[test]
+    fn invalid_regex_should_be_an_error() {
+        let matcher = Matcher::new(Algo::Regex, "fn (");
+        let err = matcher.check_query().unwrap_err().to_string();
+        assert!(err.starts_with("Invalid regex \"fn (\""), "{}", err);
+        assert!(matcher.match_line("fn (").is_none());
+
+        assert!(Matcher::new(Algo::Fzy, "fn (").check_query().is_ok());
+    }
+
+    #[test]
+    fn regex_should_be_recompiled_after_changing_query_options() {
+        let matcher = Matcher::new(Algo::Regex, "café");
+        matcher.check_query().unwrap();
+        assert!(matcher.match_line("le cafe").is_none());
+        let matcher = matcher.ascii_fold(true);
+        assert!(matcher.match_line("le cafe").is_some());
+
+        let matcher = Matcher::new(Algo::Regex, "main");
+        assert!(matcher.match_line("fn MAIN()").is_some());
+        let matcher = matcher.case_matching(CaseMatching::Respect);
+        assert!(matcher.match_line("fn MAIN()").is_none());
+    }
+}
@@ -1,13 +1,22 @@
-use crate::{Algo, FuzzyMatchedLineInfo};
-use anyhow::Result;
-use extracted_fzy::match_and_score_with_positions;
-use fuzzy_matcher::skim::fuzzy_indices;
+use crate::tags::read_tags;
+use crate::{FuzzyMatchedLineInfo, MatchResult, Matcher};
+use anyhow::{Context, Result};
+use memmap2::Mmap;
 use rayon::prelude::*;
-use std::io::BufRead;
-use std::path::PathBuf;
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::VecDeque;
+use std::io::{BufRead, Read};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
 #[cfg(feature = "enable_dyn")]
 use subprocess::Exec;
 
+/// Maximum number of the lines read ahead of scoring in [`read_files_concurrently`].
+const READ_AHEAD_LINES: usize = 1024;
+
 /// Source is anything that can produce an iterator of String.
 #[derive(Debug)]
 pub enum Source<I: Iterator<Item = String>> {
@@ -15,7 +24,28 @@ pub enum Source<I: Iterator<Item = String>> {
     #[cfg(feature = "enable_dyn")]
     Exec(Exec),
     File(PathBuf),
+    /// Lines of the file memory-mapped instead of read into memory upfront, so that the OS pages
+    /// in the huge file lazily, see [`mmap_file`].
+    MmapFile(PathBuf),
+    /// Lines of several files read by at most `threads` threads concurrently, the order of
+    /// the lines across the files is not preserved.
+    Files {
+        paths: Vec<PathBuf>,
+        threads: usize,
+    },
     List(I),
+    /// Newline-delimited candidates in memory, only the matched lines are copied.
+    Bytes(Vec<u8>),
+    /// Newline-delimited candidates read from the Unix domain socket at the path.
+    #[cfg(unix)]
+    UnixSocket(PathBuf),
+    /// Symbols of the tags file at the path, only the name of each symbol is matched, but
+    /// `name:path:line` is displayed, see [`TagInfo::display`](crate::TagInfo::display).
+    Tags(PathBuf),
+    /// Pairs of (display text, match text), e.g., `("function_name  |  src/foo.rs:42",
+    /// "function_name")`, only the match text is scored but the display text is emitted with
+    /// the indices remapped into it, see [`remap_indices`].
+    Pairs(Vec<(String, String)>),
 }
 
 impl From<Vec<String>> for Source<std::vec::IntoIter<String>> {
@@ -39,20 +69,21 @@ impl<I: Iterator<Item = String>> From<Exec> for Source<I> {
 
 impl<I: Iterator<Item = String>> Source<I> {
     /// Returns the complete filtered results after applying the specified
-    /// filter algo on each item in the input stream.
+    /// matcher on each item in the input stream.
     ///
     /// This is kind of synchronous filtering, can be used for multi-staged processing.
-    pub fn fuzzy_filter(self, algo: Algo, query: &str) -> Result<Vec<FuzzyMatchedLineInfo>> {
-        let scorer = |line: &str| match algo {
-            Algo::Skim => fuzzy_indices(line, &query),
-            Algo::Fzy => match_and_score_with_positions(&query, line)
-                .map(|(score, indices)| (score as i64, indices)),
-        };
+    pub fn fuzzy_filter(self, matcher: &Matcher) -> Result<Vec<FuzzyMatchedLineInfo>> {
+        self.filter_with(&|line: &str| matcher.match_line(line))
+    }
 
+    /// Same as [`Source::fuzzy_filter`], but each item is scored by `scorer`, e.g., to skip
+    /// some items before scoring them.
+    pub fn filter_with(
+        self,
+        scorer: &(dyn Fn(&str) -> Option<MatchResult> + Sync),
+    ) -> Result<Vec<FuzzyMatchedLineInfo>> {
         let filtered = match self {
-            Self::Stdin => std::io::stdin()
-                .lock()
-                .lines()
+            Self::Stdin => lossy_lines(std::io::stdin().lock())
                 .filter_map(|lines_iter| {
                     lines_iter.ok().and_then(|line| {
                         scorer(&line).map(|(score, indices)| (line, score, indices))
@@ -60,27 +91,252 @@ impl<I: Iterator<Item = String>> Source<I> {
                 })
                 .collect::<Vec<_>>(),
             #[cfg(feature = "enable_dyn")]
-            Self::Exec(exec_cmd) => std::io::BufReader::new(exec_cmd.stream_stdout()?)
-                .lines()
+            Self::Exec(exec_cmd) => lossy_lines(std::io::BufReader::new(exec_cmd.stream_stdout()?))
                 .filter_map(|lines_iter| {
                     lines_iter.ok().and_then(|line| {
                         scorer(&line).map(|(score, indices)| (line, score, indices))
                     })
                 })
                 .collect::<Vec<_>>(),
-            Self::File(fpath) => std::fs::read_to_string(fpath)?
+            Self::File(fpath) => String::from_utf8_lossy(&std::fs::read(fpath)?)
                 .par_lines()
                 .filter_map(|line| {
                     scorer(&line).map(|(score, indices)| (line.into(), score, indices))
                 })
                 .collect::<Vec<_>>(),
+            Self::Files { paths, threads } => read_files_concurrently(paths, threads)
+                .filter_map(|line| {
+                    // The files failed to be read are skipped.
+                    let line = line.ok()?;
+                    scorer(&line).map(|(score, indices)| (line, score, indices))
+                })
+                .collect::<Vec<_>>(),
             Self::List(list) => list
+                .map(trim_trailing_cr)
                 .filter_map(|line| {
                     scorer(&line).map(|(score, indices)| (line.into(), score, indices))
                 })
                 .collect::<Vec<_>>(),
+            Self::Bytes(bytes) => byte_lines(&bytes)
+                .filter_map(|line| {
+                    scorer(&line).map(|(score, indices)| (line.into_owned(), score, indices))
+                })
+                .collect::<Vec<_>>(),
+            Self::MmapFile(path) => byte_lines(&mmap_file(&path)?)
+                .filter_map(|line| {
+                    scorer(&line).map(|(score, indices)| (line.into_owned(), score, indices))
+                })
+                .collect::<Vec<_>>(),
+            #[cfg(unix)]
+            Self::UnixSocket(path) => {
+                lossy_lines(std::io::BufReader::new(UnixStream::connect(path)?))
+                    .filter_map(|lines_iter| {
+                        lines_iter.ok().and_then(|line| {
+                            scorer(&line).map(|(score, indices)| (line, score, indices))
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            }
+            Self::Tags(path) => read_tags(&path)?
+                .filter_map(|tag| {
+                    scorer(&tag.name).map(|(score, indices)| (tag.display(), score, indices))
+                })
+                .collect::<Vec<_>>(),
+            Self::Pairs(pairs) => pairs
+                .into_par_iter()
+                .filter_map(|(display, text)| {
+                    scorer(&text).map(|(score, indices)| {
+                        let indices = remap_indices(&display, &text, &indices);
+                        (display, score, indices)
+                    })
+                })
+                .collect::<Vec<_>>(),
         };
 
         Ok(filtered)
     }
 }
+
+/// Returns the lines of `bytes` separated by `\n` or `\r\n`, the empty line after the trailing
+/// newline is dropped. The invalid UTF-8 sequences are replaced with `U+FFFD`.
+pub fn byte_lines(bytes: &[u8]) -> impl Iterator<Item = Cow<'_, str>> {
+    let bytes = match bytes.split_last() {
+        Some((b'\n', rest)) => rest,
+        _ => bytes,
+    };
+    let lines = if bytes.is_empty() {
+        None
+    } else {
+        Some(
+            bytes
+                .split(|&b| b == b'\n')
+                .map(|line| String::from_utf8_lossy(line.strip_suffix(b"\r").unwrap_or(line))),
+        )
+    };
+    lines.into_iter().flatten()
+}
+
+/// Memory-maps the file at `path` read-only, use [`byte_lines`] to iterate over its lines.
+pub fn mmap_file(path: &Path) -> Result<Mmap> {
+    let file =
+        std::fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    // SAFETY: the candidates file is not expected to be modified while it's being filtered,
+    // otherwise the lines read could be inconsistent but nothing else.
+    unsafe { Mmap::map(&file) }.with_context(|| format!("Failed to mmap {}", path.display()))
+}
+
+/// Returns the char indices in `display` of the chars at `indices` of `text`.
+///
+/// If `text` is part of `display`, the indices are shifted by its first occurrence, otherwise
+/// each char is mapped to the next same char in `display` ignoring case, the chars not found
+/// are dropped.
+pub fn remap_indices(display: &str, text: &str, indices: &[usize]) -> Vec<usize> {
+    if let Some(pos) = display.find(text) {
+        let offset = display[..pos].chars().count();
+        return indices.iter().map(|i| i + offset).collect();
+    }
+
+    let text = text.chars().collect::<Vec<_>>();
+    let mut display = display.chars().enumerate();
+    indices
+        .iter()
+        .filter_map(|&i| {
+            let c = text.get(i)?;
+            display
+                .by_ref()
+                .find(|(_, d)| d.to_lowercase().eq(c.to_lowercase()))
+                .map(|(idx, _)| idx)
+        })
+        .collect()
+}
+
+/// Removes a single trailing `\r` of `line`, which is left by splitting the `\r\n` terminated
+/// lines by `\n`.
+pub fn trim_trailing_cr(mut line: String) -> String {
+    if line.ends_with('\r') {
+        line.pop();
+    }
+    line
+}
+
+/// Iterator over the lines of a reader, see [`lossy_lines`].
+#[derive(Debug)]
+pub struct LossyLines<R> {
+    reader: R,
+    buf: Vec<u8>,
+}
+
+impl<R: BufRead> Iterator for LossyLines<R> {
+    type Item = std::io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.buf.clear();
+        match self.reader.read_until(b'\n', &mut self.buf) {
+            Ok(0) => None,
+            Ok(_) => {
+                if self.buf.last() == Some(&b'\n') {
+                    self.buf.pop();
+                    if self.buf.last() == Some(&b'\r') {
+                        self.buf.pop();
+                    }
+                }
+                Some(Ok(String::from_utf8_lossy(&self.buf).into_owned()))
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Returns the lines of `reader` like [`BufRead::lines`], except that a line with the invalid
+/// UTF-8 sequences is kept with them replaced by `U+FFFD` instead of yielding an error.
+pub fn lossy_lines<R: BufRead>(reader: R) -> LossyLines<R> {
+    LossyLines {
+        reader,
+        buf: Vec::new(),
+    }
+}
+
+/// Reader which adds the number of the raw bytes consumed from the inner reader to `count`,
+/// e.g., to know how much of a file has been read by [`lossy_lines`].
+#[derive(Debug)]
+pub struct CountingReader<'a, R> {
+    reader: R,
+    count: &'a Cell<u64>,
+}
+
+impl<'a, R> CountingReader<'a, R> {
+    pub fn new(reader: R, count: &'a Cell<u64>) -> Self {
+        Self { reader, count }
+    }
+
+    fn add(&self, amt: usize) {
+        self.count.set(self.count.get() + amt as u64);
+    }
+}
+
+impl<R: Read> Read for CountingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let amt = self.reader.read(buf)?;
+        self.add(amt);
+        Ok(amt)
+    }
+}
+
+impl<R: BufRead> BufRead for CountingReader<'_, R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        self.reader.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.reader.consume(amt);
+        self.add(amt);
+    }
+}
+
+/// Reads the lines of `paths` by at most `threads` threads concurrently.
+///
+/// The lines are yielded as they arrive. A file failed to be read yields an error, which
+/// doesn't stop reading the other files.
+pub fn read_files_concurrently(
+    paths: Vec<PathBuf>,
+    threads: usize,
+) -> impl Iterator<Item = Result<String>> {
+    let queue = Arc::new(Mutex::new(paths.into_iter().collect::<VecDeque<_>>()));
+    let (tx, rx) = mpsc::sync_channel(READ_AHEAD_LINES);
+    for _ in 0..threads.max(1) {
+        let queue = queue.clone();
+        let tx = tx.clone();
+        std::thread::spawn(move || loop {
+            let path = match queue.lock() {
+                Ok(mut queue) => queue.pop_front(),
+                Err(_) => None,
+            };
+            let path = match path {
+                Some(path) => path,
+                None => break,
+            };
+            let receiving = match send_lines(&path, &tx) {
+                Ok(receiving) => receiving,
+                Err(e) => tx.send(Err(e)).is_ok(),
+            };
+            // Nobody wants the lines anymore.
+            if !receiving {
+                break;
+            }
+        });
+    }
+    rx.into_iter()
+}
+
+/// Sends the lines of the file at `path`, returns false if the receiver is gone.
+fn send_lines(path: &Path, tx: &mpsc::SyncSender<Result<String>>) -> Result<bool> {
+    let file =
+        std::fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    for line in lossy_lines(std::io::BufReader::new(file)) {
+        let line = line.with_context(|| format!("Failed to read {}", path.display()))?;
+        if tx.send(Ok(line)).is_err() {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
@@ -0,0 +1,104 @@
+//! Symbols of the tags file generated by ctags.
+
+use crate::source::lossy_lines;
+use anyhow::{Context, Result};
+use std::io::BufReader;
+use std::path::Path;
+
+/// A symbol parsed from a line of the tags file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TagInfo {
+    pub name: String,
+    /// Kind of the symbol, e.g., `f` or `function`.
+    pub kind: Option<String>,
+    pub path: String,
+    pub line: Option<usize>,
+}
+
+impl TagInfo {
+    /// Parses a line in the format of `name<Tab>path<Tab>address;"<Tab>fields`, the fields are
+    /// optional.
+    ///
+    /// Returns None for the pseudo tags, i.e., `!_TAG_*`, and the malformed lines.
+    pub fn parse(line: &str) -> Option<Self> {
+        if line.starts_with("!_TAG_") {
+            return None;
+        }
+
+        let mut parts = line.splitn(3, '\t');
+        let name = parts.next().filter(|name| !name.is_empty())?;
+        let path = parts.next().filter(|path| !path.is_empty())?;
+        let rest = parts.next()?;
+
+        // The address is either the line number or the search pattern, which could contain
+        // the tabs as well, so the fields are after the last `;"`.
+        let (address, fields) = match rest.rfind(";\"") {
+            Some(idx) => (&rest[..idx], Some(&rest[idx + 2..])),
+            None => (rest, None),
+        };
+
+        let mut tag = Self {
+            name: name.into(),
+            kind: None,
+            path: path.into(),
+            line: address.parse().ok(),
+        };
+
+        for field in fields.into_iter().flat_map(|fields| fields.split('\t')) {
+            match field.split_once(':') {
+                Some(("kind", kind)) => tag.kind = Some(kind.into()),
+                Some(("line", line)) => tag.line = line.parse().ok().or(tag.line),
+                Some(_) => {}
+                None if !field.is_empty() && tag.kind.is_none() => tag.kind = Some(field.into()),
+                None => {}
+            }
+        }
+
+        Some(tag)
+    }
+
+    /// Returns `name:path:line`, or `name:path` if the line is unknown.
+    ///
+    /// The name is the prefix, so the indices of matching the name are valid in it too.
+    pub fn display(&self) -> String {
+        match self.line {
+            Some(line) => format!("{}:{}:{}", self.name, self.path, line),
+            None => format!("{}:{}", self.name, self.path),
+        }
+    }
+}
+
+/// Returns the symbols of the tags file at `path`, the malformed lines are skipped.
+pub fn read_tags(path: &Path) -> Result<impl Iterator<Item = TagInfo>> {
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("Failed to open the tags file {}", path.display()))?;
+    Ok(lossy_lines(BufReader::new(file))
+        .map_while(|line| line.ok())
+        .filter_map(|line| TagInfo::parse(&line)))
+}
+
+#[test]
+fn test_parse_tag_line() {
+    assert!(TagInfo::parse("!_TAG_FILE_FORMAT\t2\t/extended format/").is_none());
+    assert!(TagInfo::parse("main\tsrc/main.rs").is_none());
+
+    let tag = TagInfo::parse("main\tsrc/main.rs\t/^fn main() {$/;\"\tf\tline:12").unwrap();
+    assert_eq!(
+        tag,
+        TagInfo {
+            name: "main".into(),
+            kind: Some("f".into()),
+            path: "src/main.rs".into(),
+            line: Some(12),
+        }
+    );
+    assert_eq!(tag.display(), "main:src/main.rs:12");
+
+    let tag = TagInfo::parse("Foo::new\tsrc/foo.rs\t42;\"\tkind:method").unwrap();
+    assert_eq!(tag.kind.as_deref(), Some("method"));
+    assert_eq!(tag.display(), "Foo::new:src/foo.rs:42");
+
+    let tag = TagInfo::parse("CONST\tsrc/lib.rs\t/^const CONST;$/").unwrap();
+    assert_eq!(tag.kind, None);
+    assert_eq!(tag.display(), "CONST:src/lib.rs");
+}
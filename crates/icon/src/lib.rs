@@ -2,7 +2,9 @@ mod constants;
 
 pub use constants::{bsearch_icon_table, EXACTMATCH_ICON_TABLE, EXTENSION_ICON_TABLE};
 
+use std::collections::{HashMap, HashSet};
 use std::path::Path;
+use std::sync::OnceLock;
 
 use lazy_static::lazy_static;
 use regex::Regex;
@@ -10,6 +12,10 @@ use regex::Regex;
 pub const DEFAULT_ICON: char = '';
 pub const FOLDER_ICON: char = '';
 pub const DEFAULT_FILER_ICON: char = '';
+pub const SYMLINK_ICON: char = '';
+
+/// Number of chars prepended to the line by [`prepend_icon`] and its friends.
+pub const ICON_PREFIX_LEN: usize = 2;
 
 /// The type used to represent icons.
 ///
@@ -17,11 +23,40 @@ pub const DEFAULT_FILER_ICON: char = '';
 /// so functions take and return this type, not `char` or `&str` directly.
 type Icon = char;
 
+/// The user defined icons keyed by the lowercase file name or extension, see [`set_custom_icons`].
+static CUSTOM_ICONS: OnceLock<HashMap<String, Icon>> = OnceLock::new();
+
+/// Sets the user defined icons keyed by the file name or extension, e.g., `rs`, which take
+/// precedence over the builtin ones.
+///
+/// The icons can be set only once, returns false if they have been set already.
+pub fn set_custom_icons(icons: HashMap<String, Icon>) -> bool {
+    let icons = icons
+        .into_iter()
+        .map(|(key, icon)| (key.trim_start_matches('.').to_lowercase(), icon))
+        .collect();
+    CUSTOM_ICONS.set(icons).is_ok()
+}
+
+/// Returns the user defined icon for the file name of `path`, or else its extension.
+fn custom_icon(path: &Path) -> Option<Icon> {
+    let icons = CUSTOM_ICONS.get()?;
+    let filename = path.file_name()?.to_str()?.to_lowercase();
+    icons
+        .get(&filename)
+        .or_else(|| icons.get(&path.extension()?.to_str()?.to_lowercase()))
+        .copied()
+}
+
 /// Return appropriate icon for the path. If no icon matched, return the specified default one.
 ///
-/// Try matching the exactmatch map against the file name, and then the extension map.
+/// Try matching the user defined icons, the exactmatch map against the file name, and then
+/// the extension map.
 #[inline]
 pub fn get_icon_or(path: &Path, default: Icon) -> Icon {
+    if let Some(icon) = custom_icon(path) {
+        return icon;
+    }
     path.file_name()
         .and_then(std::ffi::OsStr::to_str)
         .and_then(|filename| {
@@ -48,6 +83,26 @@ pub fn prepend_icon(line: &str) -> String {
     format!("{} {}", icon_for(line), line)
 }
 
+/// Returns the icon of the entry `line`, the directories and symlinks get [`FOLDER_ICON`] and
+/// [`SYMLINK_ICON`] instead of the icon of file.
+///
+/// A line ending with the path separator is a directory, e.g., the output of `fd`, otherwise
+/// the type of entry is known only if the file at `path`, which the line refers to, is given.
+pub fn icon_for_entry(line: &str, path: Option<&Path>) -> Icon {
+    if line.ends_with('/') || line.ends_with(std::path::MAIN_SEPARATOR) {
+        return FOLDER_ICON;
+    }
+    match path.and_then(|path| path.symlink_metadata().ok()) {
+        Some(metadata) if metadata.file_type().is_symlink() => SYMLINK_ICON,
+        Some(metadata) if metadata.is_dir() => FOLDER_ICON,
+        _ => icon_for(line),
+    }
+}
+
+pub fn prepend_entry_icon(line: &str, path: Option<&Path>) -> String {
+    format!("{} {}", icon_for_entry(line, path), line)
+}
+
 #[inline]
 pub fn icon_for_filer(path: &Path) -> Icon {
     if path.is_dir() {
@@ -63,7 +118,7 @@ pub fn prepend_filer_icon(path: &Path, line: &str) -> String {
 
 pub fn prepend_grep_icon(line: &str) -> String {
     lazy_static! {
-        static ref RE: Regex = Regex::new(r"^(.*):\d+:\d+:").unwrap();
+        static ref RE: Regex = Regex::new(r"^(.*?):\d+:\d+:").unwrap();
     }
     let icon = RE
         .captures(line)
@@ -72,3 +127,35 @@ pub fn prepend_grep_icon(line: &str) -> String {
         .unwrap_or(DEFAULT_ICON);
     format!("{} {}", icon, line)
 }
+
+fn is_icon(c: Icon) -> bool {
+    lazy_static! {
+        static ref ICONS: HashSet<Icon> = EXTENSION_ICON_TABLE
+            .iter()
+            .chain(EXACTMATCH_ICON_TABLE.iter())
+            .map(|(_, icon)| *icon)
+            .chain(vec![
+                DEFAULT_ICON,
+                FOLDER_ICON,
+                DEFAULT_FILER_ICON,
+                SYMLINK_ICON
+            ])
+            .collect();
+    }
+    ICONS.contains(&c)
+        || CUSTOM_ICONS
+            .get()
+            .is_some_and(|icons| icons.values().any(|&icon| icon == c))
+}
+
+/// Returns the rest of the line if it starts with an icon prepended by [`prepend_icon`] and
+/// its friends, e.g., a cached line that has been iconized.
+pub fn strip_icon(line: &str) -> Option<&str> {
+    let mut chars = line.chars();
+    let icon = chars.next()?;
+    if chars.next() == Some(' ') && is_icon(icon) {
+        Some(chars.as_str())
+    } else {
+        None
+    }
+}
@@ -0,0 +1,93 @@
+use std::io::Read;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use fuzzy_filter::{byte_lines, Algo, Source};
+
+use crate::cmd::filter;
+
+/// Throughput of filtering the candidates with one algorithm.
+#[derive(Debug)]
+struct BenchReport {
+    algo: Algo,
+    /// Number of the candidates filtered in each iteration.
+    lines: usize,
+    /// Elapsed time averaged across the iterations.
+    elapsed: Duration,
+    /// Maximum number of the matched results buffered in an iteration.
+    peak_buffered: usize,
+}
+
+impl BenchReport {
+    fn lines_per_sec(&self) -> f64 {
+        self.lines as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+}
+
+/// Ranks `candidates` with `query` using `algo` for `iterations` times, nothing is printed.
+fn bench(query: &str, candidates: &[u8], algo: Algo, iterations: usize) -> Result<BenchReport> {
+    let iterations = iterations.max(1);
+    let mut total_elapsed = Duration::default();
+    let mut peak_buffered = 0;
+    for _ in 0..iterations {
+        let source = Source::<std::iter::Empty<_>>::Bytes(candidates.to_vec());
+        let now = Instant::now();
+        let ranked = filter::rank(query, source, algo, None)?;
+        total_elapsed += now.elapsed();
+        peak_buffered = peak_buffered.max(ranked.len());
+    }
+    Ok(BenchReport {
+        algo,
+        lines: byte_lines(candidates).count(),
+        elapsed: total_elapsed / iterations as u32,
+        peak_buffered,
+    })
+}
+
+/// Reports the throughput of filtering the candidates from `input` or stdin with `query` to
+/// stderr, for each algorithm unless `algo` is given.
+pub fn run(
+    query: &str,
+    input: Option<PathBuf>,
+    algo: Option<Algo>,
+    iterations: usize,
+) -> Result<()> {
+    let candidates = match input {
+        Some(path) => std::fs::read(path)?,
+        None => {
+            let mut buf = Vec::new();
+            std::io::stdin().read_to_end(&mut buf)?;
+            buf
+        }
+    };
+
+    let algos = match algo {
+        Some(algo) => vec![algo],
+        None => vec![Algo::Fzy, Algo::Skim, Algo::Substring],
+    };
+
+    for algo in algos {
+        let report = bench(query, &candidates, algo, iterations)?;
+        eprintln!(
+            "{:?}: {:.0} lines/sec, elapsed {:?}, peak buffer {} items ({} lines, {} iterations)",
+            report.algo,
+            report.lines_per_sec(),
+            report.elapsed,
+            report.peak_buffered,
+            report.lines,
+            iterations.max(1),
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_bench_report() {
+    let candidates = b"src/main.rs\nsrc/lib.rs\nCargo.toml\n";
+    let report = bench("src", candidates, Algo::Fzy, 3).unwrap();
+    assert_eq!(report.lines, 3);
+    assert_eq!(report.peak_buffered, 2);
+    assert!(report.lines_per_sec() > 0.0);
+}
@@ -0,0 +1,134 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::light_command::{cache_file_timestamp, cache_root, CmdInfo, CMD_INFO_FILE};
+
+/// A cache file of the output of a command.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct CacheEntry {
+    /// Name of the cache dir of the command, i.e., the hash of the command.
+    key: String,
+    /// The command, unknown for the cache dirs created before it's recorded.
+    args: Option<Vec<String>>,
+    cmd_dir: Option<PathBuf>,
+    path: PathBuf,
+    /// Creation time in seconds since the Unix epoch, taken from the file name.
+    created: u64,
+    bytes: u64,
+}
+
+/// Returns the cache files under `root`, the newest first.
+///
+/// The files not named by the timestamp are skipped.
+fn cache_entries(root: &Path) -> Result<Vec<CacheEntry>> {
+    let mut entries = Vec::new();
+    let dirs = match std::fs::read_dir(root) {
+        Ok(dirs) => dirs,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(entries),
+        Err(e) => return Err(e.into()),
+    };
+    for dir in dirs
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+    {
+        if !dir.is_dir() {
+            continue;
+        }
+        let key = dir
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let info = std::fs::read(dir.join(CMD_INFO_FILE))
+            .ok()
+            .and_then(|info| serde_json::from_slice::<CmdInfo>(&info).ok());
+        for path in std::fs::read_dir(&dir)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+        {
+            let (timestamp, metadata) = match (cache_file_timestamp(&path), path.metadata()) {
+                (Some(timestamp), Ok(metadata)) => (timestamp, metadata),
+                _ => continue,
+            };
+            entries.push(CacheEntry {
+                key: key.clone(),
+                args: info.as_ref().map(|info| info.args.clone()),
+                cmd_dir: info.as_ref().and_then(|info| info.cmd_dir.clone()),
+                path,
+                created: timestamp / 1_000_000_000,
+                bytes: metadata.len(),
+            });
+        }
+    }
+    entries.sort_by_key(|entry| std::cmp::Reverse(entry.created));
+    Ok(entries)
+}
+
+/// Removes all the cache files under `root`, which is fine to be missing.
+fn clear_cache(root: &Path) -> Result<()> {
+    match std::fs::remove_dir_all(root) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Prints the total size of the cache dir, each cache file as well with `list`, and then
+/// removes all of them with `clear`.
+pub fn run(list: bool, clear: bool) -> Result<()> {
+    let root = cache_root();
+    let entries = cache_entries(&root)?;
+
+    if list {
+        for entry in &entries {
+            println!("{}", serde_json::json!(entry));
+        }
+    }
+
+    let total = entries.len();
+    let bytes = entries.iter().map(|entry| entry.bytes).sum::<u64>();
+    let dir = root.display().to_string();
+    println_json!(dir, total, bytes);
+
+    if clear {
+        clear_cache(&root)?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_list_and_clear_cache() {
+    let root = std::env::temp_dir().join(format!("clap_cache_list_{}", std::process::id()));
+    let dir = root.join("0123456789abcdef");
+    std::fs::create_dir_all(&dir).unwrap();
+    let info = CmdInfo {
+        args: vec!["rg".into(), "--files".into()],
+        cmd_dir: Some("/tmp".into()),
+    };
+    std::fs::write(dir.join(CMD_INFO_FILE), serde_json::to_vec(&info).unwrap()).unwrap();
+    std::fs::write(dir.join("1000000000000000000"), "old").unwrap();
+    std::fs::write(dir.join("2000000000000000000_ff"), "newer").unwrap();
+    std::fs::create_dir_all(root.join("fedcba9876543210")).unwrap();
+    std::fs::write(root.join("fedcba9876543210/1500000000000000000"), "x").unwrap();
+
+    let entries = cache_entries(&root).unwrap();
+    assert_eq!(
+        entries
+            .iter()
+            .map(|entry| (entry.created, entry.bytes, entry.args.is_some()))
+            .collect::<Vec<_>>(),
+        vec![
+            (2_000_000_000, 5, true),
+            (1_500_000_000, 1, false),
+            (1_000_000_000, 3, true)
+        ]
+    );
+    assert_eq!(entries[0].args, Some(info.args));
+    assert_eq!(entries[0].cmd_dir, info.cmd_dir);
+
+    clear_cache(&root).unwrap();
+    assert!(!root.exists());
+    assert!(cache_entries(&root).unwrap().is_empty());
+    clear_cache(&root).unwrap();
+}
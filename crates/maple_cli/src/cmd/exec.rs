@@ -1,12 +1,41 @@
 use std::path::PathBuf;
 use std::process::Command;
+use std::time::Duration;
 
 use anyhow::Result;
+use structopt::StructOpt;
 
-use crate::light_command::{set_current_dir, LightCommand};
+use crate::light_command::{set_current_dir, CacheOptions, LightCommand};
+
+#[derive(StructOpt, Debug, Default)]
+pub struct ExecOptions {
+    /// Specify the output file path when the output of command exceeds the threshold.
+    #[structopt(long = "output")]
+    pub output: Option<String>,
+
+    /// Specify the threshold for writing the output of command to a tempfile.
+    #[structopt(long = "output-threshold", default_value = "100000")]
+    pub output_threshold: usize,
+
+    /// Specify the working directory of CMD
+    #[structopt(long = "cmd-dir", parse(from_os_str))]
+    pub cmd_dir: Option<PathBuf>,
+
+    /// Strip the ANSI color codes from the displayed lines, the cached output keeps them.
+    #[structopt(long = "strip-ansi")]
+    pub strip_ansi: bool,
+
+    /// Kill CMD if it's not complete in this many milliseconds, the output so far is displayed
+    /// with `timed_out` in the result.
+    #[structopt(long = "timeout")]
+    pub timeout: Option<u64>,
+
+    #[structopt(flatten)]
+    pub cache_opts: CacheOptions,
+}
 
 // This can work with the piped command, e.g., git ls-files | uniq.
-fn prepare_exec_cmd(cmd_str: &str, cmd_dir: Option<PathBuf>) -> Command {
+fn prepare_exec_cmd(cmd_str: &str, cmd_dir: Option<PathBuf>) -> (Command, Option<String>) {
     let mut cmd = if cfg!(target_os = "windows") {
         let mut cmd = Command::new("cmd");
         cmd.args(&["/C", cmd_str]);
@@ -17,20 +46,22 @@ fn prepare_exec_cmd(cmd_str: &str, cmd_dir: Option<PathBuf>) -> Command {
         cmd
     };
 
-    set_current_dir(&mut cmd, cmd_dir);
+    let warning = set_current_dir(&mut cmd, cmd_dir);
 
-    cmd
+    (cmd, warning)
 }
 
-pub fn run(
-    cmd: String,
-    output: Option<String>,
-    output_threshold: usize,
-    cmd_dir: Option<PathBuf>,
-    number: Option<usize>,
-    enable_icon: bool,
-) -> Result<()> {
-    let mut exec_cmd = prepare_exec_cmd(&cmd, cmd_dir);
+pub fn run(cmd: String, opts: ExecOptions, number: Option<usize>, enable_icon: bool) -> Result<()> {
+    let ExecOptions {
+        output,
+        output_threshold,
+        cmd_dir,
+        strip_ansi,
+        timeout,
+        cache_opts,
+    } = opts;
+
+    let (mut exec_cmd, warning) = prepare_exec_cmd(&cmd, cmd_dir);
 
     let mut light_cmd = LightCommand::new(
         &mut exec_cmd,
@@ -39,7 +70,11 @@ pub fn run(
         enable_icon,
         false,
         output_threshold,
-    );
+        strip_ansi,
+    )
+    .cache_opts(&cache_opts)
+    .warning(warning)
+    .timeout(timeout.map(Duration::from_millis));
 
     light_cmd.execute(&cmd.split_whitespace().map(Into::into).collect::<Vec<_>>())
 }
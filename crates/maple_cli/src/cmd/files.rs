@@ -0,0 +1,69 @@
+use std::path::{Path, PathBuf};
+
+use ignore::WalkBuilder;
+use structopt::StructOpt;
+
+/// Options for walking the files of the files provider, see [`walk`].
+#[derive(StructOpt, Debug, Default)]
+pub struct FilesOptions {
+    /// Include the hidden files and directories with --files.
+    #[structopt(long = "hidden")]
+    pub hidden: bool,
+
+    /// Don't respect the ignore files, e.g., `.gitignore`, with --files.
+    #[structopt(long = "no-ignore")]
+    pub no_ignore: bool,
+}
+
+/// Returns the paths of the files under `dir` relative to it, which doesn't require the external
+/// tools like fd or rg.
+///
+/// The ignore files are respected as rg does unless `--no-ignore`, `.gitignore` is respected
+/// even outside a git repo. The entries failed to be read are skipped.
+pub fn walk(dir: PathBuf, opts: &FilesOptions) -> impl Iterator<Item = String> {
+    WalkBuilder::new(&dir)
+        .standard_filters(!opts.no_ignore)
+        .hidden(!opts.hidden)
+        .require_git(false)
+        .build()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_some_and(|ty| ty.is_file()))
+        .map(move |entry| relative_path(entry.path(), &dir))
+}
+
+fn relative_path(path: &Path, dir: &Path) -> String {
+    path.strip_prefix(dir).unwrap_or(path).display().to_string()
+}
+
+#[test]
+fn test_walk_files() {
+    let dir = std::env::temp_dir().join(format!("test_walk_files_{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("src")).unwrap();
+    std::fs::create_dir_all(dir.join("target")).unwrap();
+    for file in &[".gitignore", ".hidden", "src/main.rs", "target/debug.log"] {
+        std::fs::write(dir.join(file), "target\n").unwrap();
+    }
+
+    let files = |opts: FilesOptions| {
+        let mut files = walk(dir.clone(), &opts).collect::<Vec<_>>();
+        files.sort();
+        files
+    };
+    assert_eq!(files(FilesOptions::default()), vec!["src/main.rs"]);
+    assert_eq!(
+        files(FilesOptions {
+            hidden: true,
+            ..Default::default()
+        }),
+        vec![".gitignore", ".hidden", "src/main.rs"]
+    );
+    assert_eq!(
+        files(FilesOptions {
+            no_ignore: true,
+            ..Default::default()
+        }),
+        vec!["src/main.rs", "target/debug.log"]
+    );
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
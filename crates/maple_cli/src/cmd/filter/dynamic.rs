@@ -1,46 +1,111 @@
 use super::*;
-use extracted_fzy::match_and_score_with_positions;
-use fuzzy_filter::FuzzyMatchedLineInfo;
-use fuzzy_matcher::skim::fuzzy_indices;
-use rayon::slice::ParallelSliceMut;
-use std::io::{self, BufRead};
+use fuzzy_filter::subprocess::{Exec, Popen, Redirection};
+use fuzzy_filter::{
+    byte_lines, cmp_scores, lossy_lines, mmap_file, read_files_concurrently, read_tags,
+    remap_indices, trim_trailing_cr, CountingReader, FuzzyMatchedLineInfo, LossyLines, MatchResult,
+};
+use rayon::iter::ParallelIterator;
+use rayon::slice::{ParallelSlice, ParallelSliceMut};
+use serde::Serialize;
+use std::cell::{Cell, RefCell};
+use std::cmp::Reverse;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BinaryHeap, HashSet};
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, BufWriter, Write};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Once;
 use std::time::{Duration, Instant};
 
-/// The constant to define the length of `top_` queues.
+/// The default length of `top_` queues, i.e., the number of items shown by the updates.
 const ITEMS_TO_SHOW: usize = 100;
 
-const MAX_IDX: usize = ITEMS_TO_SHOW - 1;
+/// Number of the slowest candidates reported when profiling.
+const SLOWEST_CANDIDATES: usize = 10;
 
-/// Refresh the top filtered results per 200 ms.
-const UPDATE_INTERVAL: Duration = Duration::from_millis(200);
+/// Number of the candidates read and then scored in parallel at a time by [`par_dyn_collect_all`].
+const PARALLEL_BATCH_SIZE: usize = 16 * 1024;
+
+/// Number of the candidates scored by each rayon task in [`par_dyn_collect_all`].
+const PARALLEL_PARTITION_SIZE: usize = 1024;
+
+/// Refresh the top filtered results per 200 ms by default.
+pub(crate) const UPDATE_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Check whether to refresh the top filtered results per 16 items by default.
+const NOTIFY_EVERY: usize = 16;
+
+/// Set once SIGINT is caught, see [`catch_interrupt`].
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Installs the SIGINT handler which stops reading the source instead of killing the process,
+/// the results collected so far are then emitted as the final frame.
+///
+/// Note that the interruption takes effect when the source produces the next line.
+fn catch_interrupt() -> Result<()> {
+    static INSTALL: Once = Once::new();
+    let mut res = Ok(());
+    INSTALL.call_once(|| {
+        res = ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::SeqCst));
+    });
+    Ok(res?)
+}
+
+/// Progress of reading the source, only known for the file sources.
+#[derive(Debug, Default)]
+struct SourceProgress {
+    /// Raw bytes of the source read so far, including the line terminators.
+    read: Cell<u64>,
+    /// Size of the source in bytes if it's known upfront.
+    size: Option<u64>,
+}
+
+impl SourceProgress {
+    fn new<I: Iterator<Item = String>>(source: &Source<I>) -> Self {
+        let size = match source {
+            Source::File(path) | Source::MmapFile(path) => {
+                std::fs::metadata(path).ok().map(|meta| meta.len())
+            }
+            _ => None,
+        };
+        Self {
+            read: Cell::new(0),
+            size,
+        }
+    }
+
+    /// Returns the fraction of the source read so far if the size is known.
+    fn fraction(&self) -> Option<f32> {
+        let size = self.size.filter(|&size| size > 0)?;
+        Some((self.read.get() as f32 / size as f32).min(1.0))
+    }
+}
 
 trait Insert<T> {
     fn pop_and_insert(&mut self, idx: usize, value: T);
 }
 
-impl<T: Copy> Insert<T> for [T; ITEMS_TO_SHOW] {
+impl<T: Copy> Insert<T> for [T] {
     fn pop_and_insert(&mut self, idx: usize, value: T) {
-        if idx < MAX_IDX {
-            self.copy_within(idx..MAX_IDX, idx + 1);
+        let max_idx = self.len() - 1;
+        if idx < max_idx {
+            self.copy_within(idx..max_idx, idx + 1);
             self[idx] = value;
         } else {
-            self[MAX_IDX] = value;
+            self[max_idx] = value;
         }
     }
 }
 
-/// Combine json and println macro.
+/// Prints the message with Content-length info.
 ///
-/// Neovim needs Content-length info when using stdio-based communication.
-macro_rules! print_json_with_length {
-  ( $( $field:expr ),+ ) => {
-    {
-      let msg = serde_json::json!({ $(stringify!($field): $field,)* });
-      if let Ok(s) = serde_json::to_string(&msg) {
-          println!("Content-length: {}\n\n{}", s.len(), s);
-      }
-    }
-  }
+/// Neovim needs Content-length info when using stdio-based communication. The message is encoded
+/// in the format of --output-format.
+pub(crate) fn write_json_with_length(msg: &serde_json::Value) {
+    write_with_length(&mut io::stdout(), msg).expect("failed printing to stdout");
 }
 
 /// This macro is a special thing for [`dyn_collect_all`] and [`dyn_collect_number`].
@@ -51,7 +116,7 @@ macro_rules! insert_both {
                 match $index {
                     // If index is last possible, then the worst item is better than this we want to push in,
                     // and we do nothing.
-                    Some(MAX_IDX) => $buffer.push(($text, $score, $indices)),
+                    Some(idx) if idx == $top_scores.len() - 1 => $buffer.push(($text, $score, $indices)),
                     // Else, one item gets popped from the queue
                     // and other is inserted.
                     Some(idx) => {
@@ -72,30 +137,175 @@ macro_rules! insert_both {
             }};
 }
 
-type SelectedTopItemsInfo = (usize, [i64; ITEMS_TO_SHOW], [usize; ITEMS_TO_SHOW]);
+/// Iterator over the stdout lines of a spawned command.
+///
+/// The command could fail after producing some output, its exit status is checked
+/// via [`ExecLines::error`] once the stream is exhausted.
+struct ExecLines {
+    popen: Popen,
+    lines: LossyLines<io::BufReader<File>>,
+}
+
+impl ExecLines {
+    fn new(exec: Exec) -> Result<Self> {
+        let mut popen = exec.stdout(Redirection::Pipe).popen()?;
+        let stdout = popen.stdout.take().expect("stdout is piped; qed");
+        Ok(Self {
+            popen,
+            lines: lossy_lines(io::BufReader::new(stdout)),
+        })
+    }
+
+    /// Kills the command, used when the rest of its output is no longer wanted.
+    fn kill(&mut self) -> Result<()> {
+        Ok(self.popen.kill()?)
+    }
+
+    /// Waits for the command to exit, returns the error message if it exits abnormally.
+    fn error(mut self) -> Result<Option<String>> {
+        let status = self.popen.wait()?;
+        if status.success() {
+            Ok(None)
+        } else {
+            Ok(Some(format!(
+                "Command exited with {:?}, the results may be incomplete",
+                status
+            )))
+        }
+    }
+}
+
+impl Iterator for ExecLines {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.lines.next()
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct SlowCandidate {
+    line: String,
+    elapsed_us: u128,
+}
+
+/// Keeps the candidates taking the longest time to be scored, the slowest first.
+#[derive(Debug)]
+struct SlowestCandidates {
+    capacity: usize,
+    candidates: Vec<SlowCandidate>,
+}
+
+impl SlowestCandidates {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            candidates: Vec::with_capacity(capacity + 1),
+        }
+    }
+
+    fn record(&mut self, line: &str, elapsed: Duration) {
+        let elapsed_us = elapsed.as_micros();
+        if let Some(fastest) = self.candidates.last() {
+            if self.candidates.len() == self.capacity && fastest.elapsed_us >= elapsed_us {
+                return;
+            }
+        }
+        let idx = self
+            .candidates
+            .iter()
+            .position(|c| c.elapsed_us < elapsed_us)
+            .unwrap_or(self.candidates.len());
+        self.candidates.insert(
+            idx,
+            SlowCandidate {
+                line: line.into(),
+                elapsed_us,
+            },
+        );
+        self.candidates.truncate(self.capacity);
+    }
+}
+
+type Scorer<'a> = dyn Fn(&str) -> Option<MatchResult> + 'a;
+
+/// Returns the scorer which records the time taken to score each line into `slowest`.
+fn profiled_scorer<'a>(
+    matcher: &'a Matcher,
+    slowest: &'a RefCell<SlowestCandidates>,
+) -> impl Fn(&str) -> Option<MatchResult> + 'a {
+    move |line: &str| {
+        let now = Instant::now();
+        let matched = matcher.match_line(line);
+        slowest.borrow_mut().record(line, now.elapsed());
+        matched
+    }
+}
+
+/// Returns the scorer skipping the lines matched before by `scorer` if `enable` is true, only
+/// the hashes of the matched lines are kept in `seen`.
+fn dedup_scorer<'a>(
+    scorer: Box<Scorer<'a>>,
+    enable: bool,
+    seen: &'a RefCell<HashSet<u64>>,
+) -> Box<Scorer<'a>> {
+    if !enable {
+        return scorer;
+    }
+    Box::new(move |line: &str| {
+        let mut hasher = DefaultHasher::new();
+        line.hash(&mut hasher);
+        let hash = hasher.finish();
+        if seen.borrow().contains(&hash) {
+            return None;
+        }
+        let matched = scorer(line)?;
+        seen.borrow_mut().insert(hash);
+        Some(matched)
+    })
+}
+
+type SelectedTopItemsInfo = (usize, Vec<i64>, Vec<usize>);
 
 /// Returns Ok if all items in the iterator has been processed.
 ///
-/// First, let's try to produce `ITEMS_TO_SHOW` items to fill the topscores.
+/// First, let's try to produce `display_count` items to fill the topscores.
 fn select_top_items_to_show(
     buffer: &mut Vec<FuzzyMatchedLineInfo>,
     iter: &mut impl Iterator<Item = FuzzyMatchedLineInfo>,
+    schedule: &mut UpdateSchedule,
+    update_opts: UpdateOptions,
+    progress: &SourceProgress,
+    display_count: usize,
 ) -> std::result::Result<usize, SelectedTopItemsInfo> {
-    let mut top_scores: [i64; ITEMS_TO_SHOW] = [i64::min_value(); ITEMS_TO_SHOW];
-    let mut top_results: [usize; ITEMS_TO_SHOW] = [usize::min_value(); ITEMS_TO_SHOW];
+    let display_count = display_count.max(1);
+    let mut top_scores = vec![i64::min_value(); display_count];
+    let mut top_results = vec![usize::min_value(); display_count];
 
     let mut total = 0;
     let res = iter.try_for_each(|(text, score, indices)| {
-        let idx = match find_best_score_idx(&top_scores, score) {
+        // Only the filled part of the queues is compared, the rest are placeholders.
+        let idx = match find_best_score_idx(&top_scores[..total], score, update_opts.reverse) {
             Some(idx) => idx + 1,
             None => 0,
         };
 
         insert_both!(idx, score, text, indices => buffer, top_results, top_scores);
 
-        // Stop iterating after `ITEMS_TO_SHOW` iterations.
         total += 1;
-        if total == ITEMS_TO_SHOW {
+
+        try_notify_top_results(
+            schedule,
+            update_opts,
+            progress,
+            total,
+            total,
+            &top_results,
+            buffer,
+        );
+
+        // Stop iterating after `display_count` iterations.
+        if total == display_count {
             Err(())
         } else {
             Ok(())
@@ -111,50 +321,219 @@ fn select_top_items_to_show(
 
 /// Returns the index of best score in `top_scores`.
 ///
-/// Best results are stored in front, the bigger the better.
+/// Best results are stored in front, the bigger the better unless `reverse`.
 #[inline]
-fn find_best_score_idx(top_scores: &[i64; ITEMS_TO_SHOW], score: i64) -> Option<usize> {
+fn find_best_score_idx(top_scores: &[i64], score: i64, reverse: bool) -> Option<usize> {
     top_scores
         .iter()
         .enumerate()
         .rev() // .rev(), because worse items are at the end.
-        .find(|&(_, &other_score)| other_score > score)
+        // The later item goes after the ones with the same score to keep the source order.
+        .find(|&(_, &other_score)| !cmp_scores(other_score, score, reverse).is_gt())
         .map(|(idx, _)| idx)
 }
 
-/// Returns the new freshed time when the new top scored items are sent to the client.
+/// Kind of the update sent to the client while filtering.
+#[derive(Debug, PartialEq)]
+enum Update {
+    /// Total number of the filtered items and the top items.
+    Full,
+    /// Total number of the filtered items only, which is cheap to send.
+    Count,
+}
+
+/// Decides when to send the updates to the client.
 ///
-/// Printing to stdout is to send the printed content to the client.
-fn try_notify_top_results(
+/// The full updates are sent per `refresh_interval`, [`UPDATE_INTERVAL`] by default, the
+/// count-only updates can be sent more frequently in between to keep the total number of
+/// matches live. It's only checked per `notify_every` items, [`NOTIFY_EVERY`] by default.
+#[derive(Debug)]
+struct UpdateSchedule {
+    last_full: Instant,
+    last_count: Instant,
+    count_interval: Option<Duration>,
+    refresh_interval: Duration,
+    notify_every: usize,
+}
+
+impl Default for UpdateSchedule {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl UpdateSchedule {
+    fn new(count_interval: Option<Duration>) -> Self {
+        let now = Instant::now();
+        Self {
+            last_full: now,
+            last_count: now,
+            count_interval,
+            refresh_interval: UPDATE_INTERVAL,
+            notify_every: NOTIFY_EVERY,
+        }
+    }
+
+    fn refresh_interval(mut self, refresh_interval: Option<Duration>) -> Self {
+        if let Some(refresh_interval) = refresh_interval {
+            self.refresh_interval = refresh_interval;
+        }
+        self
+    }
+
+    fn notify_every(mut self, notify_every: Option<usize>) -> Self {
+        if let Some(notify_every) = notify_every {
+            self.notify_every = notify_every.max(1);
+        }
+        self
+    }
+
+    fn check(&mut self, total: usize, now: Instant) -> Option<Update> {
+        // The first few items are checked more eagerly than per `notify_every` items, otherwise
+        // nothing is shown until the end if only a few items are matched from a slow source.
+        let due = if total < self.notify_every {
+            total.is_power_of_two()
+        } else {
+            total.is_multiple_of(self.notify_every)
+        };
+        if !due {
+            return None;
+        }
+        // Show the first match as soon as possible.
+        if total == 1 {
+            self.last_full = now;
+            self.last_count = now;
+            return Some(Update::Full);
+        }
+        self.check_interval(now)
+    }
+
+    /// Returns the update due by the intervals only, used when the items come in batches.
+    fn check_interval(&mut self, now: Instant) -> Option<Update> {
+        if now > self.last_full + self.refresh_interval {
+            self.last_full = now;
+            self.last_count = now;
+            return Some(Update::Full);
+        }
+        if let Some(interval) = self.count_interval {
+            if now > self.last_count + interval {
+                self.last_count = now;
+                return Some(Update::Count);
+            }
+        }
+        None
+    }
+}
+
+/// How the top items are displayed in the updates sent while filtering.
+#[derive(Debug, Clone, Copy)]
+struct UpdateOptions {
     enable_icon: bool,
+    winwidth: usize,
+    /// Include the `ids` of the top items as the final results do, see [`result_ids`].
+    ids: bool,
+    /// The worst matched items are the top ones, see [`Matcher::reverse`].
+    reverse: bool,
+}
+
+impl Default for UpdateOptions {
+    fn default() -> Self {
+        Self {
+            enable_icon: false,
+            winwidth: DEFAULT_WINWIDTH,
+            ids: false,
+            reverse: false,
+        }
+    }
+}
+
+/// Sends the new top scored items or the total number only to the client when it's time to.
+///
+/// Printing to stdout is to send the printed content to the client. The lines are truncated to
+/// fit the `winwidth` of `update_opts` as the final results are.
+fn try_notify_top_results(
+    schedule: &mut UpdateSchedule,
+    update_opts: UpdateOptions,
+    progress: &SourceProgress,
     total: usize,
-    past: &Instant,
     top_results_len: usize,
-    top_results: &[usize; ITEMS_TO_SHOW],
+    top_results: &[usize],
     buffer: &[FuzzyMatchedLineInfo],
-) -> std::result::Result<Instant, ()> {
-    if total % 16 == 0 {
-        let now = Instant::now();
-        if now > *past + UPDATE_INTERVAL {
-            let mut indices = Vec::with_capacity(top_results_len);
-            let mut lines = Vec::with_capacity(top_results_len);
-            for &idx in top_results.iter() {
-                let (text, _, idxs) = std::ops::Index::index(buffer, idx);
-                indices.push(idxs);
-                let text = if enable_icon {
-                    prepend_icon(&text)
-                } else {
-                    text.clone()
-                };
-                lines.push(text);
-            }
+) {
+    notify_top_results(
+        schedule.check(total, Instant::now()),
+        update_opts,
+        progress,
+        total,
+        top_results_len,
+        top_results,
+        buffer,
+    );
+}
+
+/// Sends the `update` of the top scored items if any, see [`try_notify_top_results`].
+///
+/// The fraction of the source read so far is included as `progress` if the size of the source
+/// is known.
+fn notify_top_results(
+    update: Option<Update>,
+    update_opts: UpdateOptions,
+    progress: &SourceProgress,
+    total: usize,
+    top_results_len: usize,
+    top_results: &[usize],
+    buffer: &[FuzzyMatchedLineInfo],
+) {
+    let mut msg = match update {
+        Some(update) => top_results_msg(
+            update,
+            update_opts,
+            total,
+            &top_results[..top_results_len.min(top_results.len())],
+            buffer,
+        ),
+        None => return,
+    };
+    if let Some(progress) = progress.fraction() {
+        msg["progress"] = serde_json::json!(progress);
+    }
+    write_json_with_length(&msg);
+}
 
-            print_json_with_length!(total, lines, indices);
+/// Returns the message of the `update` of the top items, which are `top_results` in `buffer`.
+fn top_results_msg(
+    update: Update,
+    update_opts: UpdateOptions,
+    total: usize,
+    top_results: &[usize],
+    buffer: &[FuzzyMatchedLineInfo],
+) -> serde_json::Value {
+    match update {
+        Update::Full => {
+            let top_items = top_results
+                .iter()
+                .map(|&idx| buffer[idx].clone())
+                .collect::<Vec<_>>();
+            // The ids are derived from the text before it's truncated for display.
+            let ids = result_ids(&top_items, update_opts.ids);
+            let (top_items, truncated_map) =
+                truncate_and_iconize(top_items, update_opts.winwidth, update_opts.enable_icon);
+            let (lines, indices): (Vec<_>, Vec<_>) = top_items
+                .into_iter()
+                .map(|(text, _, idxs)| (text, idxs))
+                .unzip();
 
-            return Ok(now);
+            let mut msg = serde_json::json!({ "total": total, "lines": lines, "indices": indices });
+            if !truncated_map.is_empty() {
+                msg["truncated_map"] = serde_json::json!(truncated_map);
+            }
+            if let Some(ids) = ids {
+                msg["ids"] = serde_json::json!(ids);
+            }
+            msg
         }
+        Update::Count => serde_json::json!({ "total": total }),
     }
-    Err(())
 }
 
 /// To get dynamic updates, not so much should be changed, actually.
@@ -173,17 +552,32 @@ fn try_notify_top_results(
 /// But there's a problem with `par_iter` again, as there should be mutexed access to the
 /// VecDeque for this iterator.
 ///
-/// So, this particular function won't work in parallel context at all.
+/// So, this particular function won't work in parallel context at all, see
+/// [`par_dyn_collect_all`] for the parallel version.
+///
+/// With `max_candidates`, the buffer is trimmed to the best `max_candidates` items whenever it
+/// reaches twice of that, the returned vector may still hold up to twice of them.
 fn dyn_collect_all(
     mut iter: impl Iterator<Item = FuzzyMatchedLineInfo>,
-    enable_icon: bool,
+    update_opts: UpdateOptions,
+    progress: &SourceProgress,
+    display_count: usize,
+    mut schedule: UpdateSchedule,
+    max_candidates: Option<usize>,
 ) -> Vec<FuzzyMatchedLineInfo> {
     let mut buffer = Vec::with_capacity({
         let (low, high) = iter.size_hint();
         high.unwrap_or(low)
     });
 
-    let should_return = select_top_items_to_show(&mut buffer, &mut iter);
+    let should_return = select_top_items_to_show(
+        &mut buffer,
+        &mut iter,
+        &mut schedule,
+        update_opts,
+        progress,
+        display_count,
+    );
 
     let (mut total, mut top_scores, mut top_results) = match should_return {
         Ok(_) => return buffer,
@@ -192,29 +586,195 @@ fn dyn_collect_all(
 
     // Now we have the full queue and can just pair `.pop_back()` with `.insert()` to keep
     // the queue with best results the same size.
-    let mut past = std::time::Instant::now();
-    iter.for_each(|(text, score, indices)| {
-        let idx = find_best_score_idx(&top_scores, score);
-
-        insert_both!(pop; idx, score, text, indices => buffer, top_results, top_scores);
+    iter.for_each(|item| {
+        push_item(
+            &mut buffer,
+            &mut top_scores,
+            &mut top_results,
+            item,
+            update_opts.reverse,
+        );
 
         total = total.wrapping_add(1);
 
-        if let Ok(now) = try_notify_top_results(
-            enable_icon,
+        try_notify_top_results(
+            &mut schedule,
+            update_opts,
+            progress,
             total,
-            &past,
             top_results.len(),
             &top_results,
             &buffer,
-        ) {
-            past = now;
+        );
+
+        if let Some(max) = max_candidates {
+            let max = max.max(display_count);
+            if buffer.len() >= 2 * max {
+                trim_buffer(
+                    &mut buffer,
+                    &mut top_scores,
+                    &mut top_results,
+                    max,
+                    update_opts.reverse,
+                );
+            }
         }
     });
 
     buffer
 }
 
+/// Pushes `item` into `buffer` once the `top_` queues are full, the queues are updated in place
+/// and always point to the exact best items of `buffer`, the earlier first for the same score.
+fn push_item(
+    buffer: &mut Vec<FuzzyMatchedLineInfo>,
+    top_scores: &mut [i64],
+    top_results: &mut [usize],
+    (text, score, indices): FuzzyMatchedLineInfo,
+    reverse: bool,
+) {
+    let idx = find_best_score_idx(top_scores, score, reverse);
+    insert_both!(pop; idx, score, text, indices => buffer, top_results, top_scores);
+}
+
+/// Keeps the best `len` items of `buffer`, `len` must not be less than the number of the top
+/// items, which are updated to point to the first items of the sorted buffer.
+fn trim_buffer(
+    buffer: &mut Vec<FuzzyMatchedLineInfo>,
+    top_scores: &mut [i64],
+    top_results: &mut [usize],
+    len: usize,
+    reverse: bool,
+) {
+    // Stable sort keeps the source order of the items with the same score.
+    buffer.par_sort_by(|(_, v1, _), (_, v2, _)| cmp_scores(*v1, *v2, reverse));
+
+    for (idx, (_, score, _)) in buffer[..top_scores.len()].iter().enumerate() {
+        top_scores[idx] = *score;
+        top_results[idx] = idx;
+    }
+
+    buffer.truncate(len);
+}
+
+/// Inserts the `result` with `score` into the top queues keeping at most `capacity` best ones.
+fn insert_top(
+    top_scores: &mut Vec<i64>,
+    top_results: &mut Vec<usize>,
+    capacity: usize,
+    score: i64,
+    result: usize,
+    reverse: bool,
+) {
+    let idx = find_best_score_idx(top_scores, score, reverse).map_or(0, |idx| idx + 1);
+    if idx < capacity {
+        top_scores.insert(idx, score);
+        top_results.insert(idx, result);
+        top_scores.truncate(capacity);
+        top_results.truncate(capacity);
+    }
+}
+
+/// Parallel version of [`dyn_collect_all`], the lines of `iter` are scored by `scorer`.
+///
+/// The lines are read in batches, each batch is partitioned and scored by rayon, every partition
+/// keeps its own top items, which are then merged into the global top items in the order of
+/// partitions, so that the items of the same score are still in the source order. The updates
+/// are sent after merging a batch if it's time to. The buffer is trimmed after merging a batch
+/// the same way as [`dyn_collect_all`] does with `max_candidates`.
+fn par_dyn_collect_all(
+    mut iter: impl Iterator<Item = String>,
+    scorer: &(dyn Fn(&str) -> Option<MatchResult> + Sync),
+    update_opts: UpdateOptions,
+    progress: &SourceProgress,
+    display_count: usize,
+    mut schedule: UpdateSchedule,
+    max_candidates: Option<usize>,
+) -> Vec<FuzzyMatchedLineInfo> {
+    let display_count = display_count.max(1);
+    let mut buffer = Vec::new();
+    let mut top_scores = Vec::with_capacity(display_count + 1);
+    let mut top_results = Vec::with_capacity(display_count + 1);
+
+    loop {
+        let batch = iter.by_ref().take(PARALLEL_BATCH_SIZE).collect::<Vec<_>>();
+        if batch.is_empty() {
+            break;
+        }
+
+        let partitions = batch
+            .par_chunks(PARALLEL_PARTITION_SIZE)
+            .map(|lines| {
+                let mut matched: Vec<FuzzyMatchedLineInfo> = Vec::new();
+                let mut top_scores = Vec::with_capacity(display_count + 1);
+                let mut top_results = Vec::with_capacity(display_count + 1);
+                for line in lines {
+                    if let Some((score, indices)) = scorer(line) {
+                        matched.push((line.clone(), score, indices));
+                        let idx = matched.len() - 1;
+                        insert_top(
+                            &mut top_scores,
+                            &mut top_results,
+                            display_count,
+                            score,
+                            idx,
+                            update_opts.reverse,
+                        );
+                    }
+                }
+                (matched, top_results)
+            })
+            .collect::<Vec<_>>();
+
+        for (matched, partition_top_results) in partitions {
+            let offset = buffer.len();
+            for idx in partition_top_results {
+                let score = matched[idx].1;
+                insert_top(
+                    &mut top_scores,
+                    &mut top_results,
+                    display_count,
+                    score,
+                    offset + idx,
+                    update_opts.reverse,
+                );
+            }
+            buffer.extend(matched);
+        }
+
+        // The first matches are shown as soon as possible.
+        let update = if !buffer.is_empty() && buffer.len() == top_results.len() {
+            Some(Update::Full)
+        } else {
+            schedule.check_interval(Instant::now())
+        };
+        notify_top_results(
+            update,
+            update_opts,
+            progress,
+            buffer.len(),
+            top_results.len(),
+            &top_results,
+            &buffer,
+        );
+
+        if let Some(max) = max_candidates {
+            let max = max.max(display_count);
+            if buffer.len() >= 2 * max {
+                trim_buffer(
+                    &mut buffer,
+                    &mut top_scores,
+                    &mut top_results,
+                    max,
+                    update_opts.reverse,
+                );
+            }
+        }
+    }
+
+    buffer
+}
+
 /// If you only need a `number` of elements, then you don't need to collect all
 /// items produced by the iterator.
 ///
@@ -228,14 +788,24 @@ fn dyn_collect_all(
 // `collect()` into Vec on big numbers of iterations.
 fn dyn_collect_number(
     mut iter: impl Iterator<Item = FuzzyMatchedLineInfo>,
-    enable_icon: bool,
+    update_opts: UpdateOptions,
+    progress: &SourceProgress,
+    display_count: usize,
     number: usize,
+    mut schedule: UpdateSchedule,
 ) -> (usize, Vec<FuzzyMatchedLineInfo>) {
     // To not have problems with queues after sorting and truncating the buffer,
-    // buffer has the lowest bound of `ITEMS_TO_SHOW * 2`, not `number * 2`.
-    let mut buffer = Vec::with_capacity(2 * std::cmp::max(ITEMS_TO_SHOW, number));
+    // buffer has the lowest bound of `display_count * 2`, not `number * 2`.
+    let mut buffer = Vec::with_capacity(2 * std::cmp::max(display_count.max(1), number));
 
-    let should_return = select_top_items_to_show(&mut buffer, &mut iter);
+    let should_return = select_top_items_to_show(
+        &mut buffer,
+        &mut iter,
+        &mut schedule,
+        update_opts,
+        progress,
+        display_count,
+    );
 
     let (mut total, mut top_scores, mut top_results) = match should_return {
         Ok(t) => return (t, buffer),
@@ -244,148 +814,613 @@ fn dyn_collect_number(
 
     // Now we have the full queue and can just pair `.pop_back()` with `.insert()` to keep
     // the queue with best results the same size.
-    let mut past = std::time::Instant::now();
-    iter.for_each(|(text, score, indices)| {
-        let idx = find_best_score_idx(&top_scores, score);
-
-        insert_both!(pop; idx, score, text, indices => buffer, top_results, top_scores);
+    iter.for_each(|item| {
+        push_item(
+            &mut buffer,
+            &mut top_scores,
+            &mut top_results,
+            item,
+            update_opts.reverse,
+        );
 
         total += 1;
 
-        if let Ok(now) = try_notify_top_results(
-            enable_icon,
+        try_notify_top_results(
+            &mut schedule,
+            update_opts,
+            progress,
             total,
-            &past,
             top_results.len(),
             &top_results,
             &buffer,
-        ) {
-            past = now;
-        }
+        );
 
         if buffer.len() == buffer.capacity() {
-            buffer.par_sort_unstable_by(|(_, v1, _), (_, v2, _)| v2.partial_cmp(&v1).unwrap());
-
-            for (idx, (_, score, _)) in buffer[..ITEMS_TO_SHOW].iter().enumerate() {
-                top_scores[idx] = *score;
-                top_results[idx] = idx;
-            }
-
             let half = buffer.len() / 2;
-            buffer.truncate(half);
+            trim_buffer(
+                &mut buffer,
+                &mut top_scores,
+                &mut top_results,
+                half,
+                update_opts.reverse,
+            );
         }
     });
 
     (total, buffer)
 }
 
-/// Returns the ranked results after applying fuzzy filter given the query string and a list of candidates.
-pub fn dyn_fuzzy_filter_and_rank<I: Iterator<Item = String>>(
-    query: &str,
-    source: Source<I>,
-    algo: Option<Algo>,
-    number: Option<usize>,
-    enable_icon: bool,
-    winwidth: Option<usize>,
-) -> Result<()> {
-    let algo = algo.unwrap_or(Algo::Fzy);
+/// Tempfiles of the items spilled when the buffered ones exceed the memory budget.
+///
+/// Each of them is a run of `score\ttext` lines in the ranking order of score, they are
+/// removed once dropped.
+#[derive(Debug, Default)]
+struct SpilledRuns(Vec<PathBuf>);
+
+impl SpilledRuns {
+    fn spill(&mut self, items: &[FuzzyMatchedLineInfo]) -> Result<()> {
+        let path = tempfile_path(&format!("maple_spill{}", self.0.len()))?;
+        // Remove the partially written file as well.
+        self.0.push(path.clone());
+        let mut f = BufWriter::new(File::create(&path)?);
+        for (text, score, _) in items {
+            writeln!(f, "{}\t{}", score, text)?;
+        }
+        f.flush()?;
+        Ok(())
+    }
+
+    /// Writes the text of `ranked` merged with all the spilled items to a tempfile in
+    /// descending order of score, or ascending order if `reverse`.
+    fn write_merged(&self, ranked: &[FuzzyMatchedLineInfo], reverse: bool) -> Result<PathBuf> {
+        let mut sources: Vec<Box<dyn Iterator<Item = (i64, String)>>> = vec![Box::new(
+            ranked.iter().map(|(text, score, _)| (*score, text.clone())),
+        )];
+        for path in &self.0 {
+            let lines = io::BufReader::new(File::open(path)?).lines();
+            sources.push(Box::new(lines.filter_map(|line| {
+                let line = line.ok()?;
+                let mut parts = line.splitn(2, '\t');
+                let score = parts.next()?.parse().ok()?;
+                Some((score, parts.next()?.to_string()))
+            })));
+        }
+
+        // Widened so that negating any score never overflows.
+        let rank_key = |score: i64| {
+            let score = i128::from(score);
+            if reverse {
+                -score
+            } else {
+                score
+            }
+        };
+
+        // The earlier source wins the tie to keep the order of the in-memory items.
+        let mut heap = BinaryHeap::new();
+        for (idx, source) in sources.iter_mut().enumerate() {
+            if let Some((score, text)) = source.next() {
+                heap.push((rank_key(score), Reverse(idx), text));
+            }
+        }
+
+        let tempfile = tempfile_path("maple_filter")?;
+        let mut f = BufWriter::new(File::create(&tempfile)?);
+        while let Some((_, Reverse(idx), text)) = heap.pop() {
+            writeln!(f, "{}", text)?;
+            if let Some((score, text)) = sources[idx].next() {
+                heap.push((rank_key(score), Reverse(idx), text));
+            }
+        }
+        f.flush()?;
+        Ok(tempfile)
+    }
+}
+
+impl Drop for SpilledRuns {
+    fn drop(&mut self) {
+        for path in &self.0 {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Approximate number of bytes taken by the item in memory.
+fn item_bytes((text, _, indices): &FuzzyMatchedLineInfo) -> usize {
+    std::mem::size_of::<FuzzyMatchedLineInfo>()
+        + text.len()
+        + indices.len() * std::mem::size_of::<usize>()
+}
+
+/// Collects all the items like [`dyn_collect_all`], but once the buffered items exceed
+/// `budget` bytes, all but the top `number` of them are spilled to the disk.
+///
+/// No intermediate results are sent to the client.
+///
+/// # Returns
+///
+/// Tuple of `(total_number_of_iterations: usize, Vec<_>, SpilledRuns)`.
+/// The vector is not sorted nor truncated.
+fn dyn_collect_within_budget(
+    iter: impl Iterator<Item = FuzzyMatchedLineInfo>,
+    number: usize,
+    budget: usize,
+    reverse: bool,
+) -> Result<(usize, Vec<FuzzyMatchedLineInfo>, SpilledRuns)> {
+    let mut buffer = Vec::new();
+    let mut buffered_bytes = 0;
+    let mut runs = SpilledRuns::default();
+    let mut total = 0;
+
+    for item in iter {
+        buffered_bytes += item_bytes(&item);
+        buffer.push(item);
+        total += 1;
+
+        if buffered_bytes > budget && buffer.len() > number {
+            // Stable sort keeps the source order of the items with the same score.
+            buffer.par_sort_by(|(_, v1, _), (_, v2, _)| cmp_scores(*v1, *v2, reverse));
+            runs.spill(&buffer[number..])?;
+            buffer.truncate(number);
+            buffered_bytes = buffer.iter().map(item_bytes).sum();
+        }
+    }
+
+    Ok((total, buffer, runs))
+}
+
+/// Info about the lines produced by the source.
+#[derive(Debug, Default)]
+struct SourceInfo {
+    /// Number of lines produced by the source, including the unmatched ones.
+    lines: usize,
+    /// Error of the source command if any.
+    error: Option<String>,
+    /// Whether reading the source is stopped by the interruption.
+    interrupted: bool,
+}
 
-    let scorer = |line: &str| match algo {
-        Algo::Skim => fuzzy_indices(line, query),
-        Algo::Fzy => match_and_score_with_positions(query, line)
-            .map(|(score, indices)| (score as i64, indices)),
+/// Feeds the matched lines of `source` into `collect` along with the progress of reading it.
+///
+/// Reading the source stops once `interrupted` is set.
+///
+/// Returns the collected result and the info about the source.
+fn collect_matched<I: Iterator<Item = String>, T>(
+    source: Source<I>,
+    scorer: &Scorer,
+    interrupted: &AtomicBool,
+    collect: impl FnOnce(&mut dyn Iterator<Item = FuzzyMatchedLineInfo>, &SourceProgress) -> T,
+) -> Result<(T, SourceInfo)> {
+    let lines = Cell::new(0usize);
+    let progress = SourceProgress::new(&source);
+    let scorer = |line: &str| {
+        lines.set(lines.get() + 1);
+        scorer(line)
     };
+    let is_running = || !interrupted.load(Ordering::SeqCst);
 
-    if let Some(number) = number {
-        let (total, filtered) = match source {
-            Source::Stdin => dyn_collect_number(
-                io::stdin().lock().lines().filter_map(|lines_iter| {
+    let collected = match source {
+        Source::Stdin => collect(
+            &mut lossy_lines(io::stdin().lock())
+                .take_while(|_| is_running())
+                .filter_map(|lines_iter| {
                     lines_iter.ok().and_then(|line| {
                         scorer(&line).map(|(score, indices)| (line, score, indices))
                     })
                 }),
-                enable_icon,
-                number,
-            ),
-            Source::Exec(exec) => dyn_collect_number(
-                std::io::BufReader::new(exec.stream_stdout()?)
-                    .lines()
+            &progress,
+        ),
+        Source::Exec(exec) => {
+            let mut exec_lines = ExecLines::new(exec)?;
+            let collected = collect(
+                &mut exec_lines
+                    .by_ref()
+                    .take_while(|_| is_running())
                     .filter_map(|lines_iter| {
                         lines_iter.ok().and_then(|line| {
                             scorer(&line).map(|(score, indices)| (line, score, indices))
                         })
                     }),
-                enable_icon,
-                number,
-            ),
-            Source::File(fpath) => dyn_collect_number(
-                std::fs::read_to_string(fpath)?.lines().filter_map(|line| {
-                    scorer(&line).map(|(score, indices)| (line.into(), score, indices))
-                }),
-                enable_icon,
-                number,
-            ),
-            Source::List(list) => dyn_collect_number(
-                list.filter_map(|line| {
-                    scorer(&line).map(|(score, indices)| (line, score, indices))
-                }),
-                enable_icon,
-                number,
-            ),
-        };
-        let (lines, indices, truncated_map) = process_top_items(
-            number,
-            filtered.into_iter().take(number),
-            winwidth.unwrap_or(62),
-            enable_icon,
-        );
-
-        if truncated_map.is_empty() {
-            print_json_with_length!(total, lines, indices);
-        } else {
-            print_json_with_length!(total, lines, indices, truncated_map);
+                &progress,
+            );
+            let interrupted = interrupted.load(Ordering::SeqCst);
+            if interrupted {
+                exec_lines.kill()?;
+            }
+            let info = SourceInfo {
+                lines: lines.get(),
+                error: if interrupted {
+                    None
+                } else {
+                    exec_lines.error()?
+                },
+                interrupted,
+            };
+            return Ok((collected, info));
         }
-    } else {
-        let mut filtered = match source {
-            Source::Stdin => dyn_collect_all(
-                io::stdin().lock().lines().filter_map(|lines_iter| {
+        Source::File(fpath) => collect(
+            &mut lossy_lines(CountingReader::new(
+                io::BufReader::new(File::open(fpath)?),
+                &progress.read,
+            ))
+            .take_while(|_| is_running())
+            .filter_map(|lines_iter| {
+                lines_iter
+                    .ok()
+                    .and_then(|line| scorer(&line).map(|(score, indices)| (line, score, indices)))
+            }),
+            &progress,
+        ),
+        Source::Files { paths, threads } => {
+            let errors = RefCell::new(Vec::new());
+            let collected = collect(
+                &mut read_files_concurrently(paths, threads)
+                    .take_while(|_| is_running())
+                    .filter_map(|line| match line {
+                        Ok(line) => scorer(&line).map(|(score, indices)| (line, score, indices)),
+                        Err(e) => {
+                            errors.borrow_mut().push(format!("{:#}", e));
+                            None
+                        }
+                    }),
+                &progress,
+            );
+            let errors = errors.into_inner();
+            let info = SourceInfo {
+                lines: lines.get(),
+                error: if errors.is_empty() {
+                    None
+                } else {
+                    Some(errors.join("\n"))
+                },
+                interrupted: interrupted.load(Ordering::SeqCst),
+            };
+            return Ok((collected, info));
+        }
+        Source::List(list) => collect(
+            &mut list
+                .take_while(|_| is_running())
+                .map(trim_trailing_cr)
+                .filter_map(|line| scorer(&line).map(|(score, indices)| (line, score, indices))),
+            &progress,
+        ),
+        Source::Bytes(bytes) => collect(
+            &mut byte_lines(&bytes)
+                .take_while(|_| is_running())
+                .filter_map(|line| {
+                    scorer(&line).map(|(score, indices)| (line.into_owned(), score, indices))
+                }),
+            &progress,
+        ),
+        Source::MmapFile(path) => collect(
+            &mut lossy_lines(CountingReader::new(&mmap_file(&path)?[..], &progress.read))
+                .take_while(|_| is_running())
+                .filter_map(|lines_iter| {
                     lines_iter.ok().and_then(|line| {
                         scorer(&line).map(|(score, indices)| (line, score, indices))
                     })
                 }),
-                enable_icon,
-            ),
-            Source::Exec(exec) => dyn_collect_all(
-                std::io::BufReader::new(exec.stream_stdout()?)
-                    .lines()
+            &progress,
+        ),
+        Source::Tags(path) => collect(
+            &mut read_tags(&path)?
+                .take_while(|_| is_running())
+                .filter_map(|tag| {
+                    scorer(&tag.name).map(|(score, indices)| (tag.display(), score, indices))
+                }),
+            &progress,
+        ),
+        Source::Pairs(pairs) => collect(
+            &mut pairs
+                .into_iter()
+                .take_while(|_| is_running())
+                .filter_map(|(display, text)| {
+                    scorer(&text).map(|(score, indices)| {
+                        let indices = remap_indices(&display, &text, &indices);
+                        (display, score, indices)
+                    })
+                }),
+            &progress,
+        ),
+        #[cfg(unix)]
+        Source::UnixSocket(path) => match UnixStream::connect(&path) {
+            Ok(stream) => collect(
+                &mut lossy_lines(io::BufReader::new(stream))
+                    .take_while(|_| is_running())
                     .filter_map(|lines_iter| {
                         lines_iter.ok().and_then(|line| {
                             scorer(&line).map(|(score, indices)| (line, score, indices))
                         })
                     }),
-                enable_icon,
-            ),
-            Source::File(fpath) => dyn_collect_all(
-                std::fs::read_to_string(fpath)?.lines().filter_map(|line| {
-                    scorer(line).map(|(score, indices)| (line.into(), score, indices))
-                }),
-                enable_icon,
-            ),
-            Source::List(list) => dyn_collect_all(
-                list.filter_map(|line| {
-                    scorer(&line).map(|(score, indices)| (line, score, indices))
-                }),
-                enable_icon,
+                &progress,
             ),
-        };
-
-        filtered.par_sort_unstable_by(|(_, v1, _), (_, v2, _)| v2.partial_cmp(&v1).unwrap());
-
+            Err(e) => {
+                let collected = collect(&mut std::iter::empty(), &progress);
+                let info = SourceInfo {
+                    error: Some(format!("Failed to connect to {}: {}", path.display(), e)),
+                    ..Default::default()
+                };
+                return Ok((collected, info));
+            }
+        },
+    };
+
+    let info = SourceInfo {
+        lines: lines.get(),
+        error: None,
+        interrupted: interrupted.load(Ordering::SeqCst),
+    };
+    Ok((collected, info))
+}
+
+/// Returns the ranked results after applying fuzzy filter given the matcher and a list of candidates.
+pub fn dyn_fuzzy_filter_and_rank<I: Iterator<Item = String>>(
+    matcher: &Matcher,
+    source: Source<I>,
+    number: Option<usize>,
+    enable_icon: bool,
+    winwidth: Option<usize>,
+    opts: &FilterOptions,
+) -> Result<()> {
+    // The flag is process-global, a previous run could have been interrupted.
+    INTERRUPTED.store(false, Ordering::SeqCst);
+    if opts.partial_on_interrupt {
+        catch_interrupt()?;
+    }
+
+    dyn_filter_and_rank_to(
+        &mut io::stdout(),
+        matcher,
+        source,
+        number,
+        enable_icon,
+        winwidth,
+        opts,
+        &INTERRUPTED,
+    )
+}
+
+/// Same as [`dyn_fuzzy_filter_and_rank`], but the final results are written to `out`, the
+/// intermediate updates are still printed to stdout.
+///
+/// Reading the source stops once `interrupted` is set.
+#[allow(clippy::too_many_arguments)]
+fn dyn_filter_and_rank_to<I: Iterator<Item = String>>(
+    out: &mut impl Write,
+    matcher: &Matcher,
+    source: Source<I>,
+    number: Option<usize>,
+    enable_icon: bool,
+    winwidth: Option<usize>,
+    opts: &FilterOptions,
+    interrupted: &AtomicBool,
+) -> Result<()> {
+    set_request_id(opts.request_id.clone());
+    set_output_format(opts.output_format);
+
+    if let Some(warning) = tty_stdin_warning(&source, opts) {
+        write_empty_results(out, number, &warning, number.is_some(), opts)?;
+        return Ok(());
+    }
+
+    let schedule = || {
+        UpdateSchedule::new(opts.count_update_interval.map(Duration::from_millis))
+            .refresh_interval(opts.refresh_interval.map(Duration::from_millis))
+            .notify_every(opts.notify_every)
+    };
+    let winwidth = winwidth.unwrap_or(DEFAULT_WINWIDTH);
+    let update_opts = UpdateOptions {
+        enable_icon,
+        winwidth,
+        ids: opts.ids,
+        reverse: matcher.ranks_reversed(),
+    };
+    let display_count = opts.display_count.unwrap_or(ITEMS_TO_SHOW);
+
+    let slowest = RefCell::new(SlowestCandidates::new(SLOWEST_CANDIDATES));
+    let scorer: Box<Scorer> = if opts.profile {
+        Box::new(profiled_scorer(matcher, &slowest))
+    } else {
+        Box::new(|line: &str| matcher.match_line(line))
+    };
+    let scorer: Box<Scorer> = match opts.excluded_path() {
+        Some(excluded) => Box::new(move |line: &str| {
+            if excluded.matches(line) {
+                None
+            } else {
+                scorer(line)
+            }
+        }),
+        None => scorer,
+    };
+    let scorer: Box<Scorer> = if opts.excludes.is_empty() {
+        scorer
+    } else {
+        Box::new(move |line: &str| {
+            if opts.has_excluded_substring(line) {
+                None
+            } else {
+                scorer(line)
+            }
+        })
+    };
+    let seen = RefCell::new(HashSet::new());
+    let scorer = dedup_scorer(scorer, opts.dedup, &seen);
+
+    if let Some(number) = number {
+        // The full ranked results are required for writing the tempfile or ranking not by the
+        // score, the best items can't be selected while collecting.
+        let ((total, filtered, tempfile), info) = if let (Some(threshold), Some(budget), true) = (
+            opts.output_threshold,
+            opts.memory_budget,
+            matcher.ranks_by_score(),
+        ) {
+            let (collected, info) = collect_matched(source, &scorer, interrupted, |iter, _| {
+                dyn_collect_within_budget(iter, number, budget, update_opts.reverse)
+            })?;
+            let (total, mut filtered, runs) = collected?;
+            matcher.rank(&mut filtered);
+            let tempfile = if total > threshold {
+                Some(runs.write_merged(&filtered, update_opts.reverse)?)
+            } else {
+                None
+            };
+            ((total, filtered, tempfile), info)
+        } else if opts.output_threshold.is_some() || !matcher.ranks_by_score() {
+            let (mut filtered, info) =
+                collect_matched(source, &scorer, interrupted, |iter, progress| {
+                    dyn_collect_all(iter, update_opts, progress, display_count, schedule(), None)
+                })?;
+            matcher.rank(&mut filtered);
+            let tempfile = match opts.output_threshold {
+                Some(threshold) => try_write_tempfile(&filtered, threshold)?,
+                None => None,
+            };
+            ((filtered.len(), filtered, tempfile), info)
+        } else {
+            let ((total, mut filtered), info) =
+                collect_matched(source, &scorer, interrupted, |iter, progress| {
+                    dyn_collect_number(
+                        iter,
+                        update_opts,
+                        progress,
+                        display_count,
+                        number,
+                        schedule(),
+                    )
+                })?;
+            matcher.rank(&mut filtered);
+            ((total, filtered, None), info)
+        };
+
+        let top_items = filtered.into_iter().take(number).collect::<Vec<_>>();
+        let ids = result_ids(&top_items, opts.ids);
+        let scores = result_scores(&top_items, opts.with_score);
+        let top_items = replace_items(top_items, &opts.replace).collect::<Vec<_>>();
+        let full_lines = full_lines(&top_items, opts.full_lines);
+        let (lines, indices, truncated_map) = process_top_items(
+            number,
+            top_items,
+            winwidth,
+            enable_icon,
+            opts.display_suffix.as_deref(),
+        );
+
+        let mut msg = serde_json::json!({ "total": total, "lines": lines, "indices": indices });
+        if !truncated_map.is_empty() {
+            msg["truncated_map"] = serde_json::json!(truncated_map);
+        }
+        if let Some(tempfile) = tempfile {
+            msg["tempfile"] = serde_json::json!(tempfile);
+        }
+        if let Some(full_lines) = full_lines {
+            msg["full_lines"] = serde_json::json!(full_lines);
+        }
+        if let Some(ids) = ids {
+            msg["ids"] = serde_json::json!(ids);
+        }
+        if let Some(scores) = scores {
+            msg["scores"] = serde_json::json!(scores);
+        }
+        if info.lines == 0 {
+            msg["source_empty"] = serde_json::json!(true);
+        }
+        if let Some(error) = info.error {
+            msg["error"] = serde_json::json!(error);
+        }
+        if info.interrupted {
+            msg["interrupted"] = serde_json::json!(true);
+        }
+        if opts.span {
+            let spans = indices.iter().map(|i| match_span(i)).collect::<Vec<_>>();
+            msg["spans"] = serde_json::json!(spans);
+        }
+        if opts.compact_indices {
+            try_compact_indices(&mut msg, &indices);
+        }
+        if opts.emit_query {
+            msg["query"] = effective_query(matcher);
+        }
+        if opts.profile {
+            msg["slowest"] = serde_json::json!(slowest.borrow().candidates);
+        }
+        write_with_length(out, &msg)?;
+        try_write_end_marker(out, opts, true)?;
+    } else {
+        let (mut filtered, info) = if opts.parallel && !opts.profile {
+            let excluded = opts.excluded_path();
+            let par_scorer = |line: &str| {
+                if opts.is_excluded(line, excluded.as_ref()) {
+                    None
+                } else {
+                    matcher.match_line(line)
+                }
+            };
+            // The lines are passed through as is, they are scored in parallel later, so all the
+            // duplicate lines are skipped with --dedup.
+            let pass_through =
+                dedup_scorer(Box::new(|_: &str| Some((0, Vec::new()))), opts.dedup, &seen);
+            collect_matched(source, &pass_through, interrupted, |iter, progress| {
+                par_dyn_collect_all(
+                    iter.map(|(line, _, _)| line),
+                    &par_scorer,
+                    update_opts,
+                    progress,
+                    display_count,
+                    schedule(),
+                    opts.max_candidates,
+                )
+            })?
+        } else {
+            collect_matched(source, &scorer, interrupted, |iter, progress| {
+                dyn_collect_all(
+                    iter,
+                    update_opts,
+                    progress,
+                    display_count,
+                    schedule(),
+                    opts.max_candidates,
+                )
+            })?
+        };
+
+        match opts.sort_cap {
+            Some(cap) => matcher.rank_top(&mut filtered, cap),
+            None => matcher.rank(&mut filtered),
+        }
+        if let Some(max) = opts.max_candidates {
+            filtered.truncate(max);
+        }
+
         let ranked = filtered;
 
-        for (text, _, indices) in ranked.iter() {
-            println_json!(text, indices);
+        write_ranked_lines(out, ranked, opts)?;
+
+        if info.lines == 0 {
+            let source_empty = true;
+            writeln_frame!(out, source_empty)?;
         }
+
+        if let Some(error) = info.error {
+            writeln_frame!(out, error)?;
+        }
+
+        if info.interrupted {
+            let interrupted = true;
+            writeln_frame!(out, interrupted)?;
+        }
+
+        if opts.profile {
+            let slowest = &slowest.borrow().candidates;
+            writeln_frame!(out, slowest)?;
+        }
+
+        if opts.emit_query {
+            let query = effective_query(matcher);
+            writeln_frame!(out, query)?;
+        }
+
+        try_write_end_marker(out, opts, false)?;
     }
 
     Ok(())
@@ -395,6 +1430,772 @@ pub fn dyn_fuzzy_filter_and_rank<I: Iterator<Item = String>>(
 mod tests {
     use super::*;
 
+    #[test]
+    fn top_queues_should_point_to_exact_best_items() {
+        let display_count = 30;
+        // Pseudo random scores with plenty of ties.
+        let mut seed = 42u64;
+        let mut items = (0..5000).map(|i| {
+            seed = seed.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+            (format!("{}", i), (seed >> 33) as i64 % 500, Vec::new())
+        });
+
+        let mut buffer = Vec::new();
+        let (_, mut top_scores, mut top_results) = select_top_items_to_show(
+            &mut buffer,
+            &mut items,
+            &mut UpdateSchedule::default(),
+            UpdateOptions::default(),
+            &SourceProgress::default(),
+            display_count,
+        )
+        .unwrap_err();
+
+        for (n, item) in items.enumerate() {
+            push_item(&mut buffer, &mut top_scores, &mut top_results, item, false);
+            if n % 1000 == 999 {
+                trim_buffer(&mut buffer, &mut top_scores, &mut top_results, 100, false);
+            }
+
+            let mut expected = buffer.clone();
+            expected.sort_by(|(_, s1, _), (_, s2, _)| s2.cmp(s1));
+            let top = top_results
+                .iter()
+                .map(|&idx| &buffer[idx])
+                .collect::<Vec<_>>();
+            assert_eq!(top, expected.iter().take(display_count).collect::<Vec<_>>());
+            assert_eq!(
+                top_scores,
+                top.iter().map(|(_, score, _)| *score).collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn slow_candidate_should_be_reported() {
+        let query = "a".repeat(20);
+        let slow_line = "a".repeat(1000);
+        let matcher = Matcher::new(Algo::Fzy, &query);
+        let slowest = RefCell::new(SlowestCandidates::new(3));
+
+        {
+            let scorer = profiled_scorer(&matcher, &slowest);
+            for i in 0..100 {
+                scorer(&format!("{}{}", query, i));
+            }
+            scorer(&slow_line);
+            for i in 0..100 {
+                scorer(&format!("{}{}", i, query));
+            }
+        }
+
+        let slowest = slowest.into_inner().candidates;
+        assert_eq!(slowest.len(), 3);
+        // Not necessarily the first one in case of the preemption of a fast one.
+        assert!(slowest.iter().any(|c| c.line == slow_line));
+    }
+
+    #[test]
+    fn empty_source_should_be_distinguished_from_no_matches() {
+        let matcher = Matcher::new(Algo::Fzy, "zzz");
+        let scorer = |line: &str| matcher.match_line(line);
+
+        let running = AtomicBool::new(false);
+
+        let (matched, info) =
+            collect_matched(Source::from(Vec::new()), &scorer, &running, |iter, _| {
+                iter.count()
+            })
+            .unwrap();
+        assert_eq!(matched, 0);
+        assert_eq!(info.lines, 0);
+
+        let (matched, info) = collect_matched(
+            Source::from(vec!["foo".to_string(), "bar".into()]),
+            &scorer,
+            &running,
+            |iter, _| iter.count(),
+        )
+        .unwrap();
+        assert_eq!(matched, 0);
+        assert_eq!(info.lines, 2);
+    }
+
+    #[test]
+    fn count_updates_should_fire_between_full_updates() {
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+
+        let mut schedule = UpdateSchedule::new(Some(Duration::from_millis(50)));
+        schedule.last_full = start;
+        schedule.last_count = start;
+        let updates = (1..=10)
+            .filter_map(|i| schedule.check(16, at(i * 60)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            updates,
+            vec![
+                Update::Count,
+                Update::Count,
+                Update::Count,
+                Update::Full,
+                Update::Count,
+                Update::Count,
+                Update::Count,
+                Update::Full,
+                Update::Count,
+                Update::Count,
+            ]
+        );
+
+        // Only the full updates without the count interval.
+        let mut schedule = UpdateSchedule::new(None);
+        schedule.last_full = start;
+        let updates = (1..=10)
+            .filter_map(|i| schedule.check(16, at(i * 60)))
+            .collect::<Vec<_>>();
+        assert_eq!(updates, vec![Update::Full, Update::Full]);
+    }
+
+    #[test]
+    fn request_id_should_be_echoed_in_every_frame() {
+        let total = 42;
+        let lines = vec!["foo"];
+        let indices = vec![vec![0]];
+        let frames = || {
+            vec![
+                // Intermediate ones.
+                serde_json::json!({ "total": total, "lines": lines, "indices": indices }),
+                serde_json::json!({ "total": total }),
+                // Final ones.
+                serde_json::json!({ "total": total, "lines": lines, "indices": indices, "source_empty": false }),
+            ]
+        };
+
+        set_request_id(Some("req-7".into()));
+        for msg in frames() {
+            let encoded = encode_with_length(&msg).unwrap();
+            let body = &encoded[encoded.find("\n\n").unwrap() + 2..];
+            assert!(encoded.starts_with(&format!("Content-length: {}\n", body.len())));
+            let decoded: serde_json::Value = serde_json::from_str(body).unwrap();
+            assert_eq!(decoded["request_id"], "req-7");
+        }
+        assert_eq!(
+            frame(serde_json::json!({ "text": "foo" }))["request_id"],
+            "req-7"
+        );
+
+        set_request_id(None);
+        for msg in frames() {
+            assert_eq!(frame(msg.clone()), msg);
+        }
+    }
+
+    #[test]
+    fn few_matches_should_be_notified_before_the_end() {
+        let now = Instant::now();
+        let mut schedule = UpdateSchedule::new(None);
+        let updates = (1..=10)
+            .filter_map(|total| schedule.check(total, now))
+            .collect::<Vec<_>>();
+        assert_eq!(updates, vec![Update::Full]);
+
+        // The later ones are still throttled by the update interval.
+        let later = now + UPDATE_INTERVAL + Duration::from_millis(1);
+        assert_eq!(schedule.check(3, later), None);
+        assert_eq!(schedule.check(4, later), Some(Update::Full));
+
+        let mut schedule = UpdateSchedule::new(None)
+            .refresh_interval(Some(Duration::from_millis(10)))
+            .notify_every(Some(4));
+        let later = Instant::now() + Duration::from_millis(11);
+        assert_eq!(schedule.check(6, later), None);
+        assert_eq!(schedule.check(8, later), Some(Update::Full));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unix_socket_source_should_be_filtered() {
+        use std::io::Write;
+        use std::os::unix::net::UnixListener;
+
+        let mut path = std::env::temp_dir();
+        path.push(format!("maple_test_socket_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"foo\nbar\nbaz\n").unwrap();
+        });
+
+        let matcher = Matcher::new(Algo::Fzy, "ba");
+        let scorer = |line: &str| matcher.match_line(line);
+        let running = AtomicBool::new(false);
+        let (matched, info) = collect_matched(
+            Source::<std::iter::Empty<_>>::UnixSocket(path.clone()),
+            &scorer,
+            &running,
+            |iter, _| iter.map(|(text, _, _)| text).collect::<Vec<_>>(),
+        )
+        .unwrap();
+        server.join().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(matched, vec!["bar", "baz"]);
+        assert_eq!(info.lines, 3);
+        assert!(info.error.is_none());
+
+        // Connection failure results in an empty source with the error.
+        let (matched, info) = collect_matched(
+            Source::<std::iter::Empty<_>>::UnixSocket(path),
+            &scorer,
+            &running,
+            |iter, _| iter.count(),
+        )
+        .unwrap();
+        assert_eq!(matched, 0);
+        assert_eq!(info.lines, 0);
+        assert!(info.error.is_some());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn interrupt_should_stop_with_partial_results() {
+        // Set by the source itself, as the signal handler would do in the middle of reading it.
+        let lines = |interrupted: &'static AtomicBool| {
+            (0..1000).map(move |i| {
+                if i == 500 {
+                    interrupted.store(true, Ordering::SeqCst);
+                }
+                format!("line {}", i)
+            })
+        };
+
+        let matcher = Matcher::new(Algo::Fzy, "line");
+        let scorer = |line: &str| matcher.match_line(line);
+        static COLLECT_INTERRUPTED: AtomicBool = AtomicBool::new(false);
+        let source = Source::List(lines(&COLLECT_INTERRUPTED));
+        let (matched, info) = collect_matched(source, &scorer, &COLLECT_INTERRUPTED, |iter, _| {
+            dyn_collect_number(
+                iter,
+                UpdateOptions::default(),
+                &SourceProgress::default(),
+                ITEMS_TO_SHOW,
+                10,
+                UpdateSchedule::default(),
+            )
+        })
+        .unwrap();
+        assert!(info.interrupted);
+        assert_eq!(matched.0, 500);
+        assert!(!matched.1.is_empty());
+
+        static RUN_INTERRUPTED: AtomicBool = AtomicBool::new(false);
+        let mut out = Vec::new();
+        dyn_filter_and_rank_to(
+            &mut out,
+            &matcher,
+            Source::List(lines(&RUN_INTERRUPTED)),
+            None,
+            false,
+            None,
+            &FilterOptions::default(),
+            &RUN_INTERRUPTED,
+        )
+        .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let frames = out.lines().collect::<Vec<_>>();
+        assert_eq!(frames.len(), 501);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(frames[500]).unwrap(),
+            serde_json::json!({ "interrupted": true })
+        );
+    }
+
+    #[test]
+    fn reverse_should_keep_the_real_scores() {
+        let lines = ["xaxxbxxc", "abcd", "a_b_c", "xxabc", "nothing"];
+        let source = || Source::List(lines.iter().map(|line| line.to_string()));
+        let real_scores = {
+            let matcher = Matcher::new(Algo::Fzy, "abc");
+            let mut scores = lines
+                .iter()
+                .filter_map(|line| matcher.match_line(line).map(|(score, _)| score))
+                .collect::<Vec<_>>();
+            scores.sort_unstable();
+            scores
+        };
+        let matcher = Matcher::new(Algo::Fzy, "abc").reverse(true);
+        let opts = FilterOptions {
+            with_score: true,
+            ..Default::default()
+        };
+        let interrupted = AtomicBool::new(false);
+
+        let mut out = Vec::new();
+        dyn_filter_and_rank_to(
+            &mut out,
+            &matcher,
+            source(),
+            Some(3),
+            false,
+            None,
+            &opts,
+            &interrupted,
+        )
+        .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let msg =
+            serde_json::from_str::<serde_json::Value>(&out[out.find('{').unwrap()..]).unwrap();
+        assert_eq!(msg["scores"], serde_json::json!(real_scores[..3]));
+
+        let mut out = Vec::new();
+        dyn_filter_and_rank_to(
+            &mut out,
+            &matcher,
+            source(),
+            None,
+            false,
+            None,
+            &opts,
+            &interrupted,
+        )
+        .unwrap();
+        let scores = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["score"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            scores,
+            serde_json::json!(real_scores).as_array().unwrap().clone()
+        );
+    }
+
+    #[test]
+    fn effective_query_should_be_emitted() {
+        let matcher = MatchOptions {
+            ascii_fold: true,
+            match_field: Some(2),
+            ..Default::default()
+        }
+        .matcher(Algo::Fzy, "  ^src !tést main$ ");
+        let source = || Source::List(vec!["a\t^src !test main$".to_string()].into_iter());
+        let opts = FilterOptions {
+            emit_query: true,
+            ..Default::default()
+        };
+        let interrupted = AtomicBool::new(false);
+        let expected = serde_json::json!({
+            "text": "^src !test main$",
+            "algo": "fzy",
+            "respect_case": false,
+            "ascii_fold": true,
+            "whole_word": false,
+            "match_field": 2,
+            "match_columns": null,
+        });
+
+        let mut out = Vec::new();
+        dyn_filter_and_rank_to(
+            &mut out,
+            &matcher,
+            source(),
+            None,
+            false,
+            None,
+            &opts,
+            &interrupted,
+        )
+        .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let frames = out.lines().collect::<Vec<_>>();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(frames[1]).unwrap(),
+            serde_json::json!({ "query": expected })
+        );
+
+        let mut out = Vec::new();
+        dyn_filter_and_rank_to(
+            &mut out,
+            &matcher,
+            source(),
+            Some(1),
+            false,
+            None,
+            &opts,
+            &interrupted,
+        )
+        .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let msg =
+            serde_json::from_str::<serde_json::Value>(&out[out.find('{').unwrap()..]).unwrap();
+        assert_eq!(msg["total"], 1);
+        assert_eq!(msg["query"], expected);
+    }
+
+    #[test]
+    fn files_should_be_read_concurrently() {
+        let dir = std::env::temp_dir().join(format!("maple_files_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut paths = (0..4)
+            .map(|i| {
+                let path = dir.join(format!("{}.txt", i));
+                let content = (0..100)
+                    .map(|j| format!("{} {} {}", if j % 10 == 0 { "needle" } else { "hay" }, i, j))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                std::fs::write(&path, content).unwrap();
+                path
+            })
+            .collect::<Vec<_>>();
+        let missing = dir.join("missing.txt");
+        paths.insert(1, missing.clone());
+
+        let matcher = Matcher::new(Algo::Fzy, "needle");
+        let scorer = |line: &str| matcher.match_line(line);
+        let source: Source<std::iter::Empty<_>> = Source::Files { paths, threads: 3 };
+        let (filtered, info) =
+            collect_matched(source, &scorer, &AtomicBool::new(false), |iter, _| {
+                iter.collect::<Vec<_>>()
+            })
+            .unwrap();
+
+        let mut matched = filtered
+            .into_iter()
+            .map(|(text, _, _)| text)
+            .collect::<Vec<_>>();
+        matched.sort();
+        let mut expected = (0..4)
+            .flat_map(|i| {
+                (0..100)
+                    .step_by(10)
+                    .map(move |j| format!("needle {} {}", i, j))
+            })
+            .collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(matched, expected);
+        assert_eq!(info.lines, 400);
+        assert!(info.error.unwrap().contains(&missing.display().to_string()));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn exec_failure_should_be_reported_with_partial_results() {
+        let mut exec_lines = ExecLines::new(Exec::shell("echo foo; echo bar; exit 3")).unwrap();
+        let partial = exec_lines
+            .by_ref()
+            .filter_map(|line| line.ok())
+            .collect::<Vec<_>>();
+        assert_eq!(partial, vec!["foo", "bar"]);
+        assert!(exec_lines.error().unwrap().is_some());
+
+        let mut exec_lines = ExecLines::new(Exec::shell("echo foo")).unwrap();
+        assert_eq!(exec_lines.by_ref().count(), 1);
+        assert!(exec_lines.error().unwrap().is_none());
+    }
+
+    #[test]
+    fn invalid_utf8_lines_should_be_kept() {
+        let mut exec_lines =
+            ExecLines::new(Exec::shell("printf 'foo\\n\\377bar\\r\\nbaz'")).unwrap();
+        let lines = exec_lines.by_ref().collect::<io::Result<Vec<_>>>().unwrap();
+        assert_eq!(lines, vec!["foo", "\u{fffd}bar", "baz"]);
+        assert!(exec_lines.error().unwrap().is_none());
+    }
+
+    #[test]
+    fn memory_budget_should_spill_but_keep_top_items() {
+        let matcher = Matcher::new(Algo::Fzy, "");
+        let items = (0..1000)
+            .map(|i| (format!("line{}", i), (i * 7919 % 1000) as i64, vec![]))
+            .collect::<Vec<_>>();
+        let mut expected = items.clone();
+        matcher.rank(&mut expected);
+
+        let (total, mut filtered, runs) =
+            dyn_collect_within_budget(items.into_iter(), 10, 4096, false).unwrap();
+        matcher.rank(&mut filtered);
+
+        assert_eq!(total, 1000);
+        assert!(!runs.0.is_empty());
+        assert!(filtered.len() < 1000);
+        assert_eq!(filtered[..10], expected[..10]);
+
+        let tempfile = runs.write_merged(&filtered, false).unwrap();
+        let merged = std::fs::read_to_string(&tempfile).unwrap();
+        assert_eq!(
+            merged.lines().collect::<Vec<_>>(),
+            expected
+                .iter()
+                .map(|(text, _, _)| text.as_str())
+                .collect::<Vec<_>>()
+        );
+        std::fs::remove_file(tempfile).unwrap();
+
+        let spilled = runs.0.clone();
+        drop(runs);
+        assert!(spilled.iter().all(|path| !path.exists()));
+    }
+
+    #[test]
+    fn uniform_scores_should_keep_source_order() {
+        let matcher = Matcher::new(Algo::Fzy, "a");
+        let lines = (0..1000).map(|i| format!("a{:04}", i)).collect::<Vec<_>>();
+        let matched = lines.iter().map(|line| {
+            let (score, indices) = matcher.match_line(line).unwrap();
+            (line.clone(), score, indices)
+        });
+
+        let (total, mut filtered) = dyn_collect_number(
+            matched,
+            UpdateOptions::default(),
+            &SourceProgress::default(),
+            ITEMS_TO_SHOW,
+            30,
+            UpdateSchedule::default(),
+        );
+        matcher.rank(&mut filtered);
+
+        assert_eq!(total, 1000);
+        assert_eq!(
+            filtered
+                .into_iter()
+                .take(30)
+                .map(|(text, _, _)| text)
+                .collect::<Vec<_>>(),
+            lines[..30].to_vec()
+        );
+    }
+
+    #[test]
+    fn bytes_source_should_drop_trailing_empty_line() {
+        let matcher = Matcher::new(Algo::Fzy, "");
+        let scorer = |line: &str| matcher.match_line(line);
+        let running = AtomicBool::new(false);
+        let lines = |bytes: &[u8]| {
+            collect_matched(
+                Source::<std::iter::Empty<_>>::Bytes(bytes.to_vec()),
+                &scorer,
+                &running,
+                |iter, _| iter.map(|(text, _, _)| text).collect::<Vec<_>>(),
+            )
+            .unwrap()
+        };
+
+        let (matched, info) = lines(b"foo\n\nbar\xff\n");
+        assert_eq!(matched, vec!["foo", "", "bar\u{fffd}"]);
+        assert_eq!(info.lines, 3);
+        assert_eq!(lines(b"foo\nbar").0, vec!["foo", "bar"]);
+        assert!(lines(b"").0.is_empty());
+        assert_eq!(lines(b"\n").0, Vec::<String>::new());
+    }
+
+    #[test]
+    fn tags_source_should_only_match_names() {
+        let path = std::env::temp_dir().join(format!("maple_tags_{}", std::process::id()));
+        std::fs::write(
+            &path,
+            "!_TAG_FILE_FORMAT\t2\t/extended format/\n\
+             main\tsrc/main.rs\t/^fn main() {$/;\"\tf\tline:3\n\
+             run\tsrc/main.rs\t7;\"\tf\n",
+        )
+        .unwrap();
+        let running = AtomicBool::new(false);
+        let matched = |query: &str| {
+            let matcher = Matcher::new(Algo::Fzy, query);
+            collect_matched(
+                Source::<std::iter::Empty<_>>::Tags(path.clone()),
+                &|line: &str| matcher.match_line(line),
+                &running,
+                |iter, _| iter.collect::<Vec<_>>(),
+            )
+            .unwrap()
+        };
+
+        let (tags, info) = matched("");
+        assert_eq!(info.lines, 2);
+        assert_eq!(tags[0].0, "main:src/main.rs:3");
+        assert_eq!(tags[1].0, "run:src/main.rs:7");
+        assert!(matched("src").0.is_empty());
+        assert_eq!(matched("mn").0[0].2, vec![0, 3]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn dedup_should_skip_duplicate_matches() {
+        let matcher = Matcher::new(Algo::Fzy, "foo");
+        let seen = RefCell::new(HashSet::new());
+        let scorer = dedup_scorer(Box::new(|line: &str| matcher.match_line(line)), true, &seen);
+        let source: Source<_> = vec!["foo", "bar", "foo", "bar", "foobar", "foo"]
+            .into_iter()
+            .map(String::from)
+            .collect::<Vec<_>>()
+            .into();
+        let running = AtomicBool::new(false);
+        let (matched, info) = collect_matched(source, &scorer, &running, |iter, _| {
+            iter.map(|(text, _, _)| text).collect::<Vec<_>>()
+        })
+        .unwrap();
+        assert_eq!(matched, vec!["foo", "foobar"]);
+        assert_eq!(info.lines, 6);
+        // Only the matched lines are remembered.
+        assert_eq!(seen.borrow().len(), 2);
+    }
+
+    #[test]
+    fn crlf_lines_should_be_trimmed_in_all_sources() {
+        let fixture = "fn main() {}\r\nlet line = 1;\r\n";
+        let path = std::env::temp_dir().join(format!("maple_crlf_{}", std::process::id()));
+        std::fs::write(&path, fixture).unwrap();
+
+        let matcher = Matcher::new(Algo::Fzy, "ne");
+        let scorer = |line: &str| matcher.match_line(line);
+        let running = AtomicBool::new(false);
+        let matched = |source: Source<std::vec::IntoIter<String>>| {
+            collect_matched(source, &scorer, &running, |iter, _| {
+                iter.collect::<Vec<_>>()
+            })
+            .unwrap()
+            .0
+        };
+
+        let expected = vec![("let line = 1;".to_string(), 0, vec![6, 7])];
+        let sources = vec![
+            Source::Bytes(fixture.into()),
+            Source::File(path.clone()),
+            Source::MmapFile(path.clone()),
+            Source::Exec(Exec::shell(format!("cat {}", path.display()))),
+            Source::List(vec!["fn main() {}\r".to_string(), "let line = 1;\r".into()].into_iter()),
+        ];
+        for source in sources {
+            let matched = matched(source)
+                .into_iter()
+                .map(|(text, _, indices)| (text, 0, indices))
+                .collect::<Vec<_>>();
+            assert_eq!(matched, expected);
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn full_updates_should_have_ids() {
+        let long_line = format!("{}needle", "x".repeat(200));
+        let buffer = vec![
+            ("hay".to_string(), 1, vec![0]),
+            (long_line.clone(), 10, vec![200]),
+        ];
+        let update_opts = UpdateOptions {
+            ids: true,
+            ..Default::default()
+        };
+        let msg = top_results_msg(Update::Full, update_opts, 2, &[1, 0], &buffer);
+        // The ids are of the text before truncated.
+        assert_ne!(msg["lines"][0], serde_json::json!(long_line));
+        assert_eq!(
+            msg["ids"],
+            serde_json::json!([result_id(&long_line), result_id("hay")])
+        );
+
+        let msg = top_results_msg(Update::Full, UpdateOptions::default(), 2, &[1, 0], &buffer);
+        assert!(msg.get("ids").is_none());
+        let msg = top_results_msg(Update::Count, update_opts, 2, &[1, 0], &buffer);
+        assert_eq!(msg, serde_json::json!({ "total": 2 }));
+    }
+
+    #[test]
+    fn progress_should_be_known_for_file_sources() {
+        let path =
+            std::env::temp_dir().join(format!("maple_source_progress_{}", std::process::id()));
+        std::fs::write(&path, "foo\nbar\nbaz\nqux\n").unwrap();
+
+        let scorer = |_: &str| Some((0, Vec::new()));
+        let progress = |source: Source<std::vec::IntoIter<String>>| {
+            collect_matched(
+                source,
+                &scorer,
+                &AtomicBool::new(false),
+                |iter, progress| {
+                    let mut fractions = Vec::new();
+                    while iter.next().is_some() {
+                        fractions.push(progress.fraction());
+                    }
+                    fractions
+                },
+            )
+            .unwrap()
+            .0
+        };
+
+        let expected = vec![Some(0.25), Some(0.5), Some(0.75), Some(1.0)];
+        assert_eq!(progress(Source::File(path.clone())), expected);
+        assert_eq!(progress(Source::MmapFile(path.clone())), expected);
+        assert_eq!(progress(Source::from(vec!["foo".to_string()])), vec![None]);
+
+        // The raw bytes are counted, not the lines read.
+        std::fs::write(&path, b"fo\r\nb\xff\r\n").unwrap();
+        let expected = vec![Some(0.5), Some(1.0)];
+        assert_eq!(progress(Source::File(path.clone())), expected);
+        assert_eq!(progress(Source::MmapFile(path.clone())), expected);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn display_count_should_bound_top_queues() {
+        let mut top_scores = vec![5, 3, 1];
+        top_scores.pop_and_insert(1, 4);
+        assert_eq!(top_scores, vec![5, 4, 3]);
+        top_scores.pop_and_insert(2, 2);
+        assert_eq!(top_scores, vec![5, 4, 2]);
+
+        let matched = (0..1000).map(|i| (format!("line {}", i), (i * 7919) % 1000, vec![0]));
+        let mut expected = matched.clone().collect::<Vec<_>>();
+        expected.sort_by(|(_, s1, _), (_, s2, _)| s2.cmp(s1));
+
+        for &display_count in &[1, 3, ITEMS_TO_SHOW, 500] {
+            let (total, mut filtered) = dyn_collect_number(
+                matched.clone(),
+                UpdateOptions::default(),
+                &SourceProgress::default(),
+                display_count,
+                10,
+                UpdateSchedule::default(),
+            );
+            filtered.sort_by(|(_, s1, _), (_, s2, _)| s2.cmp(s1));
+            assert_eq!(total, 1000);
+            assert_eq!(filtered[..10], expected[..10]);
+
+            let filtered = dyn_collect_all(
+                matched.clone(),
+                UpdateOptions::default(),
+                &SourceProgress::default(),
+                display_count,
+                UpdateSchedule::default(),
+                None,
+            );
+            assert_eq!(filtered.len(), 1000);
+
+            let mut filtered = dyn_collect_all(
+                matched.clone(),
+                UpdateOptions::default(),
+                &SourceProgress::default(),
+                display_count,
+                UpdateSchedule::default(),
+                Some(100),
+            );
+            assert!(filtered.len() < 2 * 100.max(display_count));
+            filtered.sort_by(|(_, s1, _), (_, s2, _)| s2.cmp(s1));
+            let max = 100.max(display_count);
+            assert_eq!(filtered[..max], expected[..max]);
+        }
+    }
+
     #[test]
     // This is a very time-consuming test,
     // results of which could be proved only be inspecting stdout.
@@ -423,7 +2224,7 @@ mod tests {
         let mut changing_text: [u8; 16] = [ALPHABET[31]; 16];
         let mut total_lines_created: usize = 0;
         dyn_fuzzy_filter_and_rank(
-            "abc",
+            &Matcher::new(Algo::Fzy, "abc"),
             Source::List(
                 std::iter::repeat_with(|| {
                     bytes = bytes.reverse_bits().rotate_right(3).wrapping_add(1);
@@ -450,11 +2251,70 @@ mod tests {
                 })
                 .take(usize::max_value() >> 8),
             ),
-            Some(Algo::Fzy),
             Some(100),
             false,
             None,
+            &FilterOptions::default(),
         )
         .unwrap()
     }
+
+    #[test]
+    fn parallel_collect_should_match_sequential_collect() {
+        let lines = (0..PARALLEL_BATCH_SIZE * 2 + 100)
+            .map(|i| format!("line {}", i))
+            .collect::<Vec<_>>();
+        let scorer = |line: &str| {
+            let n = line[5..].parse::<i64>().unwrap();
+            if n % 3 == 0 {
+                None
+            } else {
+                Some(((n * 7919) % 1000, vec![0]))
+            }
+        };
+
+        let filtered = par_dyn_collect_all(
+            lines.clone().into_iter(),
+            &scorer,
+            UpdateOptions::default(),
+            &SourceProgress::default(),
+            ITEMS_TO_SHOW,
+            UpdateSchedule::default(),
+            None,
+        );
+        let expected = dyn_collect_all(
+            lines
+                .clone()
+                .into_iter()
+                .filter_map(|line| scorer(&line).map(|(score, indices)| (line, score, indices))),
+            UpdateOptions::default(),
+            &SourceProgress::default(),
+            ITEMS_TO_SHOW,
+            UpdateSchedule::default(),
+            None,
+        );
+        assert_eq!(filtered, expected);
+
+        let mut filtered = par_dyn_collect_all(
+            lines.into_iter(),
+            &scorer,
+            UpdateOptions::default(),
+            &SourceProgress::default(),
+            ITEMS_TO_SHOW,
+            UpdateSchedule::default(),
+            Some(100),
+        );
+        assert!(filtered.len() < 2 * 100 + PARALLEL_BATCH_SIZE);
+        filtered.sort_by(|(_, s1, _), (_, s2, _)| s2.cmp(s1));
+        let mut expected = expected;
+        expected.sort_by(|(_, s1, _), (_, s2, _)| s2.cmp(s1));
+        assert_eq!(filtered[..100], expected[..100]);
+
+        let (mut top_scores, mut top_results) = (Vec::new(), Vec::new());
+        for (idx, &score) in [1, 5, 3, 5, 0].iter().enumerate() {
+            insert_top(&mut top_scores, &mut top_results, 3, score, idx, false);
+        }
+        assert_eq!(top_scores, vec![5, 5, 3]);
+        assert_eq!(top_results, vec![1, 3, 2]);
+    }
 }
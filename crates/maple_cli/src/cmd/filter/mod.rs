@@ -1,68 +1,1009 @@
+/// Same as `println_json`, but the message is tagged with the request id, see [`frame`].
+macro_rules! println_frame {
+  ( $( $field:expr ),+ ) => {
+    {
+      println!("{}", frame(serde_json::json!({ $(stringify!($field): $field,)* })))
+    }
+  }
+}
+
+/// Same as `println_frame`, but the message is written to `out`.
+macro_rules! writeln_frame {
+  ( $out:expr, $( $field:expr ),+ ) => {
+    {
+      writeln!($out, "{}", frame(serde_json::json!({ $(stringify!($field): $field,)* })))
+    }
+  }
+}
+
 pub mod dynamic;
 
 pub use dynamic::dyn_fuzzy_filter_and_rank as dyn_run;
 
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
-use std::path::Path;
+use std::fs::File;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
-use anyhow::Result;
-use fuzzy_filter::{fuzzy_filter_and_rank, truncate_long_matched_lines, Algo, Source};
+use anyhow::{Context, Result};
+use fuzzy_filter::{
+    truncate_long_matched_lines, Algo, CaseMatching, FuzzyMatchedLineInfo, Matcher, ScoringParams,
+    SortOrder, Source, DEFAULT_WINWIDTH,
+};
+use structopt::clap::arg_enum;
+use structopt::StructOpt;
 
 use icon::prepend_icon;
 
+use crate::utils::{fnv1a, ExcludedPath, FNV_OFFSET_BASIS};
+
+/// Options for matching the query against each candidate.
+#[derive(StructOpt, Debug, Default)]
+pub struct MatchOptions {
+    /// Keep the leading and trailing whitespace of the query, which are trimmed by default.
+    #[structopt(long = "keep-query-whitespace")]
+    pub keep_query_whitespace: bool,
+
+    /// Match against the ASCII folded text, e.g., `cafe` matches `café`.
+    #[structopt(long = "ascii-fold")]
+    pub ascii_fold: bool,
+
+    /// Whether the case of the query is respected, `smart` by default, i.e., only if the query
+    /// contains an uppercase char.
+    #[structopt(long = "case-matching", possible_values = &CaseMatching::variants(), case_insensitive = true)]
+    pub case_matching: Option<CaseMatching>,
+
+    /// Only match the query as a whole word, e.g., `err` matches `err handler` but not `error`.
+    #[structopt(long = "whole-word")]
+    pub whole_word: bool,
+
+    /// Ignore the icon prepended to the candidates, e.g., the cached lines that have been iconized.
+    #[structopt(long = "strip-icon")]
+    pub strip_icon: bool,
+
+    /// Bonus of fzy for the consecutive matched chars, lower it for the acronym-style queries.
+    #[structopt(long = "fzy-consecutive-bonus")]
+    pub fzy_consecutive_bonus: Option<i32>,
+
+    /// Bonus of fzy for the match at the beginning of a word.
+    #[structopt(long = "fzy-word-bonus")]
+    pub fzy_word_bonus: Option<i32>,
+
+    /// Bonus of fzy for the uppercase match following a lowercase char.
+    #[structopt(long = "fzy-capital-bonus")]
+    pub fzy_capital_bonus: Option<i32>,
+
+    /// Bonus of fzy for the match right after a dot.
+    #[structopt(long = "fzy-dot-bonus")]
+    pub fzy_dot_bonus: Option<i32>,
+
+    /// Bonus of fzy for the match at the beginning of a path component, i.e., right after `/`.
+    #[structopt(long = "fzy-slash-bonus")]
+    pub fzy_slash_bonus: Option<i32>,
+
+    /// Bonus for the candidates whose file extension is the query, half of it if the query is
+    /// just a prefix of the extension.
+    #[structopt(long = "extension-bonus")]
+    pub extension_bonus: Option<i64>,
+
+    /// Bonus scaled by the ratio of the query length to the candidate length, which favors the
+    /// concise matches, e.g., `main` for `main.rs` over a long line.
+    #[structopt(long = "length-ratio-bonus")]
+    pub length_ratio_bonus: Option<i64>,
+
+    /// Penalty for each path separator crossed by the matched chars, which favors the query
+    /// matched within a single path component.
+    #[structopt(long = "path-boundary-penalty")]
+    pub path_boundary_penalty: Option<i64>,
+
+    /// Boost the matched chars at the start of a path segment or word, i.e., after `/`, `_`,
+    /// `-` or a case transition, which suits the file path candidates.
+    #[structopt(long = "path-aware")]
+    pub path_aware: bool,
+
+    /// Only score the first SCORE_PREFIX bytes of each candidate, the full line is still displayed.
+    #[structopt(long = "score-prefix")]
+    pub score_prefix: Option<usize>,
+
+    /// Delimiter of the fields used by --match-field and --tiebreak-fields, `\t` by default.
+    #[structopt(long = "delimiter")]
+    pub delimiter: Option<char>,
+
+    /// Match against the field at this 1-based index only instead of the whole line, the lines
+    /// without such field are never matched. `--nth` is the same as fzf.
+    #[structopt(long = "match-field", alias = "nth")]
+    pub match_field: Option<usize>,
+
+    /// Match against the chars in these 1-based columns only instead of the whole line, e.g.,
+    /// `25-` for the process name column of `ps`, in the format of `cut -c`: START-END or START-.
+    #[structopt(long = "match-columns", parse(try_from_str = parse_columns))]
+    pub match_columns: Option<(usize, Option<usize>)>,
+
+    /// Also match the fields at these 1-based indices and take the best, e.g., the short alias.
+    #[structopt(long = "alternate-fields", use_delimiter = true)]
+    pub alternate_fields: Vec<usize>,
+
+    /// Order the items with the same score by the fields at these 1-based indices in descending order.
+    #[structopt(long = "tiebreak-fields", use_delimiter = true)]
+    pub tiebreak_fields: Vec<usize>,
+
+    /// Rank the items by the leading number of the field at this 1-based index instead of the
+    /// filtering score, the items without such number are ranked last.
+    #[structopt(long = "sort-by-number")]
+    pub sort_by_number: Option<usize>,
+
+    /// Order of --sort-by-number, `desc` by default.
+    #[structopt(long = "sort-order", possible_values = &SortOrder::variants(), case_insensitive = true)]
+    pub sort_order: Option<SortOrder>,
+
+    /// Rank the worst matches first, the emitted scores are still the real ones.
+    #[structopt(long = "reverse")]
+    pub reverse: bool,
+}
+
+impl MatchOptions {
+    /// Returns the matcher applying `algo` to `query` with these options.
+    pub fn matcher(&self, algo: Algo, query: &str) -> Matcher {
+        let query = if self.keep_query_whitespace {
+            query
+        } else {
+            query.trim()
+        };
+        Matcher::new(algo, query)
+            .ascii_fold(self.ascii_fold)
+            .case_matching(self.case_matching.unwrap_or(CaseMatching::Smart))
+            .whole_word(self.whole_word)
+            .strip_icon(self.strip_icon)
+            .fzy_params(self.fzy_params())
+            .extension_bonus(self.extension_bonus)
+            .length_ratio_bonus(self.length_ratio_bonus)
+            .path_boundary_penalty(self.path_boundary_penalty)
+            .path_aware(self.path_aware)
+            .score_prefix(self.score_prefix)
+            .delimiter(self.delimiter.unwrap_or('\t'))
+            .match_field(self.match_field.map(|n| n.saturating_sub(1)))
+            .match_columns(
+                self.match_columns
+                    .map(|(start, end)| (start.saturating_sub(1), end)),
+            )
+            .alternate_fields(
+                self.alternate_fields
+                    .iter()
+                    .map(|n| n.saturating_sub(1))
+                    .collect(),
+            )
+            .tiebreak_fields(
+                self.tiebreak_fields
+                    .iter()
+                    .map(|n| n.saturating_sub(1))
+                    .collect(),
+            )
+            .sort_by_number(
+                self.sort_by_number.map(|n| n.saturating_sub(1)),
+                self.sort_order.unwrap_or(SortOrder::Desc),
+            )
+            .reverse(self.reverse)
+    }
+
+    fn fzy_params(&self) -> ScoringParams {
+        let default = ScoringParams::default();
+        ScoringParams {
+            consecutive: self.fzy_consecutive_bonus.unwrap_or(default.consecutive),
+            word: self.fzy_word_bonus.unwrap_or(default.word),
+            capital: self.fzy_capital_bonus.unwrap_or(default.capital),
+            dot: self.fzy_dot_bonus.unwrap_or(default.dot),
+            slash: self.fzy_slash_bonus.unwrap_or(default.slash),
+        }
+    }
+}
+
+/// Options for collecting and printing the filtered results.
+#[derive(StructOpt, Debug, Default)]
+pub struct FilterOptions {
+    /// Working directory of shell command.
+    #[structopt(short, long)]
+    pub cmd_dir: Option<String>,
+
+    /// Read the stdin even if it's a terminal, otherwise the results are empty with a warning
+    /// as reading it would block until the end of the manual input.
+    #[structopt(long = "read-tty-stdin")]
+    pub read_tty_stdin: bool,
+
+    /// Number of threads reading the files concurrently if multiple inputs are given, 1 by default.
+    #[structopt(long = "read-threads")]
+    pub read_threads: Option<usize>,
+
+    /// Memory-map the input file instead of reading it into memory upfront if a single input
+    /// is given, which is lighter for the huge candidates files.
+    #[structopt(long = "mmap")]
+    pub mmap: bool,
+
+    /// Exclude the result referring to this file, the relative results are resolved against CMD_DIR.
+    #[structopt(long = "exclude-path", parse(from_os_str))]
+    pub exclude_path: Option<PathBuf>,
+
+    /// Exclude the lines containing this substring before scoring them, can be repeated.
+    #[structopt(long = "exclude", number_of_values = 1)]
+    pub excludes: Vec<String>,
+
+    /// Write the full ranked results to a tempfile if the number of them exceeds the threshold,
+    /// only the top NUM of them are printed, valid only when --number is used.
+    #[structopt(long = "output-threshold")]
+    pub output_threshold: Option<usize>,
+
+    /// Keep the buffered results within this number of bytes by spilling all but the top NUM
+    /// of them to the disk, valid only when --output-threshold is used.
+    #[structopt(long = "memory-budget")]
+    pub memory_budget: Option<usize>,
+
+    /// Report the candidates taking the longest time to be scored in the `slowest` field.
+    #[structopt(long = "profile")]
+    pub profile: bool,
+
+    /// Emit the results collected so far as the final frame on Ctrl-C instead of being killed.
+    #[structopt(long = "partial-on-interrupt")]
+    pub partial_on_interrupt: bool,
+
+    /// Score the candidates on all the cores when --number is not given, the updates are then
+    /// sent per scored batch of candidates. Ignored if --profile is used.
+    #[structopt(long = "parallel")]
+    pub parallel: bool,
+
+    /// Keep at most NUM best candidates when --number is not given to bound the memory, the
+    /// buffered ones are trimmed whenever they reach twice of NUM.
+    #[structopt(long = "max-candidates")]
+    pub max_candidates: Option<usize>,
+
+    /// Skip the candidates of the same text as a matched one before, so that the total is the
+    /// number of the unique matches.
+    #[structopt(long = "dedup")]
+    pub dedup: bool,
+
+    /// Emit the span of the first and last matched positions along with the indices of each result.
+    #[structopt(long = "span")]
+    pub span: bool,
+
+    /// Append this suffix to the displayed lines, valid only when --number is used.
+    ///
+    /// `{score}` and `{matches}` in it are replaced with the filtering score and the number of
+    /// matched chars of each line, the client has to strip it to get the original line.
+    #[structopt(long = "display-suffix")]
+    pub display_suffix: Option<String>,
+
+    /// Number of the top items sent by the intermediate updates of the dynamic filtering, 100 by
+    /// default.
+    #[structopt(long = "display-count")]
+    pub display_count: Option<usize>,
+
+    /// Emit the count-only updates at this interval in milliseconds between the refreshes of
+    /// the top items, which keeps the total number of matches live on the fast streams.
+    #[structopt(long = "count-update-interval")]
+    pub count_update_interval: Option<u64>,
+
+    /// Refresh the top items at this interval in milliseconds, 200 by default.
+    #[structopt(long = "refresh-interval")]
+    pub refresh_interval: Option<u64>,
+
+    /// Check whether to refresh the top items per this number of matched items, 16 by default.
+    #[structopt(long = "notify-every")]
+    pub notify_every: Option<usize>,
+
+    /// Emit the indices as the `[start, len]` runs of consecutive positions if that's more
+    /// compact, which is flagged by `indices_format: "rle"`, valid only when --number is used.
+    #[structopt(long = "compact-indices")]
+    pub compact_indices: bool,
+
+    /// Emit BATCH_SIZE results per message in the `items` field instead of a message per result,
+    /// the last batch may be smaller, valid only when --number is not used.
+    ///
+    /// Larger batches mean fewer writes but a longer delay before the client sees the first ones.
+    #[structopt(long = "batch-size")]
+    pub batch_size: Option<usize>,
+
+    /// Only sort and emit the top SORT_CAP results, valid only when --number is not used.
+    #[structopt(long = "sort-cap")]
+    pub sort_cap: Option<usize>,
+
+    /// Emit the effective query used for matching in the `query` field, i.e., the query text
+    /// after trimming the whitespace and ASCII folding along with the algo, case, whole word,
+    /// field and columns options. Note that `^`, `!` and `$` have no special meaning in it.
+    #[structopt(long = "emit-query")]
+    pub emit_query: bool,
+
+    /// Echo this id in the `request_id` field of every message, including the intermediate ones,
+    /// so that the client can tell which request the message answers.
+    #[structopt(long = "request-id")]
+    pub request_id: Option<String>,
+
+    /// Emit `{"type": "end"}` as the last message, which tells the client the output is complete,
+    /// even if nothing is matched or the source is empty.
+    #[structopt(long = "end-marker")]
+    pub end_marker: bool,
+
+    /// Format of the messages prefixed with Content-length info, `json` by default.
+    #[structopt(long = "output-format", possible_values = &OutputFormat::variants(), case_insensitive = true)]
+    pub output_format: Option<OutputFormat>,
+
+    /// Emit the untruncated text of each displayed line in `full_lines`, valid only when --number
+    /// is used.
+    #[structopt(long = "full-lines")]
+    pub full_lines: bool,
+
+    /// Emit the stable id of each result derived from its source text, in `ids` if --number is
+    /// used and in the updates of the top items, otherwise in the `id` field of each result.
+    ///
+    /// The id doesn't change with the display text, e.g., truncation, icon or replacement.
+    #[structopt(long = "ids")]
+    pub ids: bool,
+
+    /// Emit the filtering score of each result for debugging the ranking, in `scores` if
+    /// --number is used, otherwise in the `score` field of each result.
+    #[structopt(long = "with-score")]
+    pub with_score: bool,
+
+    /// Replace FROM with TO in the displayed results, e.g., `/home/user=~`, can be repeated.
+    ///
+    /// The query is still matched against the original text.
+    #[structopt(long = "replace", parse(try_from_str = parse_replacement), number_of_values = 1)]
+    pub replace: Vec<(String, String)>,
+}
+
+impl FilterOptions {
+    fn batch_size(&self) -> usize {
+        self.batch_size.unwrap_or(DEFAULT_BATCH_SIZE).max(1)
+    }
+
+    fn excluded_path(&self) -> Option<ExcludedPath> {
+        let path = self.exclude_path.as_ref()?;
+        ExcludedPath::new(path, self.cmd_dir.as_ref().map(Path::new))
+    }
+
+    /// Returns true if `line` contains any of the substrings of `--exclude`.
+    fn has_excluded_substring(&self, line: &str) -> bool {
+        self.excludes
+            .iter()
+            .any(|excluded| line.contains(excluded.as_str()))
+    }
+
+    /// Returns true if `line` is excluded by `--exclude` or `excluded_path`, which is checked
+    /// before scoring the line.
+    fn is_excluded(&self, line: &str, excluded_path: Option<&ExcludedPath>) -> bool {
+        self.has_excluded_substring(line)
+            || excluded_path.is_some_and(|excluded| excluded.matches(line))
+    }
+}
+
+/// Parses the 1-based inclusive column range `START-END` or `START-`.
+fn parse_columns(s: &str) -> Result<(usize, Option<usize>)> {
+    let invalid = || anyhow::anyhow!("Invalid columns {}, expected START-END or START-", s);
+    let idx = s.find('-').ok_or_else(invalid)?;
+    let start = s[..idx].parse::<usize>().map_err(|_| invalid())?;
+    let end = match &s[idx + 1..] {
+        "" => None,
+        end => Some(end.parse::<usize>().map_err(|_| invalid())?),
+    };
+    if start == 0 || end.map(|end| end < start).unwrap_or(false) {
+        return Err(invalid());
+    }
+    Ok((start, end))
+}
+
+fn parse_replacement(s: &str) -> Result<(String, String)> {
+    let idx = s
+        .find('=')
+        .ok_or_else(|| anyhow::anyhow!("Invalid replacement {}, expected FROM=TO", s))?;
+    Ok((s[..idx].into(), s[idx + 1..].into()))
+}
+
+arg_enum! {
+  /// Format of the messages prefixed with Content-length info, see [`write_with_length`].
+  #[derive(Debug, Clone, Copy, PartialEq)]
+  pub enum OutputFormat {
+      Json,
+      MsgPack,
+  }
+}
+
+thread_local! {
+    static REQUEST_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+    static OUTPUT_FORMAT: Cell<OutputFormat> = const { Cell::new(OutputFormat::Json) };
+}
+
+/// Sets the request id echoed in all the messages printed afterwards.
+fn set_request_id(request_id: Option<String>) {
+    REQUEST_ID.with(|id| *id.borrow_mut() = request_id);
+}
+
+/// Sets the format of the messages with Content-length info printed afterwards.
+fn set_output_format(output_format: Option<OutputFormat>) {
+    OUTPUT_FORMAT.with(|format| format.set(output_format.unwrap_or(OutputFormat::Json)));
+}
+
+/// Tags the message with the request id if any.
+fn frame(mut msg: serde_json::Value) -> serde_json::Value {
+    REQUEST_ID.with(|id| {
+        if let Some(request_id) = id.borrow().as_ref() {
+            msg["request_id"] = serde_json::json!(request_id);
+        }
+    });
+    msg
+}
+
+/// Returns the message tagged with the request id and prefixed with Content-length info.
+fn encode_with_length(msg: &serde_json::Value) -> Option<String> {
+    let s = serde_json::to_string(&frame(msg.clone())).ok()?;
+    Some(format!("Content-length: {}\n\n{}", s.len(), s))
+}
+
+/// Same as [`encode_with_length`], but the message is encoded in MessagePack.
+fn encode_msgpack_with_length(msg: &serde_json::Value) -> Option<Vec<u8>> {
+    let body = rmp_serde::to_vec(&frame(msg.clone())).ok()?;
+    let mut encoded = format!("Content-length: {}\n\n", body.len()).into_bytes();
+    encoded.extend(body);
+    Some(encoded)
+}
+
+/// Writes the message prefixed with Content-length info in the format of --output-format.
+fn write_with_length(out: &mut impl Write, msg: &serde_json::Value) -> std::io::Result<()> {
+    match OUTPUT_FORMAT.with(Cell::get) {
+        OutputFormat::Json => {
+            if let Some(s) = encode_with_length(msg) {
+                writeln!(out, "{}", s)?;
+            }
+        }
+        OutputFormat::MsgPack => {
+            if let Some(encoded) = encode_msgpack_with_length(msg) {
+                out.write_all(&encoded)?;
+                writeln!(out)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Emit a message per result by default, which is what the clients without --batch-size expect.
+const DEFAULT_BATCH_SIZE: usize = 1;
+
+/// Writes the ranked items used when --number is not given, --batch-size of them per line.
+///
+/// Each item is a message on its own line by default.
+fn write_ranked_lines(
+    out: &mut impl Write,
+    ranked: impl IntoIterator<Item = FuzzyMatchedLineInfo>,
+    opts: &FilterOptions,
+) -> std::io::Result<()> {
+    let batch_size = opts.batch_size();
+    let to_msg = |item: FuzzyMatchedLineInfo| {
+        let id = if opts.ids {
+            Some(result_id(&item.0))
+        } else {
+            None
+        };
+        let (text, score, indices) = replace_item(item, &opts.replace);
+        let mut msg = serde_json::json!({ "text": text, "indices": indices });
+        if opts.with_score {
+            msg["score"] = serde_json::json!(score);
+        }
+        if opts.span {
+            msg["span"] = serde_json::json!(match_span(&indices));
+        }
+        if let Some(id) = id {
+            msg["id"] = serde_json::json!(id);
+        }
+        msg
+    };
+
+    if batch_size <= 1 {
+        for item in ranked {
+            writeln!(out, "{}", frame(to_msg(item)))?;
+        }
+        return Ok(());
+    }
+
+    let mut items = Vec::with_capacity(batch_size);
+    for item in ranked {
+        items.push(to_msg(item));
+        if items.len() == batch_size {
+            writeln!(out, "{}", frame(serde_json::json!({ "items": items })))?;
+            items.clear();
+        }
+    }
+    if !items.is_empty() {
+        writeln!(out, "{}", frame(serde_json::json!({ "items": items })))?;
+    }
+    Ok(())
+}
+
+/// Writes the terminator message if --end-marker is used, which must be the last one.
+fn try_write_end_marker(
+    out: &mut impl Write,
+    opts: &FilterOptions,
+    with_length: bool,
+) -> std::io::Result<()> {
+    if opts.end_marker {
+        let msg = serde_json::json!({ "type": "end" });
+        if with_length {
+            write_with_length(out, &msg)?;
+        } else {
+            writeln!(out, "{}", frame(msg))?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the warning if `source` is the stdin attached to a terminal, i.e., nothing is piped in,
+/// unless --read-tty-stdin is used.
+fn tty_stdin_warning<I: Iterator<Item = String>>(
+    source: &Source<I>,
+    opts: &FilterOptions,
+) -> Option<String> {
+    match source {
+        Source::Stdin if !opts.read_tty_stdin && std::io::stdin().is_terminal() => Some(
+            "stdin is a terminal, nothing to filter, use --input or pipe the candidates in".into(),
+        ),
+        _ => None,
+    }
+}
+
+/// Writes the empty results with the warning instead of reading the source.
+fn write_empty_results(
+    out: &mut impl Write,
+    number: Option<usize>,
+    warning: &str,
+    with_length: bool,
+    opts: &FilterOptions,
+) -> std::io::Result<()> {
+    let msg = if number.is_some() {
+        serde_json::json!({
+            "total": 0,
+            "lines": [],
+            "indices": [],
+            "source_empty": true,
+            "warning": warning,
+        })
+    } else {
+        serde_json::json!({ "source_empty": true, "warning": warning })
+    };
+    if with_length {
+        write_with_length(out, &msg)?;
+    } else {
+        writeln!(out, "{}", frame(msg))?;
+    }
+    try_write_end_marker(out, opts, with_length)
+}
+
+/// Returns the source reading the candidates from the Unix domain socket at `path`.
+#[cfg(unix)]
+pub fn socket_source<I: Iterator<Item = String>>(path: PathBuf) -> Result<Source<I>> {
+    Ok(Source::UnixSocket(path))
+}
+
+/// Returns the source reading the candidates from the Unix domain socket at `path`.
+#[cfg(not(unix))]
+pub fn socket_source<I: Iterator<Item = String>>(_path: PathBuf) -> Result<Source<I>> {
+    Err(anyhow::anyhow!(
+        "Reading from a Unix domain socket is only supported on unix"
+    ))
+}
+
+/// Returns the query given as is, or read from `query_file` or stdin.
+///
+/// The query is read before consuming the source, so stdin can't be used for both the query
+/// and the candidates, i.e., when `reads_stdin`.
+pub fn read_query(
+    query: Option<String>,
+    query_file: Option<&Path>,
+    query_stdin: bool,
+    reads_stdin: bool,
+) -> Result<String> {
+    match (query, query_file, query_stdin) {
+        (Some(query), None, false) => Ok(query),
+        (None, Some(path), false) => {
+            let query = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read the query from {}", path.display()))?;
+            Ok(strip_trailing_newline(&query).into())
+        }
+        (None, None, true) => {
+            if reads_stdin {
+                return Err(anyhow::anyhow!(
+                    "--query-stdin requires the candidates from --cmd, --input, --socket, --tags or --files"
+                ));
+            }
+            let mut query = String::new();
+            std::io::stdin()
+                .read_line(&mut query)
+                .context("Failed to read the query from stdin")?;
+            Ok(strip_trailing_newline(&query).into())
+        }
+        (None, None, false) => Err(anyhow::anyhow!(
+            "The query is required unless --query-file or --query-stdin is used"
+        )),
+        _ => Err(anyhow::anyhow!(
+            "Only one of the query, --query-file and --query-stdin can be used"
+        )),
+    }
+}
+
+/// Returns `s` without the trailing `\n` or `\r\n`.
+fn strip_trailing_newline(s: &str) -> &str {
+    let s = s.strip_suffix('\n').unwrap_or(s);
+    s.strip_suffix('\r').unwrap_or(s)
+}
+
+/// Replaces all the occurrences of `from` in `line` with `to`.
+///
+/// The indices are adjusted to the replaced line, the ones inside the replaced text are dropped.
+fn replace_with_indices(
+    line: &str,
+    indices: &[usize],
+    from: &str,
+    to: &str,
+) -> (String, Vec<usize>) {
+    if from.is_empty() || !line.contains(from) {
+        return (line.into(), indices.to_vec());
+    }
+    let mut replaced = String::with_capacity(line.len());
+    // New char index of each original char, `None` if it's replaced.
+    let mut new_index = Vec::new();
+    let mut len = 0;
+    let mut rest = line;
+    loop {
+        let (kept, found) = match rest.find(from) {
+            Some(pos) => (&rest[..pos], true),
+            None => (rest, false),
+        };
+        for c in kept.chars() {
+            replaced.push(c);
+            new_index.push(Some(len));
+            len += 1;
+        }
+        if !found {
+            break;
+        }
+        new_index.extend(from.chars().map(|_| None));
+        replaced.push_str(to);
+        len += to.chars().count();
+        rest = &rest[kept.len() + from.len()..];
+    }
+    let indices = indices
+        .iter()
+        .filter_map(|&i| new_index.get(i).copied().flatten())
+        .collect();
+    (replaced, indices)
+}
+
+/// Applies the replacements to the text of each item for displaying.
+fn replace_items<'a>(
+    items: impl IntoIterator<Item = FuzzyMatchedLineInfo> + 'a,
+    replacements: &'a [(String, String)],
+) -> impl Iterator<Item = FuzzyMatchedLineInfo> + 'a {
+    items
+        .into_iter()
+        .map(move |item| replace_item(item, replacements))
+}
+
+fn replace_item(
+    (text, score, indices): FuzzyMatchedLineInfo,
+    replacements: &[(String, String)],
+) -> FuzzyMatchedLineInfo {
+    let (text, indices) = replacements
+        .iter()
+        .fold((text, indices), |(text, indices), (from, to)| {
+            replace_with_indices(&text, &indices, from, to)
+        });
+    (text, score, indices)
+}
+
+/// Returns the `[start, end]` span of matched positions, `None` if nothing is matched.
+fn match_span(indices: &[usize]) -> Option<[usize; 2]> {
+    Some([*indices.iter().min()?, *indices.iter().max()?])
+}
+
+/// Returns the runs of consecutive positions in `indices` as `[start, len]` pairs.
+fn run_length_encode(indices: &[usize]) -> Vec<[usize; 2]> {
+    let mut runs: Vec<[usize; 2]> = Vec::new();
+    for &idx in indices {
+        match runs.last_mut() {
+            Some([start, len]) if *start + *len == idx => *len += 1,
+            _ => runs.push([idx, 1]),
+        }
+    }
+    runs
+}
+
+/// Returns the run-length encoded indices of all the items if they are more compact than
+/// the raw ones in total.
+fn compact_indices(indices: &[Vec<usize>]) -> Option<Vec<Vec<[usize; 2]>>> {
+    let runs = indices
+        .iter()
+        .map(|i| run_length_encode(i))
+        .collect::<Vec<_>>();
+    let raw_len: usize = indices.iter().map(Vec::len).sum();
+    let runs_len: usize = runs.iter().map(|r| r.len() * 2).sum();
+    if runs_len < raw_len {
+        Some(runs)
+    } else {
+        None
+    }
+}
+
+/// Replaces the indices of `msg` with the compact encoding if possible.
+fn try_compact_indices(msg: &mut serde_json::Value, indices: &[Vec<usize>]) {
+    if let Some(runs) = compact_indices(indices) {
+        msg["indices"] = serde_json::json!(runs);
+        msg["indices_format"] = serde_json::json!("rle");
+    }
+}
+
+/// Returns a unique path in the temp dir for the file of this process.
+fn tempfile_path(prefix: &str) -> Result<PathBuf> {
+    let mut tempfile = std::env::temp_dir();
+    tempfile.push(format!(
+        "{}_{}_{}",
+        prefix,
+        std::process::id(),
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)?
+            .as_nanos()
+    ));
+    Ok(tempfile)
+}
+
+/// Writes the text of all ranked items to a tempfile if the number of them exceeds `threshold`.
+///
+/// Returns the path of the tempfile if it's written.
+fn try_write_tempfile(
+    ranked: &[FuzzyMatchedLineInfo],
+    threshold: usize,
+) -> Result<Option<PathBuf>> {
+    if ranked.len() <= threshold {
+        return Ok(None);
+    }
+    let tempfile = tempfile_path("maple_filter")?;
+    let mut f = std::io::BufWriter::new(File::create(&tempfile)?);
+    for (text, _, _) in ranked {
+        writeln!(f, "{}", text)?;
+    }
+    f.flush()?;
+    Ok(Some(tempfile))
+}
+
+/// Appends the suffix rendered from `template` to `line`.
+///
+/// The placeholders `{score}` and `{matches}` are replaced with the filtering score and
+/// the number of matched chars respectively.
+fn decorate(mut line: String, template: &str, score: i64, indices: &[usize]) -> String {
+    line.push_str(
+        &template
+            .replace("{score}", &score.to_string())
+            .replace("{matches}", &indices.len().to_string()),
+    );
+    line
+}
+
+/// Returns the id of the result whose source text is `text`.
+///
+/// The id is the same for the same text across the runs and builds.
+fn result_id(text: &str) -> String {
+    format!("{:016x}", fnv1a(FNV_OFFSET_BASIS, text.as_bytes()))
+}
+
+/// Returns the ids of the items if `enable` is true, which must be called before the text of
+/// them is changed for display.
+fn result_ids(items: &[FuzzyMatchedLineInfo], enable: bool) -> Option<Vec<String>> {
+    if enable {
+        Some(items.iter().map(|(text, _, _)| result_id(text)).collect())
+    } else {
+        None
+    }
+}
+
+/// Returns the effective query of `matcher` emitted by --emit-query, the field and columns are
+/// 1-based as in the options.
+fn effective_query(matcher: &Matcher) -> serde_json::Value {
+    let query = matcher.effective_query();
+    serde_json::json!({
+        "text": query.text,
+        "algo": format!("{:?}", query.algo).to_lowercase(),
+        "respect_case": query.respect_case,
+        "ascii_fold": query.ascii_fold,
+        "whole_word": query.whole_word,
+        "match_field": query.match_field.map(|index| index + 1),
+        "match_columns": query.match_columns.map(|(start, end)| (start + 1, end)),
+    })
+}
+
+/// Returns the scores of the items if `enable` is true.
+fn result_scores(items: &[FuzzyMatchedLineInfo], enable: bool) -> Option<Vec<i64>> {
+    if enable {
+        Some(items.iter().map(|(_, score, _)| *score).collect())
+    } else {
+        None
+    }
+}
+
+/// Returns the text of the items if `enable` is true.
+fn full_lines(items: &[FuzzyMatchedLineInfo], enable: bool) -> Option<Vec<String>> {
+    if enable {
+        Some(items.iter().map(|(text, _, _)| text.clone()).collect())
+    } else {
+        None
+    }
+}
+
+/// Lines longer than this in bytes after the truncation are cut to it, e.g., the huge lines
+/// matched within the window, which are not truncated.
+const MAX_DISPLAY_LINE_LEN: usize = 4096;
+
+/// Truncates the lines to fit `winwidth` and then prepends the icons to the truncated ones.
+///
+/// The icon is never prepended before the truncation, otherwise the huge lines would be copied
+/// in full only to have the most of them dropped. The lines still longer than
+/// [`MAX_DISPLAY_LINE_LEN`] are cut and recorded in the truncated map as well.
+fn truncate_and_iconize(
+    items: impl IntoIterator<Item = FuzzyMatchedLineInfo>,
+    winwidth: usize,
+    enable_icon: bool,
+) -> (Vec<FuzzyMatchedLineInfo>, HashMap<String, String>) {
+    let (mut truncated_lines, mut truncated_map) =
+        truncate_long_matched_lines(items, winwidth, None);
+    for (text, _, indices) in truncated_lines.iter_mut() {
+        if text.len() > MAX_DISPLAY_LINE_LEN {
+            let mut end = MAX_DISPLAY_LINE_LEN;
+            while !text.is_char_boundary(end) {
+                end -= 1;
+            }
+            let cut = text[..end].to_string();
+            let uncut = std::mem::replace(text, cut);
+            // The line could have been truncated already, the original one is kept.
+            let original = truncated_map.remove(&uncut).unwrap_or(uncut);
+            truncated_map.insert(text.clone(), original);
+            let len = text.chars().count();
+            indices.retain(|&idx| idx < len);
+        }
+        if enable_icon {
+            *text = prepend_icon(text);
+        }
+    }
+    (truncated_lines, truncated_map)
+}
+
 /// Returns the info of the truncated top items ranked by the filtering score.
-fn process_top_items<T>(
+fn process_top_items(
     top_size: usize,
-    top_list: impl IntoIterator<Item = (String, T, Vec<usize>)>,
+    top_list: impl IntoIterator<Item = FuzzyMatchedLineInfo>,
     winwidth: usize,
     enable_icon: bool,
+    display_suffix: Option<&str>,
 ) -> (Vec<String>, Vec<Vec<usize>>, HashMap<String, String>) {
-    let (truncated_lines, truncated_map) = truncate_long_matched_lines(top_list, winwidth, None);
+    let (truncated_lines, truncated_map) = truncate_and_iconize(top_list, winwidth, enable_icon);
     let mut lines = Vec::with_capacity(top_size);
     let mut indices = Vec::with_capacity(top_size);
-    if enable_icon {
-        for (text, _, idxs) in truncated_lines {
-            lines.push(prepend_icon(&text));
-            indices.push(idxs);
-        }
-    } else {
-        for (text, _, idxs) in truncated_lines {
-            lines.push(text);
-            indices.push(idxs);
-        }
+    for (line, score, idxs) in truncated_lines {
+        // The suffix is appended after the visible text, the indices are unaffected.
+        let line = match display_suffix {
+            Some(template) => decorate(line, template, score, &idxs),
+            None => line,
+        };
+        lines.push(line);
+        indices.push(idxs);
     }
     (lines, indices, truncated_map)
 }
 
-pub fn run<I: Iterator<Item = String>>(
+/// Returns the results of filtering `source` with `query` in descending order of score, only the
+/// top `number` of them if `number` is given.
+///
+/// Nothing is printed, this is for using the filter as a library.
+pub fn rank<I: Iterator<Item = String>>(
     query: &str,
     source: Source<I>,
-    algo: Option<Algo>,
+    algo: Algo,
+    number: Option<usize>,
+) -> Result<Vec<FuzzyMatchedLineInfo>> {
+    let matcher = MatchOptions::default().matcher(algo, query);
+    let mut ranked = rank_with(&matcher, source, &FilterOptions::default())?;
+    if let Some(number) = number {
+        ranked.truncate(number);
+    }
+    Ok(ranked)
+}
+
+/// Returns all the results of filtering `source` with `matcher` ranked per `opts`.
+fn rank_with<I: Iterator<Item = String>>(
+    matcher: &Matcher,
+    source: Source<I>,
+    opts: &FilterOptions,
+) -> Result<Vec<FuzzyMatchedLineInfo>> {
+    let excluded_path = opts.excluded_path();
+    let scorer = |line: &str| {
+        if opts.is_excluded(line, excluded_path.as_ref()) {
+            None
+        } else {
+            matcher.match_line(line)
+        }
+    };
+    let mut ranked = source.filter_with(&scorer)?;
+
+    matcher.rank(&mut ranked);
+
+    Ok(ranked)
+}
+
+pub fn run<I: Iterator<Item = String>>(
+    matcher: &Matcher,
+    source: Source<I>,
     number: Option<usize>,
     enable_icon: bool,
     winwidth: Option<usize>,
+    opts: &FilterOptions,
 ) -> Result<()> {
-    let ranked = fuzzy_filter_and_rank(query, source, algo.unwrap_or(Algo::Fzy))?;
+    set_request_id(opts.request_id.clone());
+
+    if let Some(warning) = tty_stdin_warning(&source, opts) {
+        write_empty_results(&mut std::io::stdout(), number, &warning, false, opts)?;
+        return Ok(());
+    }
+
+    let ranked = rank_with(matcher, source, opts)?;
 
     if let Some(number) = number {
         let total = ranked.len();
+        let tempfile = match opts.output_threshold {
+            Some(threshold) => try_write_tempfile(&ranked, threshold)?,
+            None => None,
+        };
+        let top_items = ranked.into_iter().take(number).collect::<Vec<_>>();
+        let ids = result_ids(&top_items, opts.ids);
+        let scores = result_scores(&top_items, opts.with_score);
+        let top_items = replace_items(top_items, &opts.replace).collect::<Vec<_>>();
+        let full_lines = full_lines(&top_items, opts.full_lines);
         let (lines, indices, truncated_map) = process_top_items(
             number,
-            ranked.into_iter().take(number),
-            winwidth.unwrap_or(62),
+            top_items,
+            winwidth.unwrap_or(DEFAULT_WINWIDTH),
             enable_icon,
+            opts.display_suffix.as_deref(),
         );
-        if truncated_map.is_empty() {
-            println_json!(total, lines, indices);
-        } else {
-            println_json!(total, lines, indices, truncated_map);
+        let mut msg = serde_json::json!({ "total": total, "lines": lines, "indices": indices });
+        if !truncated_map.is_empty() {
+            msg["truncated_map"] = serde_json::json!(truncated_map);
+        }
+        if let Some(tempfile) = tempfile {
+            msg["tempfile"] = serde_json::json!(tempfile);
+        }
+        if let Some(full_lines) = full_lines {
+            msg["full_lines"] = serde_json::json!(full_lines);
+        }
+        if let Some(ids) = ids {
+            msg["ids"] = serde_json::json!(ids);
+        }
+        if let Some(scores) = scores {
+            msg["scores"] = serde_json::json!(scores);
+        }
+        if opts.span {
+            let spans = indices.iter().map(|i| match_span(i)).collect::<Vec<_>>();
+            msg["spans"] = serde_json::json!(spans);
+        }
+        if opts.compact_indices {
+            try_compact_indices(&mut msg, &indices);
+        }
+        if opts.emit_query {
+            msg["query"] = effective_query(matcher);
         }
+        println!("{}", frame(msg));
     } else {
-        for (text, _, indices) in ranked.iter() {
-            println_json!(text, indices);
+        write_ranked_lines(&mut std::io::stdout(), ranked, opts)?;
+        if opts.emit_query {
+            let query = effective_query(matcher);
+            println_frame!(query);
         }
     }
 
+    try_write_end_marker(&mut std::io::stdout(), opts, false)?;
+
     Ok(())
 }
 
@@ -74,16 +1015,505 @@ pub fn blines(
     winwidth: Option<usize>,
 ) -> Result<()> {
     crate::cmd::filter::dynamic::dyn_fuzzy_filter_and_rank(
-        query,
+        &MatchOptions::default().matcher(Algo::Fzy, query),
         Source::List(
             std::fs::read_to_string(&input)?
                 .lines()
                 .enumerate()
                 .map(|(idx, item)| format!("{} {}", idx + 1, item)),
         ),
-        None,
         number,
         false,
         winwidth,
+        &FilterOptions::default(),
     )
 }
+
+#[test]
+fn test_query_is_trimmed_by_default() {
+    let candidates = vec![
+        "src/main.rs".to_string(),
+        "crates/maple_cli/src/cmd/mod.rs".into(),
+        "README.md".into(),
+    ];
+    let matched = |matcher: Matcher| {
+        Source::from(candidates.clone())
+            .fuzzy_filter(&matcher)
+            .unwrap()
+            .into_iter()
+            .map(|(text, _, _)| text)
+            .collect::<Vec<_>>()
+    };
+
+    let opts = MatchOptions::default();
+    assert_eq!(
+        matched(opts.matcher(Algo::Fzy, "  main  ")),
+        matched(opts.matcher(Algo::Fzy, "main"))
+    );
+
+    let opts = MatchOptions {
+        keep_query_whitespace: true,
+        ..Default::default()
+    };
+    assert!(matched(opts.matcher(Algo::Fzy, "  main  ")).is_empty());
+}
+
+#[test]
+fn test_match_span() {
+    let matcher = Matcher::new(Algo::Fzy, "mod");
+    let (_, indices) = matcher
+        .match_line("crates/maple_cli/src/cmd/mod.rs")
+        .unwrap();
+    assert_eq!(
+        match_span(&indices),
+        Some([
+            *indices.iter().min().unwrap(),
+            *indices.iter().max().unwrap()
+        ])
+    );
+    assert_eq!(match_span(&[]), None);
+}
+
+#[test]
+fn test_read_query() {
+    let path = std::env::temp_dir().join(format!("maple_query_{}", std::process::id()));
+    std::fs::write(&path, "foo 'bar\" |baz\r\n").unwrap();
+    assert_eq!(
+        read_query(None, Some(&path), false, true).unwrap(),
+        "foo 'bar\" |baz"
+    );
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(
+        read_query(Some("foo".into()), None, false, true).unwrap(),
+        "foo"
+    );
+    assert!(read_query(None, None, false, false).is_err());
+    assert!(read_query(Some("foo".into()), Some(&path), false, false).is_err());
+    assert!(read_query(None, Some(&path), false, false).is_err());
+    // stdin can't be used for both the query and the candidates.
+    assert!(read_query(None, None, true, true).is_err());
+}
+
+#[test]
+fn test_effective_query() {
+    let opts = MatchOptions {
+        ascii_fold: true,
+        ..Default::default()
+    };
+    // No query syntax, the whole query is matched as is.
+    assert_eq!(
+        opts.matcher(Algo::Fzy, "  ^src !test main$ ").query(),
+        "^src !test main$"
+    );
+    assert_eq!(opts.matcher(Algo::Fzy, "café ").query(), "cafe");
+}
+
+#[test]
+fn test_replace_for_display() {
+    let matcher = Matcher::new(Algo::Fzy, "xrs");
+    let line = "/home/user/x.rs".to_string();
+    let (score, indices) = matcher.match_line(&line).unwrap();
+    assert_eq!(indices, vec![11, 13, 14]);
+
+    let replacements = vec![parse_replacement("/home/user=~").unwrap()];
+    let replaced = replace_items(vec![(line, score, indices)], &replacements).collect::<Vec<_>>();
+    assert_eq!(replaced, vec![("~/x.rs".to_string(), score, vec![2, 4, 5])]);
+
+    // The matched chars inside the replaced text are dropped.
+    assert_eq!(
+        replace_with_indices("a/b/a", &[0, 2, 4], "a", "xyz"),
+        ("xyz/b/xyz".to_string(), vec![4])
+    );
+    assert!(parse_replacement("no_separator").is_err());
+}
+
+#[test]
+fn test_full_lines_of_truncated_items() {
+    let line = format!("{}/needle.rs", "nested/".repeat(20));
+    let matcher = Matcher::new(Algo::Fzy, "needle");
+    let (score, indices) = matcher.match_line(&line).unwrap();
+    let top_items = vec![(line.clone(), score, indices)];
+
+    assert!(full_lines(&top_items, false).is_none());
+    let full_lines = full_lines(&top_items, true).unwrap();
+    let (lines, _, _) = process_top_items(1, top_items, 62, false, None);
+    assert!(lines[0].starts_with(fuzzy_filter::DOTS));
+    assert_eq!(full_lines, vec![line]);
+}
+
+#[test]
+fn test_end_marker_should_be_the_last_message() {
+    let ranked = vec![
+        ("foo".to_string(), 2, vec![0]),
+        ("bar".to_string(), 1, vec![1]),
+    ];
+    let opts = FilterOptions {
+        end_marker: true,
+        ..Default::default()
+    };
+
+    let mut out = Vec::new();
+    write_ranked_lines(&mut out, ranked, &opts).unwrap();
+    try_write_end_marker(&mut out, &opts, false).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let messages = out
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+        .collect::<Vec<_>>();
+    let end = serde_json::json!({ "type": "end" });
+    assert_eq!(messages.len(), 3);
+    assert_eq!(messages.iter().filter(|msg| **msg == end).count(), 1);
+    assert_eq!(messages.last(), Some(&end));
+
+    let mut out = Vec::new();
+    try_write_end_marker(&mut out, &opts, true).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "Content-length: 14\n\n{\"type\":\"end\"}\n"
+    );
+
+    let mut out = Vec::new();
+    try_write_end_marker(&mut out, &FilterOptions::default(), false).unwrap();
+    assert!(out.is_empty());
+
+    // Nothing to filter.
+    for number in [None, Some(10)] {
+        let mut out = Vec::new();
+        write_empty_results(&mut out, number, "empty", false, &opts).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let last = out.lines().last().unwrap();
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(last).unwrap(),
+            end
+        );
+    }
+}
+
+#[test]
+fn test_compact_indices() {
+    let dense = (5..25).collect::<Vec<_>>();
+    assert_eq!(run_length_encode(&dense), vec![[5, 20]]);
+    assert_eq!(run_length_encode(&[1, 2, 4]), vec![[1, 2], [4, 1]]);
+
+    let mut msg = serde_json::json!({ "indices": [dense.clone()] });
+    try_compact_indices(&mut msg, &[dense]);
+    assert_eq!(
+        msg,
+        serde_json::json!({ "indices": [[[5, 20]]], "indices_format": "rle" })
+    );
+
+    // The sparse matches are kept as is.
+    assert!(compact_indices(&[vec![1, 3, 5]]).is_none());
+}
+
+#[test]
+fn test_display_suffix_should_not_shift_indices() {
+    let matcher = Matcher::new(Algo::Fzy, "needle");
+    let ranked = vec![
+        "src/needle.rs".to_string(),
+        format!("{}/needle.rs", "very_long_directory_name".repeat(5)),
+    ]
+    .into_iter()
+    .filter_map(|line| {
+        matcher
+            .match_line(&line)
+            .map(|(score, indices)| (line, score, indices))
+    })
+    .collect::<Vec<_>>();
+
+    let (plain_lines, plain_indices, _) = process_top_items(2, ranked.clone(), 62, false, None);
+    let (lines, indices, _) = process_top_items(2, ranked, 62, false, Some(" [{matches}]"));
+
+    assert_eq!(indices, plain_indices);
+    for (line, plain_line) in lines.iter().zip(plain_lines.iter()) {
+        assert_eq!(line, &format!("{} [6]", plain_line));
+    }
+}
+
+#[test]
+fn test_write_ranked_results_to_tempfile() {
+    let ranked = (0..10)
+        .map(|i| (format!("line {}", i), 10 - i, vec![0]))
+        .collect::<Vec<_>>();
+
+    assert!(try_write_tempfile(&ranked, 10).unwrap().is_none());
+
+    let tempfile = try_write_tempfile(&ranked, 5).unwrap().unwrap();
+    let written = std::fs::read_to_string(&tempfile).unwrap();
+    assert_eq!(
+        written.lines().collect::<Vec<_>>(),
+        ranked.iter().map(|(text, _, _)| text).collect::<Vec<_>>()
+    );
+    std::fs::remove_file(tempfile).unwrap();
+}
+
+#[test]
+fn test_ranked_lines_in_batches() {
+    let ranked = (0..7)
+        .map(|i| (format!("line{}", i), 7 - i, vec![0]))
+        .collect::<Vec<_>>();
+    let messages = |batch_size| {
+        let opts = FilterOptions {
+            batch_size,
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        write_ranked_lines(&mut out, ranked.clone(), &opts).unwrap();
+        String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>()
+    };
+
+    let batches = messages(Some(3));
+    let sizes = batches
+        .iter()
+        .map(|msg| msg["items"].as_array().unwrap().len())
+        .collect::<Vec<_>>();
+    assert_eq!(sizes, vec![3, 3, 1]);
+    let texts = batches
+        .iter()
+        .flat_map(|msg| msg["items"].as_array().unwrap().clone())
+        .map(|item| item["text"].as_str().unwrap().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        texts,
+        ranked
+            .iter()
+            .map(|(text, _, _)| text.clone())
+            .collect::<Vec<_>>()
+    );
+
+    let unbatched = messages(None);
+    assert_eq!(unbatched.len(), 7);
+    assert_eq!(
+        unbatched[0],
+        serde_json::json!({ "text": "line0", "indices": [0] })
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_nth_field() {
+    let opts = MatchOptions::from_iter(&["filter", "--delimiter", ",", "--nth", "2"]);
+    assert_eq!(opts.match_field, Some(2));
+    let matcher = opts.matcher(Algo::Fzy, "rs");
+
+    // `rs` is only in the first field.
+    assert!(matcher.match_line("src/lib.rs,fn main").is_none());
+    assert_eq!(
+        matcher.match_line("main,src/lib.rs").unwrap().1,
+        vec![13, 14]
+    );
+    // Out of range.
+    assert!(matcher.match_line("src/lib.rs").is_none());
+}
+
+#[test]
+fn test_match_columns_of_exec_output() {
+    let ps_output = "  PID TTY          TIME CMD\n\
+                     \x20   1 ?        00:00:02 systemd\n\
+                     \x20 812 ?        00:00:00 sshd\n\
+                     \x20 950 pts/0    00:00:00 bash\n\
+                     \x201001 pts/0    00:00:00 vim\n";
+    let matched = |query: &str| {
+        let opts = MatchOptions {
+            match_columns: Some(parse_columns("25-").unwrap()),
+            ..Default::default()
+        };
+        let exec = crate::subprocess::Exec::cmd("printf").arg(ps_output);
+        Source::<std::iter::Empty<_>>::from(exec)
+            .fuzzy_filter(&opts.matcher(Algo::Fzy, query))
+            .unwrap()
+            .into_iter()
+            .map(|(text, _, indices)| (text, indices))
+            .collect::<Vec<_>>()
+    };
+
+    // `pts` is only in the TTY column.
+    assert!(matched("pts").is_empty());
+    assert_eq!(
+        matched("sh"),
+        vec![
+            ("  812 ?        00:00:00 sshd".to_string(), vec![25, 26]),
+            ("  950 pts/0    00:00:00 bash".to_string(), vec![26, 27]),
+        ]
+    );
+
+    assert_eq!(parse_columns("3-7").unwrap(), (3, Some(7)));
+    assert!(parse_columns("0-").is_err());
+    assert!(parse_columns("7-3").is_err());
+}
+
+#[test]
+fn test_truncate_before_iconize() {
+    let huge_line = format!("{}/needle.rs", "a".repeat(100_000));
+    let indices = (100_001..100_007).collect::<Vec<_>>();
+    let items = vec![
+        (huge_line.clone(), 1, indices),
+        (huge_line.clone(), 1, vec![]),
+    ];
+
+    let (truncated, _) = truncate_long_matched_lines(items.clone(), 62, None);
+    let (iconized, truncated_map) = truncate_and_iconize(items, 62, true);
+    assert_eq!(iconized[0].0, prepend_icon(&truncated[0].0));
+    assert_eq!(iconized[0].2, truncated[0].2);
+    assert!(iconized[0].0.len() < 2 * 62);
+    assert_eq!(truncated_map.get(&truncated[0].0), Some(&huge_line));
+
+    // Not truncated without the matched indices, but cut for display and still iconized.
+    let cut = &huge_line[..MAX_DISPLAY_LINE_LEN];
+    assert_eq!(iconized[1].0, prepend_icon(cut));
+    assert_eq!(truncated_map.get(cut), Some(&huge_line));
+
+    // Matched within the window, the indices beyond the cut are dropped.
+    let huge_line = format!("needle{}", "é".repeat(10_000));
+    let items = vec![(huge_line.clone(), 1, vec![0, 1, 2, 3_000])];
+    let (iconized, truncated_map) = truncate_and_iconize(items, 5_000, false);
+    let cut = &huge_line[..MAX_DISPLAY_LINE_LEN];
+    assert_eq!(iconized[0].0, cut);
+    assert_eq!(iconized[0].2, vec![0, 1, 2]);
+    assert_eq!(truncated_map.get(cut), Some(&huge_line));
+}
+
+#[test]
+fn test_result_id_is_independent_of_display() {
+    let line = format!("{}/needle.rs", "nested/".repeat(20));
+    let top_items = |query: &str, winwidth: usize| {
+        let (score, indices) = Matcher::new(Algo::Fzy, query).match_line(&line).unwrap();
+        let top_items = vec![(line.clone(), score, indices)];
+        let ids = result_ids(&top_items, true).unwrap();
+        let (lines, _, _) = process_top_items(1, top_items, winwidth, true, None);
+        (lines, ids)
+    };
+
+    let (lines1, ids1) = top_items("needle", 62);
+    let (lines2, ids2) = top_items("nested", 40);
+    assert_ne!(lines1, lines2);
+    assert_eq!(ids1, ids2);
+    assert_ne!(ids1[0], result_id("nested/needle.rs"));
+    assert!(result_ids(&[], false).is_none());
+    // Stable across the builds.
+    assert_eq!(result_id("a"), "af63dc4c8601ec8c");
+}
+
+#[test]
+fn test_tty_stdin_yields_empty_results() {
+    let opts = FilterOptions::default();
+    assert_eq!(
+        tty_stdin_warning(&Source::<std::iter::Empty<_>>::Stdin, &opts).is_some(),
+        std::io::stdin().is_terminal()
+    );
+    let read_anyway = FilterOptions {
+        read_tty_stdin: true,
+        ..Default::default()
+    };
+    assert!(tty_stdin_warning(&Source::<std::iter::Empty<_>>::Stdin, &read_anyway).is_none());
+    assert!(tty_stdin_warning(&Source::from(vec!["foo".to_string()]), &opts).is_none());
+
+    let mut out = Vec::new();
+    write_empty_results(&mut out, Some(10), "stdin is a terminal", false, &opts).unwrap();
+    let msg: serde_json::Value = serde_json::from_slice(&out).unwrap();
+    assert_eq!(msg["total"], 0);
+    assert_eq!(msg["lines"], serde_json::json!([]));
+    assert_eq!(msg["warning"], "stdin is a terminal");
+}
+
+#[test]
+fn test_rank_without_printing() {
+    let source = Source::from(vec![
+        "src/lib.rs".to_string(),
+        "README.md".into(),
+        "src/cmd/filter/mod.rs".into(),
+        "src/light_command.rs".into(),
+    ]);
+    let ranked = rank("srs", source, Algo::Fzy, Some(2)).unwrap();
+    assert_eq!(ranked.len(), 2);
+    assert_eq!(ranked[0].0, "src/lib.rs");
+    assert!(ranked[0].1 >= ranked[1].1);
+
+    let source = Source::<std::iter::Empty<_>>::Bytes(b"README.md\nsrc/lib.rs\n".to_vec());
+    let ranked = rank("md", source, Algo::Fzy, None).unwrap();
+    assert_eq!(ranked.len(), 1);
+    assert_eq!(
+        (ranked[0].0.as_str(), &ranked[0].2),
+        ("README.md", &vec![7, 8])
+    );
+}
+
+#[test]
+fn test_msgpack_output_format() {
+    let msg = serde_json::json!({ "total": 1, "lines": ["foo"], "indices": [[0]] });
+
+    set_output_format(Some(OutputFormat::MsgPack));
+    let mut out = Vec::new();
+    write_with_length(&mut out, &msg).unwrap();
+    set_output_format(None);
+
+    let header_end = out.windows(2).position(|w| w == b"\n\n").unwrap() + 2;
+    let header = String::from_utf8_lossy(&out[..header_end]).to_string();
+    let body = &out[header_end..out.len() - 1];
+    assert_eq!(header, format!("Content-length: {}\n\n", body.len()));
+    let decoded: serde_json::Value = rmp_serde::from_slice(body).unwrap();
+    assert_eq!(decoded, msg);
+
+    // JSON is still the default.
+    let mut out = Vec::new();
+    write_with_length(&mut out, &msg).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        format!("{}\n", encode_with_length(&msg).unwrap())
+    );
+}
+
+#[test]
+fn test_with_score() {
+    let ranked = vec![
+        ("foo".to_string(), 42, vec![0]),
+        ("bar".to_string(), -1, vec![1]),
+    ];
+    assert_eq!(result_scores(&ranked, true), Some(vec![42, -1]));
+    assert!(result_scores(&ranked, false).is_none());
+
+    let opts = FilterOptions {
+        with_score: true,
+        ..Default::default()
+    };
+    let mut out = Vec::new();
+    write_ranked_lines(&mut out, ranked, &opts).unwrap();
+    let scores = String::from_utf8(out)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["score"].clone())
+        .collect::<Vec<_>>();
+    assert_eq!(scores, vec![42, -1]);
+}
+
+#[test]
+fn test_exclude_substrings() {
+    let opts = FilterOptions::from_iter(&[
+        "filter",
+        "--exclude",
+        "node_modules",
+        "--exclude",
+        "target/",
+    ]);
+    assert_eq!(opts.excludes, vec!["node_modules", "target/"]);
+
+    let source: Source<std::vec::IntoIter<String>> = vec![
+        "src/main.rs".to_string(),
+        "node_modules/main.js".to_string(),
+        "target/main.d".to_string(),
+    ]
+    .into();
+    let ranked = rank_with(&Matcher::new(Algo::Fzy, "main"), source, &opts).unwrap();
+    assert_eq!(
+        ranked
+            .into_iter()
+            .map(|(text, _, _)| text)
+            .collect::<Vec<_>>(),
+        vec!["src/main.rs"]
+    );
+}
@@ -1,37 +1,413 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::time::{Duration, Instant, SystemTime};
 
 use anyhow::Result;
+use fuzzy_filter::{DEFAULT_WINWIDTH, DOTS};
+use icon::{prepend_grep_icon, prepend_icon};
+use lazy_static::lazy_static;
+use regex::{Regex, RegexBuilder};
+use serde::Serialize;
+use structopt::StructOpt;
 
-use crate::light_command::{set_current_dir, LightCommand};
+use crate::cmd::filter::dynamic::{write_json_with_length, UPDATE_INTERVAL};
+use crate::light_command::{set_current_dir, CacheOptions, LightCommand};
+use crate::utils::{resolve_base_dir, ExcludedPath};
 
-fn prepare_grep_and_args(cmd_str: &str, cmd_dir: Option<PathBuf>) -> (Command, Vec<&str>) {
+#[derive(StructOpt, Debug, Default)]
+pub struct GrepOptions {
+    /// Delegate to -g option of rg, can be repeated to include and exclude files, e.g.,
+    /// `-g '*.rs' -g '!*_test.rs'`, the later one wins as rg does.
+    #[structopt(short = "g", long = "glob", number_of_values = 1)]
+    pub globs: Vec<String>,
+
+    /// Specify the working directory of CMD
+    #[structopt(long = "cmd-dir", parse(from_os_str))]
+    pub cmd_dir: Option<PathBuf>,
+
+    /// Maximum number of grep results kept in memory when --number is used, default to NUM.
+    #[structopt(long = "max-buffered")]
+    pub max_buffered: Option<usize>,
+
+    /// Exclude the grep lines in this file, valid only when --number is used.
+    #[structopt(long = "exclude-path", parse(from_os_str))]
+    pub exclude_path: Option<PathBuf>,
+
+    /// Keep only the first match of each line when it's matched at multiple columns,
+    /// valid only when --number is used.
+    #[structopt(long = "dedup-lines")]
+    pub dedup_lines: bool,
+
+    /// Show the matches in the recently modified files first, valid only when --number is used.
+    #[structopt(long = "sort-by-mtime")]
+    pub sort_by_mtime: bool,
+
+    /// Emit one line per file with the number of matches in `counts` instead of each match,
+    /// valid only when --number is used.
+    #[structopt(long = "files-with-matches")]
+    pub files_with_matches: bool,
+
+    /// Emit the indices of text matching GREP_QUERY in each line and the `segments` of the line,
+    /// valid only when --number is used.
+    #[structopt(long = "highlight")]
+    pub highlight: bool,
+
+    /// Send the first NUM matched lines periodically while grep is running, valid only when
+    /// --number is used and ignored with --files-with-matches. All the messages are then
+    /// prefixed with the Content-length like the dynamic filter.
+    #[structopt(long = "stream")]
+    pub stream: bool,
+
+    /// Drop the matches starting after this 1-based column, e.g., in the long comment tails,
+    /// they are not counted in the total either.
+    #[structopt(long = "max-column")]
+    pub max_column: Option<usize>,
+
+    /// Kill the grep command if it's not complete in this many milliseconds, the matches so far
+    /// are displayed with `timed_out` in the result.
+    #[structopt(long = "timeout")]
+    pub timeout: Option<u64>,
+
+    #[structopt(flatten)]
+    pub cache_opts: CacheOptions,
+}
+
+lazy_static! {
+    /// Matches the `path:lnum:col:` part of grep line.
+    ///
+    /// The path is up to the first `:lnum:col:`, so that it could contain the colons, e.g., the
+    /// drive letter on Windows, and the text after it could contain `:lnum:col:` as well.
+    static ref GREP_PREFIX_RE: Regex = Regex::new(r"^(.*?):(\d+):(\d+):").unwrap();
+}
+
+#[cfg(test)]
+thread_local! {
+    static QUERY_COMPILED: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Highlights the text matching the grep query in the grep lines.
+///
+/// The query is compiled into regex once on creation instead of per line.
+struct QueryHighlighter {
+    re: Regex,
+}
+
+impl QueryHighlighter {
+    /// The query is matched case insensitively unless it contains an uppercase char,
+    /// and as a literal string if it's not a valid regex.
+    fn new(query: &str) -> Self {
+        #[cfg(test)]
+        QUERY_COMPILED.with(|compiled| compiled.set(compiled.get() + 1));
+
+        let case_insensitive = !query.chars().any(char::is_uppercase);
+        let re = RegexBuilder::new(query)
+            .case_insensitive(case_insensitive)
+            .build()
+            .or_else(|_| {
+                RegexBuilder::new(&regex::escape(query))
+                    .case_insensitive(case_insensitive)
+                    .build()
+            })
+            .expect("escaped query is a valid regex; qed");
+        Self { re }
+    }
+
+    /// Returns the char indices of the matched text in `line`, the `path:lnum:col:` part is skipped.
+    fn indices(&self, line: &str) -> Vec<usize> {
+        let text_start = GREP_PREFIX_RE.find(line).map_or(0, |m| m.end());
+        let text = &line[text_start..];
+        let offset = line[..text_start].chars().count();
+        let mut indices = Vec::new();
+        for m in self.re.find_iter(text) {
+            let start = offset + text[..m.start()].chars().count();
+            indices.extend(start..start + m.as_str().chars().count());
+        }
+        indices
+    }
+}
+
+/// Byte ranges `[start, end)` of the parts of grep line, the icon is not included.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+struct GrepSegments {
+    path: [usize; 2],
+    lnum: [usize; 2],
+    text: [usize; 2],
+}
+
+impl GrepSegments {
+    /// Returns None if `line` is not in the format of `path:lnum:col:text`.
+    fn new(line: &str) -> Option<Self> {
+        let cap = GREP_PREFIX_RE.captures(line)?;
+        let range = |idx: usize| cap.get(idx).map(|m| [m.start(), m.end()]);
+        Some(Self {
+            path: range(1)?,
+            lnum: range(2)?,
+            text: [cap.get(0)?.end(), line.len()],
+        })
+    }
+}
+
+/// Returns the text of grep line, i.e., the part after `path:lnum:col:`.
+fn grep_text(line: &str) -> &str {
+    GREP_PREFIX_RE.find(line).map_or(line, |m| &line[m.end()..])
+}
+
+/// Returns the column of the match in the grep `line`.
+fn grep_column(line: &str) -> Option<usize> {
+    GREP_PREFIX_RE
+        .captures(line)
+        .and_then(|cap| cap.get(3))
+        .and_then(|col| col.as_str().parse().ok())
+}
+
+/// Returns the path of the file in the grep `line`.
+fn grep_path(line: &str) -> Option<&str> {
+    GREP_PREFIX_RE
+        .captures(line)
+        .and_then(|cap| cap.get(1))
+        .map(|path| path.as_str())
+}
+
+/// Returns the first `number` of the buffered grep `lines` sent while grep is still running.
+fn partial_grep_lines(lines: &[String], number: usize, enable_icon: bool) -> Vec<String> {
+    lines
+        .iter()
+        .take(number)
+        .map(|line| {
+            if enable_icon {
+                prepend_grep_icon(line)
+            } else {
+                line.clone()
+            }
+        })
+        .collect()
+}
+
+/// Returns the `path:lnum` part of the grep `line`, which is the same for the matches at
+/// multiple columns of a line.
+fn grep_location(line: &str) -> Option<&str> {
+    GREP_PREFIX_RE
+        .captures(line)
+        .and_then(|cap| cap.get(2))
+        .map(|lnum| &line[..lnum.end()])
+}
+
+/// Returns the modification time of `path` relative to `base_dir`.
+fn file_mtime(base_dir: &Path, path: &str) -> Option<SystemTime> {
+    std::fs::metadata(base_dir.join(path))
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Sort key of grep line, the line in the more recently modified file and then the earlier
+/// one comes first.
+type RecencyKey = (Reverse<Option<SystemTime>>, usize);
+
+/// Keeps the top `number` grep lines ordered by the modification time of file, the most
+/// recent first, while streaming the output of grep.
+///
+/// The order of lines in the same file is kept, the files failed to be stat'd sort last.
+struct RecentGrepLines<'a> {
+    base_dir: &'a Path,
+    number: usize,
+    mtimes: HashMap<String, Option<SystemTime>>,
+    /// Max-heap of the top lines, the least recent one is on the top to be replaced.
+    top: BinaryHeap<(RecencyKey, String)>,
+    pushed: usize,
+}
+
+impl<'a> RecentGrepLines<'a> {
+    fn new(base_dir: &'a Path, number: usize) -> Self {
+        Self {
+            base_dir,
+            number,
+            mtimes: HashMap::new(),
+            top: BinaryHeap::with_capacity(number + 1),
+            pushed: 0,
+        }
+    }
+
+    fn push(&mut self, line: &str) {
+        let base_dir = self.base_dir;
+        let mtime = grep_path(line).and_then(|path| {
+            *self
+                .mtimes
+                .entry(path.into())
+                .or_insert_with_key(|path| file_mtime(base_dir, path))
+        });
+        let key = (Reverse(mtime), self.pushed);
+        self.pushed += 1;
+        if self.top.len() == self.number && self.top.peek().is_some_and(|(top, _)| *top < key) {
+            return;
+        }
+        self.top.push((key, line.into()));
+        if self.top.len() > self.number {
+            self.top.pop();
+        }
+    }
+
+    /// Returns the top lines, the most recent first.
+    fn lines(&self) -> Vec<String> {
+        let mut top = self.top.iter().collect::<Vec<_>>();
+        top.sort_unstable();
+        top.into_iter().map(|(_, line)| line.clone()).collect()
+    }
+}
+
+/// Counts the matches in each file of the grep lines while streaming the output of grep.
+#[derive(Debug, Default)]
+struct GrepFileCounts {
+    /// Distinct files and the number of matches in each of them, in the order of their first
+    /// appearance.
+    files: Vec<(String, usize)>,
+    positions: HashMap<String, usize>,
+}
+
+impl GrepFileCounts {
+    fn push(&mut self, line: &str) {
+        if let Some(path) = grep_path(line) {
+            match self.positions.get(path) {
+                Some(&idx) => self.files[idx].1 += 1,
+                None => {
+                    self.positions.insert(path.into(), self.files.len());
+                    self.files.push((path.into(), 1));
+                }
+            }
+        }
+    }
+}
+
+/// Truncates the grep lines longer than `winwidth` so that the matched text stays visible.
+///
+/// The `path:lnum:col:` part is kept so that the line can still be parsed, the text
+/// before the match column is replaced with `DOTS` when the match is out of the window, and
+/// the text after the end of the window is dropped. The window is measured in chars, while the
+/// column of grep is in bytes.
+///
+/// Returns the lines and a map of the index of each truncated line to the number of bytes
+/// removed from it, `DOTS` excluded, so that the client can shift the highlight column.
+fn truncate_long_matched_grep_lines(
+    lines: Vec<String>,
+    winwidth: usize,
+) -> (Vec<String>, HashMap<usize, usize>) {
+    let mut truncated_map = HashMap::new();
+    let lines = lines
+        .into_iter()
+        .enumerate()
+        .map(|(idx, line)| {
+            if line.chars().count() <= winwidth {
+                return line;
+            }
+            let (prefix_len, col) = match GREP_PREFIX_RE.captures(&line).and_then(|cap| {
+                let col = cap.get(3)?.as_str().parse::<usize>().ok()?;
+                Some((cap.get(0)?.end(), col))
+            }) {
+                Some(info) => info,
+                None => return line,
+            };
+            let match_start = prefix_len + col.saturating_sub(1);
+            let (prefix_width, match_start_width) = match line.get(..match_start) {
+                Some(head) => (line[..prefix_len].chars().count(), head.chars().count()),
+                None => return line,
+            };
+            // Nothing to truncate if the match fits into the window or is right after the prefix.
+            if match_start_width + DOTS.len() < winwidth
+                || match_start_width < prefix_width + DOTS.len()
+            {
+                return line;
+            }
+            // No room for the match if the prefix alone fills the window.
+            let tail_width = match winwidth.checked_sub(prefix_width + DOTS.len()) {
+                Some(tail_width) if tail_width > 0 => tail_width,
+                _ => return line,
+            };
+            let matched = &line[match_start..];
+            let tail_end = matched
+                .char_indices()
+                .nth(tail_width)
+                .map_or(matched.len(), |(i, _)| i);
+            truncated_map.insert(idx, match_start - prefix_len - DOTS.len());
+            format!("{}{}{}", &line[..prefix_len], DOTS, &matched[..tail_end])
+        })
+        .collect();
+    (lines, truncated_map)
+}
+
+fn prepare_grep_and_args(
+    cmd_str: &str,
+    cmd_dir: Option<PathBuf>,
+) -> (Command, Vec<&str>, Option<String>) {
     let args = cmd_str.split_whitespace().collect::<Vec<&str>>();
 
     let mut cmd = Command::new(args[0]);
 
-    set_current_dir(&mut cmd, cmd_dir);
+    let warning = set_current_dir(&mut cmd, cmd_dir);
 
-    (cmd, args)
+    (cmd, args, warning)
 }
 
+/// Pushes the `-g` options of `globs`, which are part of the cache key as well.
+///
+/// The order is kept for the later glob to win if there are both the including and excluding
+/// ones, otherwise it doesn't matter and they are sorted, so that the equivalent commands share
+/// the same cache.
+fn push_globs<'a>(args: &mut Vec<&'a str>, globs: &'a [String]) {
+    let mut globs = globs.iter().map(|g| g.as_str()).collect::<Vec<_>>();
+    let excluding = globs.iter().filter(|g| g.starts_with('!')).count();
+    if excluding == 0 || excluding == globs.len() {
+        globs.sort_unstable();
+    }
+    for g in globs {
+        args.push("-g");
+        args.push(g);
+    }
+}
+
+/// Runs the grep command with the first query of `grep_queries`.
+///
+/// Since ripgrep can't AND the patterns, the rest queries are required as the substrings of the
+/// text of grep lines, which are filtered while streaming the output of grep.
 pub fn run(
     grep_cmd: String,
-    grep_query: &str,
-    glob: Option<&str>,
-    cmd_dir: Option<PathBuf>,
+    grep_queries: &[String],
+    opts: GrepOptions,
     number: Option<usize>,
     enable_icon: bool,
+    winwidth: Option<usize>,
 ) -> Result<()> {
-    let (mut cmd, mut args) = prepare_grep_and_args(&grep_cmd, cmd_dir);
+    let GrepOptions {
+        globs,
+        cmd_dir,
+        max_buffered,
+        exclude_path,
+        dedup_lines,
+        sort_by_mtime,
+        files_with_matches,
+        highlight,
+        stream,
+        max_column,
+        timeout,
+        cache_opts,
+    } = opts;
+
+    // The excluded path is not part of the cache key, so the whole output is left as is.
+    let excluded = exclude_path
+        .as_ref()
+        .filter(|_| number.is_some())
+        .and_then(|path| ExcludedPath::new(path, cmd_dir.as_deref()));
+    let base_dir = resolve_base_dir(cmd_dir.as_deref());
+
+    let (mut cmd, mut args, warning) = prepare_grep_and_args(&grep_cmd, cmd_dir);
 
     // We split out the grep opts and query in case of the possible escape issue of clap.
+    let (grep_query, required_queries) = grep_queries
+        .split_first()
+        .ok_or_else(|| anyhow::anyhow!("GREP_QUERY is required"))?;
     args.push(grep_query);
 
-    if let Some(g) = glob {
-        args.push("-g");
-        args.push(g);
-    }
+    push_globs(&mut args, &globs);
 
     // currently vim-clap only supports rg.
     // Ref https://github.com/liuchengxu/vim-clap/pull/60
@@ -41,9 +417,152 @@ pub fn run(
 
     cmd.args(&args[1..]);
 
-    let mut light_cmd = LightCommand::new_grep(&mut cmd, number, enable_icon);
+    // The rest queries are part of the cache key as well.
+    args.extend(required_queries.iter().map(|q| q.as_str()));
 
-    light_cmd.execute(&args)?;
+    let mut light_cmd = LightCommand::new_grep(&mut cmd, number, enable_icon)
+        .cache_opts(&cache_opts)
+        .warning(warning.clone())
+        .require_all(required_queries.to_vec(), grep_text)
+        .max_column(max_column, grep_column)
+        .exclude_path(excluded, grep_path)
+        // Same as the excluded path, the whole output is not deduplicated.
+        .dedup_by(dedup_lines && number.is_some(), grep_location)
+        .timeout(timeout.map(Duration::from_millis));
+
+    // Only the top items are needed, no need to buffer the whole output of grep.
+    if let Some(number) = number {
+        let max_buffered = max_buffered.unwrap_or(number);
+        let stream = stream && !files_with_matches;
+        let mut last_update = Instant::now();
+        // The top lines are not necessarily the first ones of the output, so they are kept aside.
+        let mut recent = base_dir
+            .as_deref()
+            .filter(|_| sort_by_mtime && !files_with_matches)
+            .map(|base_dir| RecentGrepLines::new(base_dir, number));
+        let mut file_counts = if files_with_matches {
+            Some(GrepFileCounts::default())
+        } else {
+            None
+        };
+        let (total, mut lines) = light_cmd.execute_and_gather_output(
+            std::cmp::max(number, max_buffered),
+            max_buffered,
+            |total, line, lines| {
+                if let Some(recent) = recent.as_mut() {
+                    recent.push(line);
+                }
+                if let Some(file_counts) = file_counts.as_mut() {
+                    file_counts.push(line);
+                }
+                if stream && last_update.elapsed() > UPDATE_INTERVAL {
+                    last_update = Instant::now();
+                    let lines = match &recent {
+                        Some(recent) => partial_grep_lines(&recent.lines(), number, enable_icon),
+                        None => partial_grep_lines(lines, number, enable_icon),
+                    };
+                    write_json_with_length(&serde_json::json!({ "total": total, "lines": lines }));
+                }
+            },
+        )?;
+        if let Some(recent) = recent {
+            lines = recent.lines();
+        }
+
+        if let Some(GrepFileCounts { mut files, .. }) = file_counts {
+            if sort_by_mtime {
+                if let Some(base_dir) = base_dir {
+                    files.sort_by_cached_key(|(path, _)| Reverse(file_mtime(&base_dir, path)));
+                }
+            }
+            let total = files.len();
+            let (lines, counts): (Vec<_>, Vec<_>) = files
+                .into_iter()
+                .take(number)
+                .map(|(path, count)| {
+                    let path = if enable_icon {
+                        prepend_icon(&path)
+                    } else {
+                        path
+                    };
+                    (path, count)
+                })
+                .unzip();
+            let mut msg = serde_json::json!({ "total": total, "lines": lines, "counts": counts });
+            if let Some(warning) = warning {
+                msg["warning"] = serde_json::json!(warning);
+            }
+            if light_cmd.timed_out() {
+                msg["timed_out"] = serde_json::json!(true);
+            }
+            println!("{}", msg);
+            return Ok(());
+        }
+
+        lines.truncate(number);
+        let (lines, truncated_map) =
+            truncate_long_matched_grep_lines(lines, winwidth.unwrap_or(DEFAULT_WINWIDTH));
+        let segments = if highlight {
+            Some(
+                lines
+                    .iter()
+                    .map(|line| GrepSegments::new(line))
+                    .collect::<Vec<_>>(),
+            )
+        } else {
+            None
+        };
+        let indices = if highlight {
+            let highlighters = grep_queries
+                .iter()
+                .map(|query| QueryHighlighter::new(query))
+                .collect::<Vec<_>>();
+            Some(
+                lines
+                    .iter()
+                    .map(|line| {
+                        let mut indices = highlighters
+                            .iter()
+                            .flat_map(|highlighter| highlighter.indices(line))
+                            .collect::<Vec<_>>();
+                        indices.sort_unstable();
+                        indices.dedup();
+                        indices
+                    })
+                    .collect::<Vec<_>>(),
+            )
+        } else {
+            None
+        };
+        let lines = if enable_icon {
+            lines.iter().map(|line| prepend_grep_icon(line)).collect()
+        } else {
+            lines
+        };
+        let mut msg = serde_json::json!({ "total": total, "lines": lines });
+        if let Some(indices) = indices {
+            msg["indices"] = serde_json::json!(indices);
+        }
+        if let Some(segments) = segments {
+            msg["segments"] = serde_json::json!(segments);
+        }
+        if !truncated_map.is_empty() {
+            msg["truncated_map"] = serde_json::json!(truncated_map);
+        }
+        if let Some(warning) = warning {
+            msg["warning"] = serde_json::json!(warning);
+        }
+        if light_cmd.timed_out() {
+            msg["timed_out"] = serde_json::json!(true);
+        }
+        if stream {
+            write_json_with_length(&msg);
+        } else {
+            println!("{}", msg);
+        }
+    } else {
+        light_cmd.execute(&args)?;
+    }
 
     Ok(())
 }
@@ -82,15 +601,264 @@ pub fn run_forerunner(
         }
     }
 
-    set_current_dir(&mut cmd, cmd_dir);
+    let warning = set_current_dir(&mut cmd, cmd_dir);
 
-    let mut light_cmd = LightCommand::new_grep(&mut cmd, number, enable_icon);
+    let mut light_cmd = LightCommand::new_grep(&mut cmd, number, enable_icon).warning(warning);
 
     light_cmd.execute(&args)?;
 
     Ok(())
 }
 
+#[test]
+fn test_truncate_long_matched_grep_lines() {
+    let line = format!("src/lib.rs:10:81:{}needle", " ".repeat(80));
+
+    let (truncated, truncated_map) = truncate_long_matched_grep_lines(vec![line.clone()], 50);
+    assert_eq!(truncated, vec![format!("src/lib.rs:10:81:{}needle", DOTS)]);
+    let needle_col = |line: &str| line.find("needle").unwrap();
+    assert_eq!(
+        needle_col(&line) - truncated_map[&0],
+        needle_col(&truncated[0])
+    );
+
+    // The line fits into a wider window.
+    let (truncated, truncated_map) = truncate_long_matched_grep_lines(vec![line.clone()], 120);
+    assert_eq!(truncated, vec![line]);
+    assert!(truncated_map.is_empty());
+
+    // The window is in chars, the column is in bytes, and the tail is cut at the window.
+    let line = format!(
+        "src/lib.rs:10:61:{}needle{}",
+        "é".repeat(30),
+        "x".repeat(100)
+    );
+    let (truncated, truncated_map) = truncate_long_matched_grep_lines(vec![line.clone()], 50);
+    assert_eq!(
+        truncated,
+        vec![format!("src/lib.rs:10:61:{}needle{}", DOTS, "x".repeat(24))]
+    );
+    assert_eq!(truncated[0].chars().count(), 50);
+    assert_eq!(
+        needle_col(&line) - truncated_map[&0],
+        needle_col(&truncated[0])
+    );
+
+    let line = format!("src/lib.rs:1:41:{}needle", "é".repeat(20));
+    let (truncated, truncated_map) = truncate_long_matched_grep_lines(vec![line.clone()], 50);
+    assert_eq!(truncated, vec![line]);
+    assert!(truncated_map.is_empty());
+}
+
+#[test]
+fn test_globs_keep_order() {
+    let opts = GrepOptions::from_iter(&["grep", "-g", "*.rs", "--glob", "!*_test.rs"]);
+    assert_eq!(opts.globs, vec!["*.rs", "!*_test.rs"]);
+    assert!(GrepOptions::from_iter(&["grep"]).globs.is_empty());
+}
+
+#[test]
+fn test_equivalent_globs_share_cache() {
+    let args = |globs: &[&str]| {
+        let globs = globs.iter().map(|g| g.to_string()).collect::<Vec<_>>();
+        let mut args = vec!["rg", "--vimgrep", "query"];
+        push_globs(&mut args, &globs);
+        args.into_iter().map(String::from).collect::<Vec<_>>()
+    };
+
+    assert_eq!(args(&["*.rs", "*.toml"]), args(&["*.toml", "*.rs"]));
+    assert_eq!(args(&["!*.md", "!target/*"]), args(&["!target/*", "!*.md"]));
+    assert_eq!(
+        args(&["*.toml", "*.rs"]),
+        vec!["rg", "--vimgrep", "query", "-g", "*.rs", "-g", "*.toml"]
+    );
+    // The later one wins.
+    assert_ne!(args(&["*.rs", "!*_test.rs"]), args(&["!*_test.rs", "*.rs"]));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_max_column() {
+    assert_eq!(grep_column("src/lib.rs:10:42:let x = 1;"), Some(42));
+    assert_eq!(grep_column("no prefix"), None);
+
+    let mut cmd = Command::new("printf");
+    cmd.arg("a.rs:1:5:foo\nb.rs:2:80:// foo\nc.rs:3:20:foo\n");
+    let (total, lines) = LightCommand::new_grep(&mut cmd, Some(10), false)
+        .max_column(Some(20), grep_column)
+        .execute_and_gather_output(10, 10, |_, _, _| {})
+        .unwrap();
+    assert_eq!(total, 2);
+    assert_eq!(lines, vec!["a.rs:1:5:foo", "c.rs:3:20:foo"]);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_exclude_grep_lines() {
+    let mut dir = std::env::temp_dir();
+    dir.push(format!("test_exclude_grep_lines_{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("src")).unwrap();
+    for file in &["src/lib.rs", "src/main.rs"] {
+        std::fs::write(dir.join(file), "").unwrap();
+    }
+
+    assert_eq!(
+        grep_path("src/lib.rs:1:5:fn needle() {}"),
+        Some("src/lib.rs")
+    );
+    assert_eq!(grep_path("no prefix"), None);
+
+    // The excluded lines after the buffered ones are not counted either.
+    let excluded = ExcludedPath::new(&dir.join("src/lib.rs"), Some(&dir)).unwrap();
+    let mut cmd = Command::new("printf");
+    cmd.current_dir(&dir).arg(
+        "src/main.rs:3:1:needle();\nsrc/lib.rs:1:5:fn needle() {}\n./src/lib.rs:7:9:needle();\n",
+    );
+    let (total, lines) = LightCommand::new_grep(&mut cmd, Some(1), false)
+        .exclude_path(Some(excluded), grep_path)
+        .execute_and_gather_output(1, 1, |_, _, _| {})
+        .unwrap();
+    assert_eq!(total, 1);
+    assert_eq!(lines, vec!["src/main.rs:3:1:needle();"]);
+    assert_eq!(partial_grep_lines(&lines, 2, false), lines);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_dedup_grep_lines() {
+    assert_eq!(grep_location("src/lib.rs:10:5:err"), Some("src/lib.rs:10"));
+
+    let mut cmd = Command::new("printf");
+    cmd.arg(
+        "src/lib.rs:10:5:let err = err;\nsrc/lib.rs:10:11:let err = err;\nsrc/lib.rs:11:5:err\n\
+         src/main.rs:10:5:let err = err;\nsrc/main.rs:10:11:let err = err;\n",
+    );
+    // The duplicates after the buffered lines are not counted either.
+    let (total, lines) = LightCommand::new_grep(&mut cmd, Some(2), false)
+        .dedup_by(true, grep_location)
+        .execute_and_gather_output(2, 2, |_, _, _| {})
+        .unwrap();
+    assert_eq!(total, 3);
+    assert_eq!(
+        lines,
+        vec!["src/lib.rs:10:5:let err = err;", "src/lib.rs:11:5:err"]
+    );
+}
+
+#[test]
+fn test_sort_grep_lines_by_mtime() {
+    let mut dir = std::env::temp_dir();
+    dir.push(format!(
+        "test_sort_grep_lines_by_mtime_{}",
+        std::process::id()
+    ));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("old.rs"), "").unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    std::fs::write(dir.join("new.rs"), "").unwrap();
+
+    let lines = [
+        "old.rs:1:1:needle",
+        "missing.rs:1:1:needle",
+        "new.rs:1:1:needle",
+        "old.rs:2:1:needle",
+        "new.rs:2:1:needle",
+    ];
+    let recent = |number| {
+        let mut recent = RecentGrepLines::new(&dir, number);
+        lines.iter().for_each(|line| recent.push(line));
+        recent.lines()
+    };
+    assert_eq!(
+        recent(10),
+        vec![
+            "new.rs:1:1:needle",
+            "new.rs:2:1:needle",
+            "old.rs:1:1:needle",
+            "old.rs:2:1:needle",
+            "missing.rs:1:1:needle",
+        ]
+    );
+    // The lines in the recent file are kept even if they come last.
+    assert_eq!(
+        recent(3),
+        vec![
+            "new.rs:1:1:needle",
+            "new.rs:2:1:needle",
+            "old.rs:1:1:needle"
+        ]
+    );
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_group_grep_lines_by_file() {
+    let mut cmd = Command::new("printf");
+    cmd.arg(
+        "src/main.rs:1:1:needle\nsrc/lib.rs:3:1:needle\nsrc/main.rs:7:5:needle\n\
+         src/main.rs:9:2:needle\nsrc/bin.rs:1:1:needle\n",
+    );
+    // More matches than the buffered lines are all counted.
+    let mut file_counts = GrepFileCounts::default();
+    let (total, lines) = LightCommand::new_grep(&mut cmd, Some(1), false)
+        .execute_and_gather_output(1, 1, |_, line, _| file_counts.push(line))
+        .unwrap();
+    assert_eq!(total, 5);
+    assert_eq!(lines.len(), 1);
+    assert_eq!(
+        file_counts.files,
+        vec![
+            ("src/main.rs".into(), 3),
+            ("src/lib.rs".into(), 1),
+            ("src/bin.rs".into(), 1)
+        ]
+    );
+}
+
+#[test]
+fn test_query_is_compiled_once_per_run() {
+    let lines = (0..1000)
+        .map(|i| format!("src/lib.rs:{}:5:let Err(e) = error_{};", i + 1, i))
+        .collect::<Vec<_>>();
+
+    let highlighter = QueryHighlighter::new("err");
+    let indices = lines
+        .iter()
+        .map(|line| highlighter.indices(line))
+        .collect::<Vec<_>>();
+
+    assert_eq!(QUERY_COMPILED.with(|compiled| compiled.get()), 1);
+    assert_eq!(indices[0], vec![19, 20, 21, 28, 29, 30]);
+    assert_eq!(indices.len(), lines.len());
+}
+
+#[test]
+fn test_grep_segments() {
+    let line = "src/lib.rs:12:5:let x = 1;";
+    let segments = GrepSegments::new(line).unwrap();
+    assert_eq!(
+        segments,
+        GrepSegments {
+            path: [0, 10],
+            lnum: [11, 13],
+            text: [16, line.len()],
+        }
+    );
+    assert_eq!(&line[segments.path[0]..segments.path[1]], "src/lib.rs");
+    assert_eq!(&line[segments.lnum[0]..segments.lnum[1]], "12");
+    assert_eq!(&line[segments.text[0]..segments.text[1]], "let x = 1;");
+    assert_eq!(
+        serde_json::json!(segments),
+        serde_json::json!({ "path": [0, 10], "lnum": [11, 13], "text": [16, 26] })
+    );
+
+    assert!(GrepSegments::new("not a grep line").is_none());
+}
+
 #[test]
 fn test_git_repo() {
     let mut cmd_dir: PathBuf = "/Users/xuliucheng/.vim/plugged/vim-clap".into();
@@ -101,3 +869,13 @@ fn test_git_repo() {
         println!("{:?} does not exist", cmd_dir);
     }
 }
+
+#[test]
+fn test_grep_prefix_of_windows_paths() {
+    let line = r#"C:\src\foo.rs:42:7:let s = "a:1:2:b";"#;
+    let segments = GrepSegments::new(line).unwrap();
+    assert_eq!(&line[segments.path[0]..segments.path[1]], r"C:\src\foo.rs");
+    assert_eq!(&line[segments.lnum[0]..segments.lnum[1]], "42");
+    assert_eq!(grep_column(line), Some(7));
+    assert_eq!(grep_text(line), r#"let s = "a:1:2:b";"#);
+}
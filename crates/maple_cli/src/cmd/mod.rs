@@ -4,12 +4,17 @@ use fuzzy_filter::Algo;
 use structopt::clap::AppSettings;
 use structopt::StructOpt;
 
+pub mod bench;
+pub mod cache;
 pub mod exec;
+pub mod files;
 pub mod filter;
 pub mod grep;
 pub mod helptags;
 pub mod rpc;
 
+// Cmd is parsed once, the size of the options doesn't matter.
+#[allow(clippy::large_enum_variant)]
 #[derive(StructOpt, Debug)]
 pub enum Cmd {
     /// Display the current version
@@ -18,9 +23,18 @@ pub enum Cmd {
     /// Fuzzy filter the input
     #[structopt(name = "filter")]
     Filter {
-        /// Initial query string
+        /// Initial query string, required unless --query-file or --query-stdin is used.
         #[structopt(index = 1, short, long)]
-        query: String,
+        query: Option<String>,
+
+        /// Read the query from a file instead, the trailing newline is dropped.
+        #[structopt(long = "query-file", parse(from_os_str))]
+        query_file: Option<PathBuf>,
+
+        /// Read the query from the first line of stdin instead, the candidates can't be read
+        /// from stdin then.
+        #[structopt(long = "query-stdin")]
+        query_stdin: bool,
 
         /// Filter algorithm
         #[structopt(short, long, possible_values = &Algo::variants(), case_insensitive = true)]
@@ -30,17 +44,38 @@ pub enum Cmd {
         #[structopt(short, long)]
         cmd: Option<String>,
 
-        /// Working directory of shell command.
-        #[structopt(short, long)]
-        cmd_dir: Option<String>,
-
         /// Synchronous filtering, returns after the input stream is complete.
         #[structopt(short, long)]
         sync: bool,
 
         /// Read input from a file instead of stdin, only absolute file path is supported.
-        #[structopt(long = "input", parse(from_os_str))]
-        input: Option<PathBuf>,
+        ///
+        /// Can be repeated to read the lines of all the files, see --read-threads.
+        #[structopt(long = "input", parse(from_os_str), number_of_values = 1)]
+        input: Vec<PathBuf>,
+
+        /// Read input from the Unix domain socket at this path, e.g., served by an indexing daemon.
+        #[structopt(long = "socket", parse(from_os_str))]
+        socket: Option<PathBuf>,
+
+        /// Read the symbols from the tags file generated by ctags at this path, only the symbol
+        /// names are matched, but `name:path:line` is displayed.
+        #[structopt(long = "tags", parse(from_os_str))]
+        tags: Option<PathBuf>,
+
+        /// Read the paths of the files under this directory walked without the external tools,
+        /// see --hidden and --no-ignore.
+        #[structopt(long = "files", parse(from_os_str))]
+        files: Option<PathBuf>,
+
+        #[structopt(flatten)]
+        files_opts: files::FilesOptions,
+
+        #[structopt(flatten)]
+        match_opts: filter::MatchOptions,
+
+        #[structopt(flatten)]
+        filter_opts: filter::FilterOptions,
     },
     /// Execute the command
     #[structopt(name = "exec")]
@@ -49,17 +84,8 @@ pub enum Cmd {
         #[structopt(index = 1, short, long)]
         cmd: String,
 
-        /// Specify the output file path when the output of command exceeds the threshold.
-        #[structopt(long = "output")]
-        output: Option<String>,
-
-        /// Specify the threshold for writing the output of command to a tempfile.
-        #[structopt(long = "output-threshold", default_value = "100000")]
-        output_threshold: usize,
-
-        /// Specify the working directory of CMD
-        #[structopt(long = "cmd-dir", parse(from_os_str))]
-        cmd_dir: Option<PathBuf>,
+        #[structopt(flatten)]
+        opts: exec::ExecOptions,
     },
     /// Execute the grep command to avoid the escape issue
     #[structopt(name = "grep")]
@@ -73,16 +99,14 @@ pub enum Cmd {
         grep_cmd: String,
 
         /// Specify the query string for GREP_CMD.
-        #[structopt(index = 2, short, long)]
-        grep_query: String,
-
-        /// Delegate to -g option of rg
-        #[structopt(short = "g", long = "glob")]
-        glob: Option<String>,
+        ///
+        /// Only the lines matching all the queries are kept if more than one are given,
+        /// the first one is passed to GREP_CMD.
+        #[structopt(index = 2, short, long, required = true)]
+        grep_query: Vec<String>,
 
-        /// Specify the working directory of CMD
-        #[structopt(long = "cmd-dir", parse(from_os_str))]
-        cmd_dir: Option<PathBuf>,
+        #[structopt(flatten)]
+        opts: grep::GrepOptions,
     },
     #[structopt(name = "rpc")]
     RPC,
@@ -106,6 +130,36 @@ pub enum Cmd {
         #[structopt(long = "cmd-dir", parse(from_os_str))]
         cmd_dir: Option<PathBuf>,
     },
+    /// Report the throughput of filtering the input to stderr, the results are not printed.
+    #[structopt(name = "bench")]
+    Bench {
+        /// Query string used for filtering.
+        #[structopt(index = 1, short, long)]
+        query: String,
+
+        /// Read the candidates from a file instead of stdin.
+        #[structopt(long = "input", parse(from_os_str))]
+        input: Option<PathBuf>,
+
+        /// Only benchmark this algorithm, all of them by default.
+        #[structopt(short, long, possible_values = &Algo::variants(), case_insensitive = true)]
+        algo: Option<Algo>,
+
+        /// Number of the runs averaged for each algorithm.
+        #[structopt(long = "iterations", default_value = "1")]
+        iterations: usize,
+    },
+    /// Print the number and the total size of the cache files of the commands' output.
+    #[structopt(name = "cache")]
+    Cache {
+        /// Print each cache file with its command, creation time and size as well.
+        #[structopt(long = "list")]
+        list: bool,
+
+        /// Remove all the cache files.
+        #[structopt(long = "clear")]
+        clear: bool,
+    },
 }
 
 #[derive(StructOpt, Debug)]
@@ -132,6 +186,11 @@ pub struct Maple {
     #[structopt(long = "enable-icon")]
     pub enable_icon: bool,
 
+    /// Load the icons keyed by the file name or extension from this JSON file, e.g.,
+    /// `{"rs": "🦀"}`, which take precedence over the builtin ones.
+    #[structopt(long = "icon-map", parse(from_os_str))]
+    pub icon_map: Option<PathBuf>,
+
     #[structopt(subcommand)]
     pub command: Cmd,
 }
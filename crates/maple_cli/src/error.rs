@@ -1,18 +0,0 @@
-#[derive(Debug)]
-pub struct DummyError;
-
-impl std::fmt::Display for DummyError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "DummyError is here!")
-    }
-}
-
-impl std::error::Error for DummyError {
-    fn description(&self) -> &str {
-        "DummyError used for anyhow"
-    }
-
-    fn cause(&self) -> Option<&dyn std::error::Error> {
-        None
-    }
-}
@@ -10,9 +10,11 @@ macro_rules! println_json {
 pub mod cmd;
 pub use {
     anyhow::Result,
-    fuzzy_filter::{subprocess, Source},
+    fuzzy_filter::{subprocess, Algo, Source},
     structopt::StructOpt,
 };
 
-mod error;
 mod light_command;
+mod utils;
+
+pub use utils::{load_icon_map, print_error};
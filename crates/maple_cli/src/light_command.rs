@@ -1,13 +1,201 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashSet;
 use std::fs::File;
-use std::io::Write;
-use std::path::PathBuf;
-use std::process::{Command, Output};
-use std::time::SystemTime;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{ChildStdout, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant, SystemTime};
 
 use anyhow::Result;
-use icon::{prepend_grep_icon, prepend_icon};
+use icon::{prepend_entry_icon, prepend_grep_icon};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
 
-use crate::error::DummyError;
+use crate::utils::{fnv1a, ExcludedPath, FNV_OFFSET_BASIS};
+
+/// Exit code of grep tools like rg when nothing is matched.
+const GREP_NO_MATCHES_EXIT_CODE: i32 = 1;
+
+/// Default maximum number of the cache files kept for each command.
+const MAX_CACHE_FILES_PER_CMD: usize = 10;
+
+/// Cache files older than this are removed even if the directory of command is unchanged.
+const MAX_CACHE_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Options for caching the output of command.
+#[derive(StructOpt, Debug, Clone)]
+pub struct CacheOptions {
+    /// Keep at most this number of cache files for each command, the oldest ones are removed.
+    #[structopt(long = "max-cache-files-per-cmd", default_value = "10")]
+    pub max_cache_files_per_cmd: usize,
+
+    /// Keep the cache files of each command within this number of bytes in total, the oldest
+    /// ones are removed, but the latest one is always kept.
+    #[structopt(long = "max-cache-bytes-per-cmd")]
+    pub max_cache_bytes_per_cmd: Option<u64>,
+
+    /// Reuse the cache file of the same output of the command instead of writing a new one,
+    /// which is found by the hash of the output kept in the file name.
+    #[structopt(long = "cache-by-content")]
+    pub cache_by_content: bool,
+}
+
+impl Default for CacheOptions {
+    fn default() -> Self {
+        Self {
+            max_cache_files_per_cmd: MAX_CACHE_FILES_PER_CMD,
+            max_cache_bytes_per_cmd: None,
+            cache_by_content: false,
+        }
+    }
+}
+
+/// Returns the key of the command of `args` running in `cmd_dir` for caching its output.
+fn cache_key(args: &[&str], cmd_dir: Option<&Path>) -> String {
+    let cmd_dir = cmd_dir.map(|dir| dir.to_string_lossy());
+    // Each part is terminated by a NUL byte, so that `["ab", "c"]` differs from `["a", "bc"]`.
+    let hash = args
+        .iter()
+        .copied()
+        .chain(cmd_dir.as_deref())
+        .fold(FNV_OFFSET_BASIS, |hash, part| {
+            fnv1a(hash, part.as_bytes().iter().chain(&[0]))
+        });
+    format!("{:016x}", hash)
+}
+
+/// Name of the file in the cache dir of each command recording the command, see [`CmdInfo`].
+pub const CMD_INFO_FILE: &str = "cmd.json";
+
+/// The command whose output is cached, the cache dir is named after its hash only.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CmdInfo {
+    pub args: Vec<String>,
+    pub cmd_dir: Option<PathBuf>,
+}
+
+/// Returns the root directory of all the cache dirs of the commands.
+pub fn cache_root() -> PathBuf {
+    std::env::temp_dir().join("clap_cache")
+}
+
+/// Returns the directory for caching the output of the command of `args` running in `cmd_dir`,
+/// created with the [`CMD_INFO_FILE`] if missing.
+fn cmd_cache_dir(args: &[&str], cmd_dir: Option<&Path>) -> Result<PathBuf> {
+    let dir = cache_root().join(cache_key(args, cmd_dir));
+    std::fs::create_dir_all(&dir)?;
+    let info_file = dir.join(CMD_INFO_FILE);
+    if !info_file.exists() {
+        let info = CmdInfo {
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+            cmd_dir: cmd_dir.map(Path::to_path_buf),
+        };
+        std::fs::write(info_file, serde_json::to_vec(&info)?)?;
+    }
+    Ok(dir)
+}
+
+/// Returns the hash of the output `stdout` kept in the name of its cache file.
+fn content_hash(stdout: &[u8]) -> u64 {
+    fnv1a(FNV_OFFSET_BASIS, stdout)
+}
+
+/// Returns the cache file in `dir` of the output of `len` bytes and `hash`, i.e., named
+/// `{timestamp}_{hash}`, see [`content_hash`].
+fn find_cache_file_by_content(dir: &Path, hash: u64, len: u64) -> Option<PathBuf> {
+    let suffix = format!("_{:x}", hash);
+    std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .find(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.ends_with(&suffix))
+                && path.metadata().is_ok_and(|metadata| metadata.len() == len)
+        })
+}
+
+/// Returns the creation time in nanoseconds of the cache file at `path`, which is the leading
+/// timestamp of the file name, see [`LightCommand::tempfile`].
+///
+/// Returns `None` if the file name is not in the shape of `{timestamp}[_{...}]`.
+pub fn cache_file_timestamp(path: &Path) -> Option<u64> {
+    path.file_name()?.to_str()?.split('_').next()?.parse().ok()
+}
+
+/// Returns true if the cache file at `path` was created before `cmd_dir` was modified, e.g.,
+/// a file is added, or more than [`MAX_CACHE_AGE`] ago.
+fn is_stale_cache_file(path: &Path, cmd_dir: Option<&Path>, now: SystemTime) -> bool {
+    let created = match cache_file_timestamp(path) {
+        Some(nanos) => SystemTime::UNIX_EPOCH + Duration::from_nanos(nanos),
+        None => return false,
+    };
+    let expired = now
+        .duration_since(created)
+        .map(|age| age > MAX_CACHE_AGE)
+        .unwrap_or(false);
+    expired
+        || cmd_dir
+            .and_then(|dir| dir.metadata().ok()?.modified().ok())
+            .map(|modified| modified > created)
+            .unwrap_or(false)
+}
+
+/// Removes the cache file at `path`, which could have been removed already by another maple
+/// process cleaning the same directory.
+fn remove_cache_file(path: &Path) -> Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Removes the stale files in `dir`, see [`is_stale_cache_file`], and then the oldest ones so
+/// that at most `max_files` of them are left, taking at most `max_bytes` in total if given.
+///
+/// The files are ordered by the timestamp in their names, the ones not named by
+/// [`LightCommand::tempfile`] are left alone.
+fn remove_stale_cache_files(
+    dir: &Path,
+    max_files: usize,
+    max_bytes: Option<u64>,
+    cmd_dir: Option<&Path>,
+) -> Result<()> {
+    let now = SystemTime::now();
+    let mut entries = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = match entry {
+            Ok(entry) => entry.path(),
+            Err(_) => continue,
+        };
+        let timestamp = match cache_file_timestamp(&path) {
+            Some(timestamp) => timestamp,
+            None => continue,
+        };
+        if is_stale_cache_file(&path, cmd_dir, now) {
+            remove_cache_file(&path)?;
+        } else if let Ok(metadata) = path.metadata() {
+            entries.push((timestamp, path, metadata.len()));
+        }
+    }
+
+    // The newest first, the latest one is always kept.
+    entries.sort_unstable_by(|a, b| b.cmp(a));
+    let mut total_bytes = 0;
+    for (idx, (_, path, len)) in entries.iter().enumerate() {
+        total_bytes += len;
+        let over_budget = max_bytes.map(|max| total_bytes > max).unwrap_or(false);
+        if idx >= max_files || (idx > 0 && over_budget) {
+            remove_cache_file(path)?;
+        }
+    }
+    Ok(())
+}
 
 /// Remove the last element if it's empty string.
 #[inline]
@@ -20,17 +208,159 @@ fn trim_trailing(lines: &mut Vec<String>) {
     }
 }
 
-pub fn set_current_dir(cmd: &mut Command, cmd_dir: Option<PathBuf>) {
+/// Removes the ANSI escape sequences, e.g., the color codes, from `line`.
+fn strip_ansi_codes(line: &str) -> Cow<'_, str> {
+    lazy_static! {
+        static ref ANSI_RE: Regex = Regex::new(r"\x1B\[[0-?]*[ -/]*[@-~]").unwrap();
+    }
+    ANSI_RE.replace_all(line, "")
+}
+
+/// Reads all the lines from `reader` for which `keep` returns true, but keeps at most
+/// `max_buffered` of them in memory.
+///
+/// The excess lines are dropped once the cap is hit, they are still counted in the total though.
+/// `on_line` is called with the total so far, the kept line and the buffered lines after each
+/// kept line.
+///
+/// Returns the tuple of (total number of lines, first `max_buffered` lines).
+fn read_bounded(
+    mut reader: impl BufRead,
+    max_buffered: usize,
+    keep: impl Fn(&str) -> bool,
+    mut on_line: impl FnMut(usize, &str, &[String]),
+) -> std::io::Result<(usize, Vec<String>)> {
+    let mut total = 0usize;
+    let mut lines = Vec::with_capacity(max_buffered);
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        if reader.read_until(b'\n', &mut buf)? == 0 {
+            break;
+        }
+        if buf.last() == Some(&b'\n') {
+            buf.pop();
+        }
+        let line = String::from_utf8_lossy(&buf);
+        if !keep(&line) {
+            continue;
+        }
+        total += 1;
+        if lines.len() < max_buffered {
+            lines.push(line.to_string());
+        }
+        on_line(total, &line, &lines);
+    }
+    Ok((total, lines))
+}
+
+/// Reads the stdout of command line by line in another thread until the deadline.
+///
+/// The lines are sent as a whole, so the output read before the deadline never ends with
+/// a partial line.
+struct TimedReader {
+    lines: Receiver<Vec<u8>>,
+    deadline: Instant,
+    line: Vec<u8>,
+    pos: usize,
+    timed_out: bool,
+}
+
+impl TimedReader {
+    fn new(stdout: ChildStdout, timeout: Duration) -> Self {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut stdout = BufReader::new(stdout);
+            loop {
+                let mut line = Vec::new();
+                match stdout.read_until(b'\n', &mut line) {
+                    Ok(0) | Err(_) => break,
+                    Ok(_) => {
+                        if tx.send(line).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+        Self {
+            lines: rx,
+            deadline: Instant::now() + timeout,
+            line: Vec::new(),
+            pos: 0,
+            timed_out: false,
+        }
+    }
+}
+
+impl Read for TimedReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.line.len() {
+            if self.timed_out {
+                return Ok(0);
+            }
+            let timeout = self.deadline.saturating_duration_since(Instant::now());
+            match self.lines.recv_timeout(timeout) {
+                Ok(line) => {
+                    self.line = line;
+                    self.pos = 0;
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    self.timed_out = true;
+                    return Ok(0);
+                }
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            }
+        }
+        let n = std::cmp::min(buf.len(), self.line.len() - self.pos);
+        buf[..n].copy_from_slice(&self.line[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Extracts the text to be matched from a line of output.
+type TextOf = fn(&str) -> &str;
+
+/// Extracts the 1-based column of the match in a line of output.
+type ColumnOf = fn(&str) -> Option<usize>;
+
+/// Extracts the path of the file in a line of output.
+type PathOf = fn(&str) -> Option<&str>;
+
+/// Extracts the key of a line of output, the lines of the same key are duplicates.
+type KeyOf = fn(&str) -> Option<&str>;
+
+/// Returns true if the text of `line` extracted by `text_of` contains all the `substrings`.
+fn contains_all(line: &str, substrings: &[String], text_of: TextOf) -> bool {
+    let text = text_of(line);
+    substrings.iter().all(|s| text.contains(s.as_str()))
+}
+
+/// Sets the working directory of `cmd` to `cmd_dir`.
+///
+/// If `cmd_dir` does not exist, the command is run in the current dir of this process,
+/// the warning about it is returned, which should be passed to the client.
+pub fn set_current_dir(cmd: &mut Command, cmd_dir: Option<PathBuf>) -> Option<String> {
     if let Some(cmd_dir) = cmd_dir {
         // If cmd_dir is not a directory, use its parent as current dir.
-        if cmd_dir.is_dir() {
-            cmd.current_dir(cmd_dir);
+        let dir = if cmd_dir.is_dir() {
+            cmd_dir
         } else {
             let mut cmd_dir = cmd_dir;
             cmd_dir.pop();
-            cmd.current_dir(cmd_dir);
+            cmd_dir
+        };
+        if dir.is_dir() {
+            cmd.current_dir(dir);
+        } else {
+            return Some(format!(
+                "{} does not exist, running in the current dir instead",
+                dir.display()
+            ));
         }
     }
+    None
 }
 
 #[derive(Debug)]
@@ -42,6 +372,18 @@ pub struct LightCommand<'a> {
     enable_icon: bool,
     grep_enable_icon: bool,
     output_threshold: usize,
+    strip_ansi: bool,
+    max_cache_files_per_cmd: usize,
+    max_cache_bytes_per_cmd: Option<u64>,
+    cache_by_content: bool,
+    no_matches_exit_code: Option<i32>,
+    warning: Option<String>,
+    required_substrings: Option<(Vec<String>, TextOf)>,
+    max_column: Option<(usize, ColumnOf)>,
+    excluded_path: Option<(ExcludedPath, PathOf)>,
+    dedup_by: Option<(KeyOf, RefCell<HashSet<String>>)>,
+    timeout: Option<Duration>,
+    timed_out: bool,
 }
 
 impl<'a> LightCommand<'a> {
@@ -52,6 +394,7 @@ impl<'a> LightCommand<'a> {
         enable_icon: bool,
         grep_enable_icon: bool,
         output_threshold: usize,
+        strip_ansi: bool,
     ) -> Self {
         Self {
             cmd,
@@ -61,6 +404,18 @@ impl<'a> LightCommand<'a> {
             enable_icon,
             grep_enable_icon,
             output_threshold,
+            strip_ansi,
+            max_cache_files_per_cmd: MAX_CACHE_FILES_PER_CMD,
+            max_cache_bytes_per_cmd: None,
+            cache_by_content: false,
+            no_matches_exit_code: None,
+            warning: None,
+            required_substrings: None,
+            max_column: None,
+            excluded_path: None,
+            dedup_by: None,
+            timeout: None,
+            timed_out: false,
         }
     }
 
@@ -73,70 +428,340 @@ impl<'a> LightCommand<'a> {
             enable_icon: false,
             grep_enable_icon,
             output_threshold: 0usize,
+            strip_ansi: false,
+            max_cache_files_per_cmd: MAX_CACHE_FILES_PER_CMD,
+            max_cache_bytes_per_cmd: None,
+            cache_by_content: false,
+            no_matches_exit_code: Some(GREP_NO_MATCHES_EXIT_CODE),
+            warning: None,
+            required_substrings: None,
+            max_column: None,
+            excluded_path: None,
+            dedup_by: None,
+            timeout: None,
+            timed_out: false,
         }
     }
 
-    /// Collect the output of command, exit directly if any error happened.
-    fn output(&mut self) -> Result<Output> {
-        let cmd_output = self.cmd.output()?;
+    pub fn cache_opts(mut self, cache_opts: &CacheOptions) -> Self {
+        self.max_cache_files_per_cmd = cache_opts.max_cache_files_per_cmd;
+        self.max_cache_bytes_per_cmd = cache_opts.max_cache_bytes_per_cmd;
+        self.cache_by_content = cache_opts.cache_by_content;
+        self
+    }
+
+    /// Warning passed to the client in the `warning` field of the output, e.g., returned by
+    /// [`set_current_dir`].
+    pub fn warning(mut self, warning: Option<String>) -> Self {
+        self.warning = warning;
+        self
+    }
+
+    /// Keeps only the lines of output whose text extracted by `text_of` contains all the
+    /// `substrings`, e.g., the grep queries other than the one passed to the command.
+    ///
+    /// The lines are filtered before being counted and cached.
+    pub fn require_all(mut self, substrings: Vec<String>, text_of: TextOf) -> Self {
+        if !substrings.is_empty() {
+            self.required_substrings = Some((substrings, text_of));
+        }
+        self
+    }
+
+    /// Keeps only the lines of output whose column extracted by `column_of` is not greater than
+    /// `max_column`, the lines without column are kept.
+    ///
+    /// The lines are filtered before being counted and cached.
+    pub fn max_column(mut self, max_column: Option<usize>, column_of: ColumnOf) -> Self {
+        self.max_column = max_column.map(|max_column| (max_column, column_of));
+        self
+    }
+
+    /// Drops the lines of output whose path extracted by `path_of` refers to `excluded`.
+    ///
+    /// The lines are filtered before being counted and cached.
+    pub fn exclude_path(mut self, excluded: Option<ExcludedPath>, path_of: PathOf) -> Self {
+        self.excluded_path = excluded.map(|excluded| (excluded, path_of));
+        self
+    }
 
-        // vim-clap does not handle the stderr stream, we just pass the error info via stdout.
-        if !cmd_output.status.success() && !cmd_output.stderr.is_empty() {
-            let error = format!("{}", String::from_utf8_lossy(&cmd_output.stderr));
-            println_json!(error);
-            std::process::exit(1);
+    /// Keeps only the first one of the lines of output with the same key extracted by `key_of`
+    /// if `dedup` is true, the lines without key are kept.
+    ///
+    /// The lines are filtered before being counted and cached.
+    pub fn dedup_by(mut self, dedup: bool, key_of: KeyOf) -> Self {
+        if dedup {
+            self.dedup_by = Some((key_of, RefCell::default()));
         }
+        self
+    }
 
-        Ok(cmd_output)
+    /// Kills the command if it's not complete within `timeout`, the output read so far is used
+    /// as if it's the whole output, see [`timed_out`].
+    pub fn timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Returns true if the command was killed due to the timeout, i.e., the output is partial.
+    pub fn timed_out(&self) -> bool {
+        self.timed_out
+    }
+
+    /// Returns true if the `line` of output is kept, see [`require_all`], [`max_column`],
+    /// [`exclude_path`] and [`dedup_by`].
+    fn keep_line(&self, line: &str) -> bool {
+        let within_column = match self.max_column {
+            Some((max_column, column_of)) => column_of(line).is_none_or(|col| col <= max_column),
+            None => true,
+        };
+        let excluded = match &self.excluded_path {
+            Some((excluded, path_of)) => path_of(line).is_some_and(|path| excluded.matches(path)),
+            None => false,
+        };
+        within_column
+            && !excluded
+            && match &self.required_substrings {
+                Some((substrings, text_of)) => contains_all(line, substrings, *text_of),
+                None => true,
+            }
+            // Checked last, only the kept lines are seen.
+            && match &self.dedup_by {
+                Some((key_of, seen)) => {
+                    key_of(line).is_none_or(|key| seen.borrow_mut().insert(key.into()))
+                }
+                None => true,
+            }
+    }
+
+    /// Prints the output message with the warning if any.
+    fn print(&self, mut msg: serde_json::Value) {
+        if let Some(warning) = &self.warning {
+            msg["warning"] = serde_json::json!(warning);
+        }
+        if self.timed_out {
+            msg["timed_out"] = serde_json::json!(true);
+        }
+        println!("{}", msg);
+    }
+
+    /// Runs the command and reads its stdout with `read`, returns the stderr as the error if
+    /// the command failed.
+    ///
+    /// If the command is killed due to the timeout, `read` reaches the end of stdout at the
+    /// deadline and the command is not considered failed.
+    fn read_stdout<T>(
+        &mut self,
+        read: impl FnOnce(&Self, &mut dyn BufRead) -> std::io::Result<T>,
+    ) -> Result<T> {
+        let mut child = self
+            .cmd
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // Drain stderr in another thread, otherwise the child could block on a full stderr pipe.
+        let mut stderr = child.stderr.take().expect("stderr is piped; qed");
+        let stderr_reader = std::thread::spawn(move || {
+            let mut buf = Vec::new();
+            let _ = stderr.read_to_end(&mut buf);
+            buf
+        });
+
+        let stdout = child.stdout.take().expect("stdout is piped; qed");
+        let output = match self.timeout {
+            Some(timeout) => {
+                let mut reader = TimedReader::new(stdout, timeout);
+                let output = read(self, &mut BufReader::new(&mut reader))?;
+                self.timed_out = reader.timed_out;
+                output
+            }
+            None => read(self, &mut BufReader::new(stdout))?,
+        };
+
+        if self.timed_out {
+            // The stdout and stderr could be still held by the children of the command, so
+            // they are not waited for.
+            let _ = child.kill();
+            let _ = child.wait();
+        } else {
+            let status = child.wait()?;
+            let stderr = stderr_reader.join().unwrap_or_default();
+            check_failure(status, &stderr, self.no_matches_exit_code)?;
+        }
+
+        Ok(output)
+    }
+
+    /// Collect the stdout of command, returns the stderr as the error if the command failed.
+    fn output(&mut self) -> Result<Vec<u8>> {
+        self.read_stdout(|_, stdout| {
+            let mut buf = Vec::new();
+            stdout.read_to_end(&mut buf)?;
+            Ok(buf)
+        })
+    }
+
+    /// Stream the stdout of command line by line, keeping at most `max_buffered` lines in memory.
+    ///
+    /// Returns the stderr as the error if the command failed, same as [`output`].
+    fn bounded_output(
+        &mut self,
+        max_buffered: usize,
+        on_line: impl FnMut(usize, &str, &[String]),
+    ) -> Result<Vec<String>> {
+        let (total, lines) = self.read_stdout(|this, stdout| {
+            read_bounded(stdout, max_buffered, |line| this.keep_line(line), on_line)
+        })?;
+
+        self.total = total;
+
+        Ok(lines)
+    }
+
+    /// Removes the lines of `stdout` not kept by [`keep_line`].
+    fn filter_output(&self, stdout: Vec<u8>) -> Vec<u8> {
+        if self.required_substrings.is_none()
+            && self.max_column.is_none()
+            && self.excluded_path.is_none()
+            && self.dedup_by.is_none()
+        {
+            return stdout;
+        }
+        let mut filtered = Vec::with_capacity(stdout.len());
+        for line in stdout.split_inclusive(|&b| b == b'\n') {
+            let text = line.strip_suffix(b"\n").unwrap_or(line);
+            if self.keep_line(&String::from_utf8_lossy(text)) {
+                filtered.extend_from_slice(line);
+            }
+        }
+        filtered
     }
 
     /// Normally we only care about the top N items and number of total results.
-    fn minimalize_job_overhead(&self, stdout: &[u8]) -> Result<()> {
+    ///
+    /// The stdout is streamed and counted line by line instead of being buffered as a whole,
+    /// only the top N lines are kept in memory.
+    ///
+    /// Returns false if the number is not specified, i.e., the whole output is needed.
+    fn minimalize_job_overhead(&mut self) -> Result<bool> {
         if let Some(number) = self.number {
-            // TODO: do not have to into String for whole stdout, find the nth index of newline.
-            // &cmd_output.stdout[..nth_newline_index]
-            let stdout_str = String::from_utf8_lossy(&stdout);
-            let lines = self.try_prepend_icon(stdout_str.split('\n').take(number));
-            let total = self.total;
-            println_json!(total, lines);
-            return Ok(());
+            let lines = self.top_lines(number)?;
+            self.print(serde_json::json!({ "total": self.total, "lines": lines }));
+            return Ok(true);
         }
-        Err(anyhow::Error::new(DummyError).context("No truncation"))
+        Ok(false)
     }
 
-    fn try_prepend_icon<'b>(&self, top_n: impl std::iter::Iterator<Item = &'b str>) -> Vec<String> {
-        let mut lines = if self.grep_enable_icon {
-            top_n.map(prepend_grep_icon).collect::<Vec<_>>()
-        } else if self.enable_icon {
-            top_n.map(prepend_icon).collect::<Vec<_>>()
-        } else {
-            top_n.map(Into::into).collect::<Vec<_>>()
-        };
+    /// Returns the top `number` lines of the output ready for displaying.
+    ///
+    /// The entries are few enough to be stat'ed for the icons of directories and symlinks.
+    fn top_lines(&mut self, number: usize) -> Result<Vec<String>> {
+        let lines = self.bounded_output(number, |_, _, _| {})?;
+        let base_dir = self.cmd_dir();
+        Ok(self.try_prepend_icon(lines.iter().map(|line| line.as_str()), base_dir.as_deref()))
+    }
+
+    /// Prepends the icons if enabled, the entries are stat'ed relative to `base_dir` if it's
+    /// given, otherwise only the ones ending with `/` get the icon of directory.
+    fn try_prepend_icon<'b>(
+        &self,
+        top_n: impl std::iter::Iterator<Item = &'b str>,
+        base_dir: Option<&Path>,
+    ) -> Vec<String> {
+        let mut lines = self.iconize(top_n, base_dir);
         trim_trailing(&mut lines);
         lines
     }
 
-    fn tempfile(&self, args: &[&str]) -> Result<PathBuf> {
+    fn iconize<'b>(
+        &self,
+        lines: impl std::iter::Iterator<Item = &'b str>,
+        base_dir: Option<&Path>,
+    ) -> Vec<String> {
+        lines
+            .map(|line| {
+                let line = if self.strip_ansi {
+                    strip_ansi_codes(line)
+                } else {
+                    Cow::Borrowed(line)
+                };
+                if self.grep_enable_icon {
+                    prepend_grep_icon(&line)
+                } else if self.enable_icon {
+                    let path = base_dir.map(|dir| dir.join(line.as_ref()));
+                    prepend_entry_icon(&line, path.as_deref())
+                } else {
+                    line.into_owned()
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the working directory of the command.
+    fn cmd_dir(&self) -> Option<PathBuf> {
+        match self.cmd.get_current_dir() {
+            Some(cmd_dir) => Some(cmd_dir.to_path_buf()),
+            None => std::env::current_dir().ok(),
+        }
+    }
+
+    /// Returns the file for caching the output, named `{timestamp}[_{content_hash}]` in the
+    /// cache dir of the command unless the output file is specified explicitly.
+    fn tempfile(&self, args: &[&str], content_hash: Option<u64>) -> Result<PathBuf> {
         if let Some(ref output) = self.output {
             Ok(output.into())
         } else {
-            let mut dir = std::env::temp_dir();
-            dir.push(format!(
-                "{}_{}",
-                args.join("_"),
-                SystemTime::now()
-                    .duration_since(SystemTime::UNIX_EPOCH)?
-                    .as_secs()
-            ));
+            let mut dir = cmd_cache_dir(args, self.cmd_dir().as_deref())?;
+            let timestamp = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)?
+                .as_nanos();
+            match content_hash {
+                Some(hash) => dir.push(format!("{}_{:x}", timestamp, hash)),
+                None => dir.push(format!("{}", timestamp)),
+            }
             Ok(dir)
         }
     }
 
     /// Cache the stdout into a tempfile if the output threshold exceeds.
+    ///
+    /// The raw stdout is cached even if the ANSI codes are stripped for displaying,
+    /// so that both the colored and stripped lines can be derived from the cache.
+    ///
+    /// The cache files older than the last change of the command's working directory are
+    /// removed, at most `max_cache_files_per_cmd` cache files taking at most
+    /// `max_cache_bytes_per_cmd` in total are kept for each command unless the output file is
+    /// specified explicitly.
+    ///
+    /// With `cache_by_content`, the existing cache file of the same output is renamed to the
+    /// new one as if it's just created, instead of writing the output again.
     fn try_cache(&self, cmd_stdout: &[u8], args: &[&str]) -> Result<(String, Option<PathBuf>)> {
         if self.total > self.output_threshold {
-            let tempfile = self.tempfile(args)?;
-            File::create(&tempfile)?.write_all(cmd_stdout)?;
+            let hash = if self.cache_by_content && self.output.is_none() {
+                Some(content_hash(cmd_stdout))
+            } else {
+                None
+            };
+            let tempfile = self.tempfile(args, hash)?;
+            let cached = hash.and_then(|hash| {
+                find_cache_file_by_content(tempfile.parent()?, hash, cmd_stdout.len() as u64)
+            });
+            match cached {
+                Some(cached) => std::fs::rename(cached, &tempfile)?,
+                None => File::create(&tempfile)?.write_all(cmd_stdout)?,
+            }
+            if self.output.is_none() {
+                if let Some(dir) = tempfile.parent() {
+                    remove_stale_cache_files(
+                        dir,
+                        self.max_cache_files_per_cmd,
+                        self.max_cache_bytes_per_cmd,
+                        self.cmd_dir().as_deref(),
+                    )?;
+                }
+            }
             // FIXME find the nth newline index of stdout.
             // let _end = std::cmp::min(cmd_stdout.len(), 500);
             Ok((
@@ -151,27 +776,321 @@ impl<'a> LightCommand<'a> {
     }
 
     pub fn execute(&mut self, args: &[&str]) -> Result<()> {
-        let cmd_output = self.output()?;
-        let cmd_stdout = &cmd_output.stdout;
-
-        self.total = bytecount::count(cmd_stdout, b'\n');
-
-        if self.minimalize_job_overhead(cmd_stdout).is_ok() {
+        if self.minimalize_job_overhead()? {
             return Ok(());
         }
 
+        let cmd_stdout = self.output()?;
+        let cmd_stdout = &self.filter_output(cmd_stdout);
+
+        self.total = bytecount::count(cmd_stdout, b'\n');
+
         // Write the output to a tempfile if the lines are too many.
         let (stdout_str, tempfile) = self.try_cache(&cmd_stdout, args)?;
-        let lines = self.try_prepend_icon(stdout_str.split('\n'));
-        let total = self.total;
+        let lines = self.try_prepend_icon(stdout_str.split('\n'), None);
+        let mut msg = serde_json::json!({ "total": self.total, "lines": lines });
         if let Some(tempfile) = tempfile {
-            println_json!(total, lines, tempfile);
-        } else {
-            println_json!(total, lines);
+            msg["tempfile"] = serde_json::json!(tempfile);
         }
+        self.print(msg);
 
         Ok(())
     }
+
+    /// Execute the command without buffering the whole stdout.
+    ///
+    /// Returns the number of total results and the top `number` lines of the output,
+    /// `max_buffered` is the maximum number of lines kept in memory while streaming the output.
+    /// `on_line` is called with the total so far, the kept line and the buffered lines after each
+    /// kept line of the output, e.g., for sending the partial results.
+    pub fn execute_and_gather_output(
+        &mut self,
+        number: usize,
+        max_buffered: usize,
+        on_line: impl FnMut(usize, &str, &[String]),
+    ) -> Result<(usize, Vec<String>)> {
+        let mut lines = self.bounded_output(std::cmp::max(number, max_buffered), on_line)?;
+        lines.truncate(number);
+        Ok((self.total, lines))
+    }
+}
+
+/// Returns the error message if the command failed.
+///
+/// If `no_matches_exit_code` is given, the command exiting with it just found nothing and any
+/// other non-zero exit code is an error. Otherwise the command is considered failed only if it
+/// exits abnormally with some stderr output.
+fn failure(status: ExitStatus, stderr: &[u8], no_matches_exit_code: Option<i32>) -> Option<String> {
+    if status.success() {
+        return None;
+    }
+    let stderr = String::from_utf8_lossy(stderr);
+    match no_matches_exit_code {
+        Some(code) if status.code() == Some(code) => None,
+        Some(_) if stderr.is_empty() => Some(format!("Command exited with {}", status)),
+        _ if stderr.is_empty() => None,
+        _ => Some(stderr.into()),
+    }
+}
+
+/// Returns the error carrying the stderr text if the command failed, see [`failure`].
+fn check_failure(
+    status: ExitStatus,
+    stderr: &[u8],
+    no_matches_exit_code: Option<i32>,
+) -> Result<()> {
+    match failure(status, stderr, no_matches_exit_code) {
+        Some(error) => Err(anyhow::anyhow!(error)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_nonexistent_cmd_dir_should_fall_back_to_current_dir() {
+    let mut cmd = Command::new("pwd");
+    let warning = set_current_dir(&mut cmd, Some("/nonexistent/clap/dir/file.rs".into()));
+    assert!(warning.unwrap().contains("/nonexistent/clap/dir"));
+
+    let output = cmd.output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout).trim(),
+        std::env::current_dir().unwrap().display().to_string()
+    );
+
+    let mut cmd = Command::new("pwd");
+    let dir = std::env::temp_dir().canonicalize().unwrap();
+    assert!(set_current_dir(&mut cmd, Some(dir.clone())).is_none());
+    assert_eq!(
+        String::from_utf8_lossy(&cmd.output().unwrap().stdout).trim(),
+        dir.display().to_string()
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_grep_no_matches_is_not_failure() {
+    let exit = |code: i32| {
+        Command::new("sh")
+            .args(["-c", &format!("exit {}", code)])
+            .status()
+            .unwrap()
+    };
+
+    let grep = Some(GREP_NO_MATCHES_EXIT_CODE);
+    assert_eq!(failure(exit(0), b"", grep), None);
+    assert_eq!(failure(exit(1), b"", grep), None);
+    assert!(failure(exit(2), b"", grep).is_some());
+    assert_eq!(
+        failure(exit(2), b"regex parse error", grep),
+        Some("regex parse error".into())
+    );
+
+    // The other commands only fail with some stderr output.
+    assert_eq!(failure(exit(1), b"", None), None);
+    assert!(failure(exit(1), b"oops", None).is_some());
+
+    let mut cmd = Command::new("sh");
+    cmd.args(["-c", "exit 1"]);
+    let (total, lines) = LightCommand::new_grep(&mut cmd, Some(10), false)
+        .execute_and_gather_output(10, 100, |_, _, _| {})
+        .unwrap();
+    assert_eq!(total, 0);
+    assert!(lines.is_empty());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_failure_should_return_stderr_as_error() {
+    let mut cmd = Command::new("sh");
+    cmd.args(["-c", "echo 'regex parse error' >&2; exit 2"]);
+    let err = LightCommand::new_grep(&mut cmd, Some(10), false)
+        .execute_and_gather_output(10, 100, |_, _, _| {})
+        .unwrap_err();
+    assert_eq!(err.to_string(), "regex parse error\n");
+
+    let mut cmd = Command::new("sh");
+    cmd.args(["-c", "echo oops >&2; exit 1"]);
+    let mut light_cmd = LightCommand::new(&mut cmd, None, None, false, false, 0, false);
+    assert_eq!(light_cmd.execute(&[]).unwrap_err().to_string(), "oops\n");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_timeout_should_keep_partial_output() {
+    let mut cmd = Command::new("sh");
+    cmd.args(["-c", "echo a; echo b; sleep 10; echo c"]);
+    let mut light_cmd =
+        LightCommand::new_grep(&mut cmd, Some(10), false).timeout(Some(Duration::from_millis(500)));
+    let start = Instant::now();
+    let (total, lines) = light_cmd
+        .execute_and_gather_output(10, 100, |_, _, _| {})
+        .unwrap();
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert!(light_cmd.timed_out());
+    assert_eq!(total, 2);
+    assert_eq!(lines, vec!["a", "b"]);
+
+    let mut cmd = Command::new("sh");
+    cmd.args(["-c", "echo a; sleep 10"]);
+    let mut light_cmd = LightCommand::new(&mut cmd, None, None, false, false, 0, false)
+        .timeout(Some(Duration::from_millis(500)));
+    assert_eq!(light_cmd.output().unwrap(), b"a\n");
+    assert!(light_cmd.timed_out());
+
+    let mut cmd = Command::new("sh");
+    cmd.args(["-c", "echo a"]);
+    let mut light_cmd = LightCommand::new(&mut cmd, None, None, false, false, 0, false)
+        .timeout(Some(Duration::from_secs(10)));
+    assert_eq!(light_cmd.output().unwrap(), b"a\n");
+    assert!(!light_cmd.timed_out());
+}
+
+#[test]
+fn test_read_bounded() {
+    let fixture = (0..100_000)
+        .map(|i| format!("src/lib.rs:{}:1:let x = {};", i + 1, i))
+        .collect::<Vec<_>>()
+        .join("\n");
+
+    let mut calls = 0;
+    let (total, lines) = read_bounded(
+        std::io::Cursor::new(fixture),
+        50,
+        |_| true,
+        |total, line, lines| {
+            calls += 1;
+            assert_eq!(
+                line,
+                format!("src/lib.rs:{}:1:let x = {};", total, total - 1)
+            );
+            assert_eq!(lines.len(), total.min(50));
+        },
+    )
+    .unwrap();
+
+    assert_eq!(calls, 100_000);
+    assert_eq!(total, 100_000);
+    assert_eq!(lines.len(), 50);
+    assert_eq!(lines.capacity(), 50);
+    assert_eq!(lines[49], "src/lib.rs:50:1:let x = 49;");
+}
+
+#[test]
+fn test_cache_preserves_ansi_codes() {
+    let mut tempfile = std::env::temp_dir();
+    tempfile.push("test_cache_preserves_ansi_codes");
+
+    let mut cmd = Command::new("ls");
+    let mut light_cmd = LightCommand::new(
+        &mut cmd,
+        None,
+        Some(tempfile.to_string_lossy().into()),
+        false,
+        false,
+        0,
+        true,
+    );
+    light_cmd.total = 2;
+
+    let colored = "\x1B[35msrc/main.rs\x1B[0m\n\x1B[1;32mCargo.toml\x1B[0m\n";
+    let (stdout_str, cached) = light_cmd.try_cache(colored.as_bytes(), &[]).unwrap();
+    let displayed = light_cmd.try_prepend_icon(stdout_str.split('\n'), None);
+    assert_eq!(displayed, vec!["src/main.rs", "Cargo.toml"]);
+
+    // Cache hit: the original colored output and the displayed lines are both reproducible.
+    let cached = std::fs::read_to_string(cached.unwrap()).unwrap();
+    assert_eq!(cached, colored);
+    assert_eq!(
+        light_cmd.try_prepend_icon(cached.split('\n'), None),
+        displayed
+    );
+
+    std::fs::remove_file(tempfile).unwrap();
+}
+
+#[test]
+fn test_cache_key() {
+    // The key must not change across the builds, otherwise the existing caches are lost.
+    assert_eq!(content_hash(b""), FNV_OFFSET_BASIS);
+    assert_eq!(content_hash(b"a"), 0xaf63_dc4c_8601_ec8c);
+    assert_eq!(
+        cache_key(&["rg", "--files"], Some(Path::new("/tmp"))),
+        cache_key(&["rg", "--files"], Some(Path::new("/tmp")))
+    );
+    assert_eq!(cache_key(&["rg", "--files"], None).len(), 16);
+
+    assert_ne!(cache_key(&["ab", "c"], None), cache_key(&["a", "bc"], None));
+    assert_ne!(
+        cache_key(&["rg", "--files"], Some(Path::new("/tmp"))),
+        cache_key(&["rg", "--files"], Some(Path::new("/usr")))
+    );
+}
+
+#[test]
+fn test_max_cache_files_per_cmd() {
+    let args = ["test_max_cache_files_per_cmd", "--files"];
+    let cache_dir = cmd_cache_dir(&args, std::env::current_dir().ok().as_deref()).unwrap();
+    std::fs::remove_dir_all(&cache_dir).unwrap();
+
+    let mut cmd = Command::new("ls");
+    let mut light_cmd = LightCommand::new(&mut cmd, None, None, false, false, 0, false);
+    light_cmd.total = 1;
+
+    let cached = (0..MAX_CACHE_FILES_PER_CMD + 5)
+        .map(|i| {
+            let stdout = format!("{}\n", i);
+            let (_, cached) = light_cmd.try_cache(stdout.as_bytes(), &args).unwrap();
+            cached.unwrap()
+        })
+        .collect::<Vec<_>>();
+
+    let mut remained = std::fs::read_dir(&cache_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| !path.ends_with(CMD_INFO_FILE))
+        .collect::<Vec<_>>();
+    remained.sort();
+    assert_eq!(remained, cached[5..]);
+
+    // The command is recorded and never evicted.
+    let info: CmdInfo =
+        serde_json::from_slice(&std::fs::read(cache_dir.join(CMD_INFO_FILE)).unwrap()).unwrap();
+    assert_eq!(info.args, args);
+    assert_eq!(info.cmd_dir, std::env::current_dir().ok());
+
+    std::fs::remove_dir_all(cache_dir).unwrap();
+}
+
+#[test]
+fn test_cache_by_content() {
+    let args = ["test_cache_by_content", "--files"];
+    let cache_dir = cmd_cache_dir(&args, std::env::current_dir().ok().as_deref()).unwrap();
+    std::fs::remove_dir_all(&cache_dir).unwrap();
+
+    let mut cmd = Command::new("ls");
+    let mut light_cmd =
+        LightCommand::new(&mut cmd, None, None, false, false, 0, false).cache_opts(&CacheOptions {
+            cache_by_content: true,
+            ..Default::default()
+        });
+    light_cmd.total = 1;
+
+    let cache = |stdout: &str| light_cmd.try_cache(stdout.as_bytes(), &args).unwrap().1;
+    let first = cache("foo\n").unwrap();
+    let second = cache("foo\n").unwrap();
+    let other = cache("bar\n").unwrap();
+
+    // The cache file of the same output is reused as the latest one.
+    assert!(!first.exists());
+    assert_eq!(std::fs::read_to_string(&second).unwrap(), "foo\n");
+    assert!(cache_file_timestamp(&second) > cache_file_timestamp(&first));
+    assert_eq!(std::fs::read_to_string(&other).unwrap(), "bar\n");
+    // Besides the command info file.
+    assert_eq!(std::fs::read_dir(&cache_dir).unwrap().count(), 3);
+
+    std::fs::remove_dir_all(cache_dir).unwrap();
 }
 
 #[test]
@@ -183,3 +1102,202 @@ fn test_trim_trailing() {
     assert_eq!(empty_iconized_line.len(), 4);
     assert!(empty_iconized_line.chars().next().unwrap() == DEFAULT_ICON);
 }
+
+#[test]
+fn test_stale_cache_files_are_removed() {
+    let dir = std::env::temp_dir().join(format!("clap_stale_cache_{}", std::process::id()));
+    let cmd_dir = dir.join("cmd_dir");
+    let cache_dir = dir.join("cache");
+    std::fs::create_dir_all(&cmd_dir).unwrap();
+    std::fs::create_dir_all(&cache_dir).unwrap();
+
+    let timestamp = |time: SystemTime| {
+        time.duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos()
+            .to_string()
+    };
+    let now = SystemTime::now();
+    let expired = cache_dir.join(timestamp(now - MAX_CACHE_AGE - Duration::from_secs(60)));
+    let outdated = cache_dir.join(timestamp(now - Duration::from_secs(60)));
+    let fresh = cache_dir.join(timestamp(now + Duration::from_secs(60)));
+    for path in &[&expired, &outdated, &fresh] {
+        File::create(path).unwrap();
+    }
+
+    // The cmd_dir was just created, which is newer than `outdated`.
+    assert!(is_stale_cache_file(&expired, None, now));
+    assert!(!is_stale_cache_file(&outdated, None, now));
+    assert!(is_stale_cache_file(&outdated, Some(&cmd_dir), now));
+
+    remove_stale_cache_files(&cache_dir, MAX_CACHE_FILES_PER_CMD, None, Some(&cmd_dir)).unwrap();
+    let remained = std::fs::read_dir(&cache_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect::<Vec<_>>();
+    assert_eq!(remained, vec![fresh]);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_cache_eviction_by_bytes_and_concurrently() {
+    let dir = std::env::temp_dir().join(format!("clap_cache_eviction_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let now = SystemTime::now();
+    let create_cache_files = |count: u64| {
+        (0..count)
+            .map(|i| {
+                let created = (now + Duration::from_secs(i))
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap()
+                    .as_nanos();
+                let path = dir.join(created.to_string());
+                File::create(&path)
+                    .unwrap()
+                    .write_all(&[b'x'; 100])
+                    .unwrap();
+                path
+            })
+            .collect::<Vec<_>>()
+    };
+    let remained = || {
+        let mut remained = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        remained.sort();
+        remained
+    };
+
+    let cached = create_cache_files(5);
+    remove_stale_cache_files(&dir, MAX_CACHE_FILES_PER_CMD, Some(250), None).unwrap();
+    assert_eq!(remained(), cached[3..]);
+    // The latest one is kept even if it alone exceeds the budget.
+    remove_stale_cache_files(&dir, MAX_CACHE_FILES_PER_CMD, Some(50), None).unwrap();
+    assert_eq!(remained(), cached[4..]);
+
+    create_cache_files(50);
+    let handles = (0..4)
+        .map(|_| {
+            let dir = dir.clone();
+            std::thread::spawn(move || {
+                remove_stale_cache_files(&dir, MAX_CACHE_FILES_PER_CMD, None, None)
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        assert!(handle.join().unwrap().is_ok());
+    }
+    assert_eq!(remained().len(), MAX_CACHE_FILES_PER_CMD);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_cache_files_are_ordered_by_timestamp() {
+    let dir = std::env::temp_dir().join(format!("clap_cache_order_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    // Created in the reverse order, so that the newest one has the oldest mtime.
+    let newest = dir.join(format!("{}_100", now + 2));
+    let older = dir.join((now + 1).to_string());
+    let oldest = dir.join(now.to_string());
+    let unknown = dir.join("not_a_cache_file");
+    for path in &[&newest, &older, &oldest, &unknown] {
+        File::create(path).unwrap();
+    }
+    assert_eq!(cache_file_timestamp(&newest), Some(now as u64 + 2));
+    assert_eq!(cache_file_timestamp(&unknown), None);
+
+    remove_stale_cache_files(&dir, 1, None, None).unwrap();
+    let mut remained = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect::<Vec<_>>();
+    remained.sort();
+    assert_eq!(remained, vec![newest, unknown]);
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_require_all_substrings() {
+    fn text_of(line: &str) -> &str {
+        line.splitn(4, ':').nth(3).unwrap_or(line)
+    }
+    let output = "a.rs:1:1:async fn timeout\nasync.rs:2:1:fn timeout\nb.rs:3:1:async fn\n";
+    let required = vec!["async".to_string(), "timeout".to_string()];
+
+    let mut cmd = Command::new("printf");
+    cmd.arg(output);
+    let mut light_cmd =
+        LightCommand::new_grep(&mut cmd, Some(10), false).require_all(required.clone(), text_of);
+    let (total, lines) = light_cmd
+        .execute_and_gather_output(10, 10, |_, _, _| {})
+        .unwrap();
+    assert_eq!(total, 1);
+    assert_eq!(lines, vec!["a.rs:1:1:async fn timeout"]);
+
+    // The output to be cached is filtered the same way.
+    assert_eq!(
+        light_cmd.filter_output(output.as_bytes().to_vec()),
+        b"a.rs:1:1:async fn timeout\n"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_top_lines_are_streamed() {
+    let mut cmd = Command::new("seq");
+    cmd.arg("100000");
+    let mut light_cmd = LightCommand::new(&mut cmd, Some(3), None, false, false, 0, false);
+    assert_eq!(light_cmd.top_lines(3).unwrap(), vec!["1", "2", "3"]);
+    assert_eq!(light_cmd.total, 100000);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_icons_of_directories_and_symlinks() {
+    let dir = std::env::temp_dir().join(format!("test_entry_icons_{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    std::fs::write(dir.join("main.rs"), "").unwrap();
+    std::os::unix::fs::symlink(dir.join("main.rs"), dir.join("link.rs")).unwrap();
+
+    let mut cmd = Command::new("ls");
+    let light_cmd = LightCommand::new(&mut cmd, None, None, true, false, 0, false);
+    let icons = |base_dir: Option<&Path>| {
+        light_cmd
+            .iconize(
+                ["main.rs", "sub", "sub/", "link.rs"].iter().copied(),
+                base_dir,
+            )
+            .into_iter()
+            .map(|line| line.chars().next().unwrap())
+            .collect::<Vec<_>>()
+    };
+
+    let rs_icon = icon::prepend_icon("main.rs").chars().next().unwrap();
+    assert_eq!(
+        icons(Some(&dir)),
+        vec![
+            rs_icon,
+            icon::FOLDER_ICON,
+            icon::FOLDER_ICON,
+            icon::SYMLINK_ICON
+        ]
+    );
+    // Only the trailing `/` tells the directory without stat.
+    assert_eq!(
+        icons(None),
+        vec![rs_icon, icon::DEFAULT_ICON, icon::FOLDER_ICON, rs_icon]
+    );
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+
+pub const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+/// 64-bit FNV-1a hash of `bytes`, starting from `hash`, which is [`FNV_OFFSET_BASIS`] for
+/// the first bytes.
+///
+/// Unlike `DefaultHasher`, the result is stable across the Rust versions, so the cache files
+/// and the ids of results named after it stay the same after a rebuild.
+pub fn fnv1a<'b>(hash: u64, bytes: impl IntoIterator<Item = &'b u8>) -> u64 {
+    bytes.into_iter().fold(hash, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// Returns the directory which the relative paths in the output of command are resolved against.
+///
+/// That's `cmd_dir`, or its parent if it's a file, the current working directory is used
+/// if it's not specified.
+pub fn resolve_base_dir(cmd_dir: Option<&Path>) -> Option<PathBuf> {
+    match cmd_dir {
+        Some(dir) if dir.is_dir() => Some(dir.to_path_buf()),
+        Some(dir) => dir.parent().map(Path::to_path_buf),
+        None => std::env::current_dir().ok(),
+    }
+}
+
+/// Loads the user defined icons from the JSON file at `path`, e.g., `{"rs": "🦀"}`, which
+/// take precedence over the builtin ones, see [`icon::set_custom_icons`].
+pub fn load_icon_map(path: &Path) -> Result<()> {
+    let icons: HashMap<String, char> = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+    if !icon::set_custom_icons(icons) {
+        return Err(anyhow::anyhow!("the custom icons have been loaded already"));
+    }
+    Ok(())
+}
+
+/// Prints the error as JSON to stdout, vim-clap does not handle the stderr stream, we just
+/// pass the error info via stdout.
+pub fn print_error(error: &anyhow::Error) {
+    let error = format!("{:#}", error);
+    println_json!(error);
+}
+
+/// A path excluded from the results, e.g., the file where the picker is opened.
+///
+/// The paths are compared in the canonical form so that the relative and absolute paths
+/// referring to the same file are both excluded.
+#[derive(Debug)]
+pub struct ExcludedPath {
+    canonical: PathBuf,
+    base_dir: PathBuf,
+}
+
+impl ExcludedPath {
+    /// Returns `None` if `path` does not exist.
+    ///
+    /// The relative paths of results are resolved against `cmd_dir`, see [`resolve_base_dir`].
+    pub fn new(path: &Path, cmd_dir: Option<&Path>) -> Option<Self> {
+        let canonical = path.canonicalize().ok()?;
+        let base_dir = resolve_base_dir(cmd_dir)?;
+        Some(Self {
+            canonical,
+            base_dir,
+        })
+    }
+
+    /// Returns true if `path` refers to the excluded path.
+    pub fn matches(&self, path: &str) -> bool {
+        let path = Path::new(path);
+        // Avoid the syscall of canonicalization for most of the paths.
+        if path.file_name() != self.canonical.file_name() {
+            return false;
+        }
+        self.base_dir.join(path).canonicalize().ok().as_ref() == Some(&self.canonical)
+    }
+}
+
+#[test]
+fn test_excluded_path() {
+    let mut dir = std::env::temp_dir();
+    dir.push(format!("test_excluded_path_{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("src")).unwrap();
+    for file in &["src/lib.rs", "src/main.rs"] {
+        std::fs::write(dir.join(file), "").unwrap();
+    }
+
+    let excluded = ExcludedPath::new(&dir.join("src/lib.rs"), Some(&dir)).unwrap();
+    assert!(excluded.matches("src/lib.rs"));
+    assert!(excluded.matches("./src/../src/lib.rs"));
+    assert!(excluded.matches(&dir.join("src/lib.rs").to_string_lossy()));
+    assert!(!excluded.matches("src/main.rs"));
+    assert!(!excluded.matches("lib.rs"));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_load_icon_map() {
+    let path = std::env::temp_dir().join(format!("test_icon_map_{}.json", std::process::id()));
+    std::fs::write(&path, r#"{".ClapIcon": "★", "ClapIcon.lock": "☆"}"#).unwrap();
+    load_icon_map(&path).unwrap();
+    std::fs::remove_file(path).unwrap();
+
+    assert_eq!(icon::prepend_icon("src/foo.clapicon"), "★ src/foo.clapicon");
+    assert_eq!(icon::prepend_icon("clapicon.lock"), "☆ clapicon.lock");
+    assert_eq!(
+        icon::prepend_grep_icon("src/foo.clapicon:1:2:bar"),
+        "★ src/foo.clapicon:1:2:bar"
+    );
+    assert_eq!(
+        icon::strip_icon("★ src/foo.clapicon"),
+        Some("src/foo.clapicon")
+    );
+}
@@ -1,6 +1,6 @@
 use maple_cli::{
     cmd::{Cmd, Maple},
-    subprocess, Result, Source, StructOpt,
+    subprocess, Algo, Result, Source, StructOpt,
 };
 
 pub mod built_info {
@@ -21,6 +21,10 @@ fn version() {
 }
 
 fn run(maple: Maple) -> Result<()> {
+    if let Some(icon_map) = &maple.icon_map {
+        maple_cli::load_icon_map(icon_map)?;
+    }
+
     match maple.command {
         Cmd::Version => {
             version();
@@ -30,89 +34,113 @@ fn run(maple: Maple) -> Result<()> {
         }
         Cmd::Filter {
             query,
-            input,
+            query_file,
+            query_stdin,
+            mut input,
+            socket,
+            tags,
+            files,
+            files_opts,
             algo,
             cmd,
-            cmd_dir,
             sync,
+            match_opts,
+            filter_opts,
         } => {
-            let source = if let Some(cmd_str) = cmd {
-                if let Some(dir) = cmd_dir {
+            let reads_stdin = cmd.is_none()
+                && socket.is_none()
+                && tags.is_none()
+                && files.is_none()
+                && input.is_empty();
+            let query = maple_cli::cmd::filter::read_query(
+                query,
+                query_file.as_deref(),
+                query_stdin,
+                reads_stdin,
+            )?;
+            let matcher = match_opts.matcher(algo.unwrap_or(Algo::Fzy), &query);
+            matcher.check_query()?;
+            let source: Source<Box<dyn Iterator<Item = String>>> = if let Some(cmd_str) = cmd {
+                if let Some(dir) = &filter_opts.cmd_dir {
                     subprocess::Exec::shell(cmd_str).cwd(dir).into()
                 } else {
                     subprocess::Exec::shell(cmd_str).into()
                 }
+            } else if let Some(socket) = socket {
+                maple_cli::cmd::filter::socket_source(socket)?
+            } else if let Some(tags) = tags {
+                Source::Tags(tags)
+            } else if let Some(dir) = files {
+                Source::List(Box::new(maple_cli::cmd::files::walk(dir, &files_opts)))
             } else {
-                input
-                    .map(Into::into)
-                    .unwrap_or(Source::<std::iter::Empty<_>>::Stdin)
+                match input.len() {
+                    0 => Source::Stdin,
+                    1 if filter_opts.mmap => Source::MmapFile(input.remove(0)),
+                    1 => input.remove(0).into(),
+                    _ => Source::Files {
+                        paths: input,
+                        threads: filter_opts.read_threads.unwrap_or(1),
+                    },
+                }
             };
             if sync {
                 maple_cli::cmd::filter::run(
-                    &query,
+                    &matcher,
                     source,
-                    algo,
                     maple.number,
                     maple.enable_icon,
                     maple.winwidth,
+                    &filter_opts,
                 )?;
             } else {
                 maple_cli::cmd::filter::dyn_run(
-                    &query,
+                    &matcher,
                     source,
-                    algo,
                     maple.number,
                     maple.enable_icon,
                     maple.winwidth,
+                    &filter_opts,
                 )?;
             }
         }
         Cmd::Blines { query, input } => {
             maple_cli::cmd::filter::blines(&query, &input, maple.number, maple.winwidth)?;
         }
-        Cmd::Exec {
-            cmd,
-            output,
-            cmd_dir,
-            output_threshold,
-        } => {
-            maple_cli::cmd::exec::run(
-                cmd,
-                output,
-                output_threshold,
-                cmd_dir,
-                maple.number,
-                maple.enable_icon,
-            )?;
+        Cmd::Exec { cmd, opts } => {
+            maple_cli::cmd::exec::run(cmd, opts, maple.number, maple.enable_icon)?;
         }
         Cmd::Grep {
             grep_cmd,
             grep_query,
-            glob,
-            cmd_dir,
+            opts,
         } => {
-            let g = match &glob {
-                Some(s) => Some(s.as_str()),
-                None => None,
-            };
-
             maple_cli::cmd::grep::run(
                 grep_cmd,
                 &grep_query,
-                g,
-                cmd_dir,
+                opts,
                 maple.number,
                 maple.enable_icon,
+                maple.winwidth,
             )?;
         }
         Cmd::Helptags { meta_info } => maple_cli::cmd::helptags::run(meta_info)?,
         Cmd::RipgrepForerunner { cmd_dir } => {
             maple_cli::cmd::grep::run_forerunner(cmd_dir, maple.number, maple.enable_icon)?
         }
+        Cmd::Bench {
+            query,
+            input,
+            algo,
+            iterations,
+        } => maple_cli::cmd::bench::run(&query, input, algo, iterations)?,
+        Cmd::Cache { list, clear } => maple_cli::cmd::cache::run(list, clear)?,
     }
     Ok(())
 }
 
-pub fn main() -> Result<()> {
-    run(Maple::from_args())
+pub fn main() {
+    if let Err(error) = run(Maple::from_args()) {
+        maple_cli::print_error(&error);
+        std::process::exit(1);
+    }
 }